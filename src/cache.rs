@@ -1,16 +1,299 @@
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::{DashMap, DashSet};
+use tokio::sync::Mutex;
 use moka::future::Cache as MokaCache;
-use tracing::debug;
+use anyhow::Result;
+use futures_util::stream::{BoxStream, StreamExt};
+use tracing::{debug, warn};
 use crate::drive::{QuarkDrive};
-use crate::drive::model::QuarkFile;
+use crate::drive::model::{PathFolder, QuarkFile, QuarkFiles};
+use crate::names::{disambiguate, DuplicateNames};
+
+/// Directory path -> fid index, kept apart from the listing cache so it
+/// survives TTL expiry and `invalidate_all`. Any directory seen once can be
+/// re-listed directly by fid instead of walking down from the root.
+#[derive(Clone, Default)]
+pub struct PathIndex {
+    fids: Arc<DashMap<String, String>>,
+    // directory fid -> names of its child directories
+    children: Arc<DashMap<String, HashSet<String>>>,
+}
+
+impl PathIndex {
+    pub fn fid(&self, path: &str) -> Option<String> {
+        self.fids.get(path).map(|v| v.clone())
+    }
+
+    /// Records a listing of `path` and forgets child directories that disappeared from it.
+    pub fn record_listing(&self, path: &str, fid: &str, files: &[QuarkFile]) {
+        self.fids.insert(path.to_string(), fid.to_string());
+        let dirs: HashSet<String> = files
+            .iter()
+            .filter(|f| f.dir)
+            .map(|f| f.file_name.clone())
+            .collect();
+        for f in files.iter().filter(|f| f.dir) {
+            let child = child_path(path, &f.file_name);
+            // a directory replaced by another one with the same name has a new fid
+            if self.fid(&child).is_some_and(|old| old != f.fid) {
+                self.forget(&child);
+            }
+            self.fids.insert(child, f.fid.clone());
+        }
+        let gone: Vec<String> = self
+            .children
+            .insert(fid.to_string(), dirs.clone())
+            .map(|old| old.difference(&dirs).cloned().collect())
+            .unwrap_or_default();
+        for name in gone {
+            self.forget(&child_path(path, &name));
+        }
+    }
+
+    /// Fid -> path of every known directory.
+    pub fn paths_by_fid(&self) -> HashMap<String, String> {
+        self.fids.iter().map(|e| (e.value().clone(), e.key().clone())).collect()
+    }
+
+    pub fn export_fids(&self) -> Vec<(String, String)> {
+        self.fids.iter().map(|e| (e.key().clone(), e.value().clone())).collect()
+    }
+
+    pub fn export_children(&self) -> Vec<(String, Vec<String>)> {
+        self.children
+            .iter()
+            .map(|e| (e.key().clone(), e.value().iter().cloned().collect()))
+            .collect()
+    }
+
+    pub fn import(&self, fids: Vec<(String, String)>, children: Vec<(String, Vec<String>)>) {
+        for (path, fid) in fids {
+            self.fids.insert(path, fid);
+        }
+        for (fid, names) in children {
+            self.children.insert(fid, names.into_iter().collect());
+        }
+    }
+
+    fn add_dir(&self, parent: &str, dir: &QuarkFile) {
+        self.fids.insert(child_path(parent, &dir.file_name), dir.fid.clone());
+        if let Some(parent_fid) = self.fid(parent) {
+            self.children.entry(parent_fid).or_default().insert(dir.file_name.clone());
+        }
+    }
+
+    /// Records the folders of a path the drive reported, top first, and
+    /// returns the path of the last one.
+    pub fn record_folder_path(&self, folders: &[PathFolder]) -> String {
+        let mut path = "/".to_string();
+        for folder in folders {
+            let child = child_path(&path, &folder.file_name);
+            if self.fid(&child).is_some_and(|old| old != folder.fid) {
+                self.forget(&child);
+            }
+            self.fids.insert(child.clone(), folder.fid.clone());
+            if let Some(parent_fid) = self.fid(&path) {
+                self.children.entry(parent_fid).or_default().insert(folder.file_name.clone());
+            }
+            path = child;
+        }
+        path
+    }
+
+    /// Drops `path` and every known directory below it.
+    pub fn forget(&self, path: &str) {
+        if let Some((_, fid)) = self.fids.remove(path) {
+            debug!(path = %path, fid = %fid, "path index: forget");
+            if let Some((_, names)) = self.children.remove(&fid) {
+                for name in names {
+                    self.forget(&child_path(path, &name));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn child_path(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+#[derive(Clone)]
+struct Listing {
+    files: Vec<QuarkFile>,
+    // None for listings restored from a snapshot, those are stale from the start
+    fetched_at: Option<Instant>,
+}
+
+impl Listing {
+    fn is_older_than(&self, age: Duration) -> bool {
+        self.fetched_at.is_none_or(|t| t.elapsed() >= age)
+    }
+}
+
+/// Child directories of `old` whose subtree may differ in `new`: removed,
+/// replaced by another fid or touched since the previous listing.
+fn changed_dirs<'a>(old: &'a [QuarkFile], new: &[QuarkFile]) -> Vec<&'a str> {
+    old.iter()
+        .filter(|o| o.dir)
+        .filter(|o| {
+            !new.iter().any(|n| {
+                n.dir && n.file_name == o.file_name && n.fid == o.fid && n.updated_at == o.updated_at
+            })
+        })
+        .map(|o| o.file_name.as_str())
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+enum Change {
+    // a new, renamed or replaced entry, wins over entries with the same fid or name
+    Put(QuarkFile),
+    Remove(String),
+}
+
+impl Change {
+    fn apply(&self, files: &mut Vec<QuarkFile>) {
+        match self {
+            Change::Put(file) => {
+                files.retain(|f| f.fid != file.fid && f.file_name != file.file_name);
+                files.push(file.clone());
+            }
+            Change::Remove(fid) => files.retain(|f| &f.fid != fid),
+        }
+    }
+}
+
+struct PendingChange {
+    change: Change,
+    at: Instant,
+}
 
 #[derive(Clone)]
 pub struct Cache {
-    inner: MokaCache<String, Vec<QuarkFile>>,
+    inner: MokaCache<String, Listing>,
+    index: PathIndex,
+    // listings older than this are served as-is and refreshed in the background
+    refresh_after: Duration,
+    revalidating: Arc<DashSet<String>>,
+    // directories read since the last periodic revalidation
+    accessed: Arc<DashSet<String>>,
+    // one lock per directory being listed, concurrent lookups wait for the first fetch
+    inflight: Arc<DashMap<String, Arc<Mutex<()>>>>,
+    // paths recently found not to exist
+    missing: MokaCache<String, ()>,
+    // our own writes per directory, re-applied over server listings that may not show them yet
+    overlay: Arc<DashMap<String, Vec<PendingChange>>>,
+    duplicate_names: DuplicateNames,
     drive: QuarkDrive,
 }
 const ONE_PAGE: u32 = 500;
+const MISSING_TTL: Duration = Duration::from_secs(30);
+// how long Quark listings may lag behind our writes
+const OVERLAY_WINDOW: Duration = Duration::from_secs(30);
+// the sort endpoint stops paging after 10,000 entries for a given order
+const MAX_PAGES: u32 = 20;
+// orders used to reach the entries past that cap in larger folders
+const FALLBACK_SORTS: [&str; 3] = ["file_type:asc,updated_at:asc", "file_name:asc", "file_name:desc"];
+
+pub type PageStream = BoxStream<'static, Result<Vec<QuarkFile>>>;
+
+/// Pages through a directory listing, yielding only entries not seen on
+/// earlier pages. Folders larger than the server cap are walked again in
+/// the `FALLBACK_SORTS` orders until every entry has been seen.
+struct ListingPager {
+    drive: QuarkDrive,
+    fid: String,
+    path: String,
+    // 0 is the default order, then the FALLBACK_SORTS
+    order: usize,
+    page_no: u32,
+    total: u32,
+    seen: HashSet<String>,
+    files: Vec<QuarkFile>,
+    missing: bool,
+    done: bool,
+}
+
+impl ListingPager {
+    fn new(drive: QuarkDrive, fid: &str, path: &str) -> Self {
+        Self {
+            drive,
+            fid: fid.to_string(),
+            path: path.to_string(),
+            order: 0,
+            page_no: 1,
+            total: 0,
+            seen: HashSet::new(),
+            files: Vec::new(),
+            missing: false,
+            done: false,
+        }
+    }
+
+    async fn fetch(&self) -> Result<(Option<QuarkFiles>, u32)> {
+        match self.order.checked_sub(1) {
+            None => self.drive.get_files_by_pdir_fid(&self.fid, self.page_no, ONE_PAGE).await,
+            Some(i) => {
+                self.drive
+                    .get_files_by_pdir_fid_sorted(&self.fid, self.page_no, ONE_PAGE, FALLBACK_SORTS[i])
+                    .await
+            }
+        }
+    }
+
+    async fn next_page(&mut self) -> Result<Option<Vec<QuarkFile>>> {
+        while !self.done {
+            let (files, total) = self.fetch().await?;
+            let Some(files) = files else {
+                self.missing = true;
+                self.done = true;
+                break;
+            };
+            self.total = total;
+            let size = files.list.len();
+            let mut new_files = Vec::with_capacity(size);
+            for mut f in files.list {
+                if self.seen.insert(f.fid.clone()) {
+                    f.parent_path = Some(self.path.clone());
+                    new_files.push(f);
+                }
+            }
+            self.files.extend(new_files.iter().cloned());
+            self.advance(size);
+            if !new_files.is_empty() {
+                return Ok(Some(new_files));
+            }
+        }
+        Ok(None)
+    }
+
+    fn advance(&mut self, page_size: usize) {
+        let exhausted = page_size < ONE_PAGE as usize || self.page_no >= self.total / ONE_PAGE + 1;
+        if self.seen.len() >= self.total as usize || (exhausted && self.total <= MAX_PAGES * ONE_PAGE) {
+            self.done = true;
+        } else if exhausted || self.page_no >= MAX_PAGES {
+            if self.order < FALLBACK_SORTS.len() {
+                self.order += 1;
+                self.page_no = 1;
+                debug!(path = %self.path, seen = self.seen.len(), total = self.total,
+                    sort = FALLBACK_SORTS[self.order - 1], "cache: listing capped, re-paging");
+            } else {
+                self.done = true;
+                warn!(path = %self.path, fid = %self.fid, seen = self.seen.len(), total = self.total,
+                    "cache: listing incomplete, entries past the server cap are missing");
+            }
+        } else {
+            self.page_no += 1;
+        }
+    }
+}
 
 impl Cache {
     pub fn new(max_capacity: u64, ttl: u64, drive: QuarkDrive) -> Self {
@@ -19,19 +302,129 @@ impl Cache {
             .time_to_live(Duration::from_secs(ttl))
             .build();
         
-        Self { inner , drive}
+        Self {
+            inner,
+            index: PathIndex::default(),
+            // refreshed well before moka drops it
+            refresh_after: Duration::from_secs(ttl) / 2,
+            revalidating: Arc::new(DashSet::new()),
+            accessed: Arc::new(DashSet::new()),
+            inflight: Arc::new(DashMap::new()),
+            missing: MokaCache::builder()
+                .max_capacity(max_capacity * 10)
+                .time_to_live(MISSING_TTL)
+                .build(),
+            overlay: Arc::new(DashMap::new()),
+            duplicate_names: DuplicateNames::default(),
+            drive,
+        }
+    }
+
+    pub fn set_refresh_after(&mut self, secs: u64) -> &mut Self {
+        self.refresh_after = Duration::from_secs(secs);
+        self
+    }
+
+    pub fn set_duplicate_names(&mut self, policy: DuplicateNames) -> &mut Self {
+        self.duplicate_names = policy;
+        self
+    }
+
+    pub fn index(&self) -> &PathIndex {
+        &self.index
     }
+
+    pub fn export_listings(&self) -> Vec<(String, Vec<QuarkFile>)> {
+        self.inner
+            .iter()
+            .map(|(key, listing)| (key.as_ref().clone(), listing.files))
+            .collect()
+    }
+
+    /// Loads listings from a snapshot, each is revalidated on first access.
+    pub async fn import_stale_listings(&self, listings: Vec<(String, Vec<QuarkFile>)>) {
+        for (key, mut files) in listings {
+            disambiguate(&mut files, self.duplicate_names);
+            self.inner.insert(key, Listing { files, fetched_at: None }).await;
+        }
+    }
+
+    fn spawn_revalidate(&self, key: String) {
+        if !self.revalidating.insert(key.clone()) {
+            return;
+        }
+        let cache = self.clone();
+        tokio::spawn(async move {
+            cache.revalidate(&key).await;
+            cache.revalidating.remove(&key);
+        });
+    }
+
+    /// Re-lists a cached directory by fid and drops the cached subtrees of
+    /// child directories that changed, unchanged ones keep their listings.
+    async fn revalidate(&self, key: &str) {
+        let Some(fid) = self.index.fid(key) else {
+            return;
+        };
+        debug!(key = %key, fid = %fid, "cache: revalidate listing");
+        let old = self.inner.get(key).await;
+        let Some(files) = self.list(&fid, key).await else {
+            return;
+        };
+        if let Some(old) = old {
+            for name in changed_dirs(&old.files, &files) {
+                debug!(key = %key, dir = %name, "cache: child directory changed");
+                self.invalidate_subtree(&child_path(key, name)).await;
+            }
+        }
+        self.store(key, &fid, files).await;
+    }
+
+    /// Refreshes directories read since the last call whose listing is at
+    /// least half way to `refresh_after`, so hot folders rarely go stale.
+    pub async fn revalidate_hot(&self) {
+        let keys: Vec<String> = self.accessed.iter().map(|k| k.clone()).collect();
+        self.accessed.clear();
+        for key in keys {
+            let Some(listing) = self.inner.get(&key).await else {
+                continue;
+            };
+            if listing.is_older_than(self.refresh_after / 2) && self.revalidating.insert(key.clone()) {
+                self.revalidate(&key).await;
+                self.revalidating.remove(&key);
+            }
+        }
+    }
+
     pub async fn get_or_insert(&self, key: &str) -> Option<Vec<QuarkFile>> {
         debug!(key = %key, "cache: get_or_insert");
-        if let Some(files) = self.get(key).await {
-            return Some(files);
+        if let Some(listing) = self.inner.get(key).await {
+            self.accessed.insert(key.to_string());
+            if listing.is_older_than(self.refresh_after) {
+                self.spawn_revalidate(key.to_string());
+            }
+            return Some(listing.files);
+        }
+        if self.missing.contains_key(key) {
+            debug!(key = %key, "cache: known missing");
+            return None;
+        }
+        if let Some(fid) = self.index.fid(key) {
+            // known directory, list it directly by fid
+            debug!(key = %key, fid = %fid, "cache: re-list by indexed fid");
+            self.dfs(&fid, key, key).await;
+            return self.get(key).await;
         }
         if key == "/" {
-            self.dfs(QuarkFile::new_root(), key, "/").await;
+            self.dfs(&QuarkFile::new_root().fid, key, "/").await;
         }else {
             let mut path = Path::new(key);
-            let mut dsf_root_file = None;
+            let mut dsf_root_fid = None;
             while let Some(parent) = path.parent() {
+                if let Some(fid) = self.index.fid(path.to_str().unwrap()) {
+                    dsf_root_fid = Some(fid);
+                    break;
+                }
                 if let Some(c_files) = self.get(parent.to_str().unwrap()).await {
                     let file_name = path.file_name().and_then(|os_str| os_str.to_str());
                     let found = c_files.iter().find(|quark_file| {
@@ -42,7 +435,8 @@ impl Cache {
                         path = parent;
                         continue;
                     }
-                    dsf_root_file = found;
+                    // a regular file has no listing of its own
+                    dsf_root_fid = found.filter(|f| f.dir).map(|f| f.fid);
                     break;
                 }
 
@@ -54,12 +448,12 @@ impl Cache {
 
             }
             if path.to_str() == Some("/") {
-                self.dfs(QuarkFile::new_root(), key, "/").await;
+                self.dfs(&QuarkFile::new_root().fid, key, "/").await;
             }else {
-                match dsf_root_file { 
-                    Some(dsf_root_fil) => {
-                        debug!(key = %key, "cache: found root file: {}", dsf_root_fil.file_name);
-                        self.dfs(dsf_root_fil, key, path.to_str().unwrap()).await;
+                match dsf_root_fid {
+                    Some(dsf_root_fid) => {
+                        debug!(key = %key, "cache: found root fid: {}", dsf_root_fid);
+                        self.dfs(&dsf_root_fid, key, path.to_str().unwrap()).await;
                     },
                     None => {
                         debug!(key = %key, "cache: no root file found for path: {}", path.to_str().unwrap());
@@ -73,70 +467,190 @@ impl Cache {
             Some(files)
         }else {
             debug!(key = %key, "cache: no files found for key");
+            self.missing.insert(key.to_string(), ()).await;
             None
         }
     }
 
-    async fn dfs(&self, file: QuarkFile, target_path: &str, dfs_path: &str) {
-        if file.dir {
-            let mut current_files = Vec::<QuarkFile>::new();
-            for page_no in 1..=20 {
-                let (files, total) =
-                    match self.drive.get_files_by_pdir_fid(&file.fid, page_no, ONE_PAGE).await{
-                    Ok((k, v)) => (k, v),
-                    Err(e) => {
-                        debug!(error = %e, file_id = &file.fid, file_name = &file.file_name,
-                                page_no = page_no,
-                            "Failed to get files from drive");
-                        return;
-                    }
-                };
-                let mut files = files.unwrap();
-                // add dfs_path to each file
-                for f in files.list.iter_mut() {
-                    f.parent_path = Some(dfs_path.to_string());
-                }
-                let size = files.list.len();
-                current_files.extend(files.list);
-                // guess: es limit is 10000
-                if size < ONE_PAGE as usize || page_no >= total / ONE_PAGE + 1   {
-                    break;
+    /// Fetches the full listing of directory `fid` located at `path`, `None` if it failed.
+    async fn list(&self, fid: &str, path: &str) -> Option<Vec<QuarkFile>> {
+        let mut pager = ListingPager::new(self.drive.clone(), fid, path);
+        loop {
+            match pager.next_page().await {
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!(error = %e, file_id = fid, path = path,
+                            page_no = pager.page_no,
+                        "Failed to get files from drive");
+                    return None;
                 }
             }
+        }
+        if pager.missing {
+            // directory no longer exists
+            self.forget_key(path).await;
+            return None;
+        }
+        Some(pager.files)
+    }
 
-            self.insert(dfs_path.to_string(), current_files.clone()).await;
-            debug!("{} in cache", &dfs_path);
-            if dfs_path == target_path {
-                return;
+    /// Streams the listing of an uncached but known directory page by page,
+    /// caching it once the last page has been read. `None` when it is
+    /// already cached, has to be resolved through its parents first or its
+    /// names can only be settled once every entry is known.
+    pub async fn stream_listing(&self, key: &str) -> Result<Option<PageStream>> {
+        if self.duplicate_names.needs_full_listing()
+            || self.inner.contains_key(key)
+            || self.missing.contains_key(key)
+            || self.overlay.contains_key(key)
+        {
+            return Ok(None);
+        }
+        let fid = match self.index.fid(key) {
+            Some(fid) => fid,
+            None if key == "/" => QuarkFile::new_root().fid,
+            None => return Ok(None),
+        };
+        // someone else is listing it, wait for that through get_or_insert
+        let Ok(guard) = self.inflight_lock(key).try_lock_owned() else {
+            return Ok(None);
+        };
+        debug!(key = %key, fid = %fid, "cache: stream listing");
+        let mut pager = ListingPager::new(self.drive.clone(), &fid, key);
+        let first = match pager.next_page().await {
+            Ok(first) if !pager.missing => first,
+            result => {
+                drop(guard);
+                self.release_inflight(key);
+                if pager.missing {
+                    self.forget_key(key).await;
+                }
+                return result.map(|_| None);
             }
-            for curr_f in current_files {
-                let file_path = if dfs_path == "/" {
-                    format!("{}{}", dfs_path, curr_f.file_name)
-                }else {
-                    format!("{}/{}", dfs_path, curr_f.file_name)
-                };
-                if target_path.starts_with(&file_path) {
-                    Box::pin(self.dfs(curr_f, target_path, &file_path)).await;
+        };
+        let cache = self.clone();
+        // the guard lives as long as the stream so waiters find the listing cached
+        let rest = futures_util::stream::unfold(Some((pager, guard)), move |state| {
+            let cache = cache.clone();
+            async move {
+                let (mut pager, guard) = state?;
+                match pager.next_page().await {
+                    Ok(Some(files)) => Some((Ok(files), Some((pager, guard)))),
+                    Ok(None) => {
+                        if !pager.missing {
+                            cache.store(&pager.path, &pager.fid, pager.files).await;
+                        }
+                        drop(guard);
+                        cache.release_inflight(&pager.path);
+                        None
+                    }
+                    Err(err) => {
+                        drop(guard);
+                        cache.release_inflight(&pager.path);
+                        Some((Err(err), None))
+                    }
                 }
             }
+        });
+        Ok(Some(futures_util::stream::iter(first.map(Ok)).chain(rest).boxed()))
+    }
 
+    async fn store(&self, path: &str, fid: &str, mut files: Vec<QuarkFile>) {
+        self.apply_overlay(path, &mut files);
+        disambiguate(&mut files, self.duplicate_names);
+        self.index.record_listing(path, fid, &files);
+        // the listing proves the directory and its child directories exist
+        self.missing.invalidate(path).await;
+        for f in files.iter().filter(|f| f.dir) {
+            self.missing.invalidate(&child_path(path, &f.file_name)).await;
+        }
+        self.insert(path.to_string(), files).await;
+        debug!("{} in cache", path);
+    }
+
+    fn inflight_lock(&self, path: &str) -> Arc<Mutex<()>> {
+        self.inflight.entry(path.to_string()).or_default().clone()
+    }
+
+    fn release_inflight(&self, path: &str) {
+        // only the map itself still holds the lock, nobody is waiting
+        self.inflight.remove_if(path, |_, lock| Arc::strong_count(lock) == 1);
+    }
+
+    /// Lists and caches a directory, concurrent callers for the same path
+    /// share a single fetch instead of each hitting the API.
+    async fn fetch_coalesced(&self, fid: &str, path: &str) -> Option<Vec<QuarkFile>> {
+        let started = Instant::now();
+        let lock = self.inflight_lock(path);
+        let guard = lock.lock().await;
+        let cached = self.inner.get(path).await;
+        let files = match cached {
+            // listed by whoever held the lock before us
+            Some(listing) if listing.fetched_at.is_some_and(|t| t >= started) => {
+                debug!(path = %path, "cache: coalesced listing");
+                Some(listing.files)
+            }
+            _ => match self.list(fid, path).await {
+                Some(files) => {
+                    self.store(path, fid, files.clone()).await;
+                    Some(files)
+                }
+                None => None,
+            },
+        };
+        drop(guard);
+        drop(lock);
+        self.release_inflight(path);
+        files
+    }
+
+    async fn dfs(&self, fid: &str, target_path: &str, dfs_path: &str) {
+        let Some(current_files) = self.fetch_coalesced(fid, dfs_path).await else {
+            return;
+        };
+        if dfs_path == target_path {
+            return;
+        }
+        for curr_f in current_files.into_iter().filter(|f| f.dir) {
+            let file_path = child_path(dfs_path, &curr_f.file_name);
+            if target_path == file_path || target_path.starts_with(&format!("{}/", file_path)) {
+                Box::pin(self.dfs(&curr_f.fid, target_path, &file_path)).await;
+            }
         }
     }
 
     async fn get(&self, key: &str) -> Option<Vec<QuarkFile>> {
         debug!(key = %key, "cache: get");
-        self.inner.get(key).await
+        self.inner.get(key).await.map(|listing| listing.files)
     }
 
-    async fn insert(&self, key: String, value: Vec<QuarkFile>) {
+    async fn insert(&self, key: String, files: Vec<QuarkFile>) {
         debug!(key = %key, "cache: insert");
-        self.inner.insert(key, value).await;
+        let listing = Listing { files, fetched_at: Some(Instant::now()) };
+        self.inner.insert(key, listing).await;
     }
 
     pub async fn invalidate(&self, path: &Path) {
         let key = path.to_string_lossy().into_owned();
         debug!(path = %path.display(), key = %key, "cache: invalidate");
         self.inner.invalidate(&key).await;
+        self.clear_missing(&key).await;
+    }
+
+    /// Drops negative entries for `dir` and everything below it, called
+    /// whenever the directory is written to or re-listed.
+    async fn clear_missing(&self, dir: &str) {
+        let prefix = if dir == "/" { dir.to_string() } else { format!("{}/", dir) };
+        let keys: Vec<String> = self
+            .missing
+            .iter()
+            .map(|(key, _)| key.as_ref().clone())
+            .filter(|key| key == dir || key.starts_with(&prefix))
+            .collect();
+        for key in keys {
+            self.missing.invalidate(&key).await;
+        }
     }
 
     pub async fn invalidate_parent(&self, path: &Path) {
@@ -145,9 +659,388 @@ impl Cache {
         }
     }
 
+    /// Drops the listing and index entries of a directory that was removed or moved away.
+    pub async fn forget(&self, path: &Path) {
+        self.forget_key(&path.to_string_lossy()).await;
+    }
+
+    async fn forget_key(&self, key: &str) {
+        debug!(key = %key, "cache: forget");
+        self.index.forget(key);
+        self.inner.invalidate(key).await;
+    }
+
+    fn apply_overlay(&self, dir: &str, files: &mut Vec<QuarkFile>) {
+        if let Some(mut changes) = self.overlay.get_mut(dir) {
+            changes.retain(|c| c.at.elapsed() < OVERLAY_WINDOW);
+            for c in changes.iter() {
+                c.change.apply(files);
+            }
+        }
+        self.overlay.remove_if(dir, |_, changes| changes.is_empty());
+    }
+
+    async fn patch(&self, dir: &str, change: Change) {
+        debug!(dir = %dir, change = ?change, "cache: patch");
+        if let Some(mut listing) = self.inner.get(dir).await {
+            change.apply(&mut listing.files);
+            disambiguate(&mut listing.files, self.duplicate_names);
+            self.inner.insert(dir.to_string(), listing).await;
+        }
+        self.overlay
+            .entry(dir.to_string())
+            .or_default()
+            .push(PendingChange { change, at: Instant::now() });
+        self.clear_missing(dir).await;
+    }
+
+    /// Shows a created, uploaded, renamed or moved entry in `dir` right away.
+    pub async fn put_entry(&self, dir: &Path, mut file: QuarkFile) {
+        let dir = dir.to_string_lossy();
+        file.parent_path = Some(dir.to_string());
+        if file.dir {
+            self.index.add_dir(&dir, &file);
+        }
+        self.patch(&dir, Change::Put(file)).await;
+    }
+
+    /// Hides a deleted or moved away entry from `dir` right away.
+    pub async fn remove_entry(&self, dir: &Path, fid: &str) {
+        self.patch(&dir.to_string_lossy(), Change::Remove(fid.to_string())).await;
+    }
+
+    /// Drops the listings of `path` and every directory below it, the path index is kept.
+    async fn invalidate_subtree(&self, path: &str) {
+        let prefix = format!("{}/", path);
+        let keys: Vec<String> = self
+            .inner
+            .iter()
+            .map(|(key, _)| key.as_ref().clone())
+            .filter(|key| key == path || key.starts_with(&prefix))
+            .collect();
+        for key in keys {
+            self.inner.invalidate(&key).await;
+        }
+    }
+
+    /// Clears listings only, the path index is kept so directories can be re-listed by fid.
     pub fn invalidate_all(&self) {
         debug!("cache: invalidate all");
         self.inner.invalidate_all();
+        self.missing.invalidate_all();
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::create_test_drive;
+
+    // --- child_path tests ---
+
+    #[test]
+    fn test_child_path() {
+        assert_eq!(child_path("/", "a"), "/a");
+        assert_eq!(child_path("/a", "b"), "/a/b");
+    }
+
+    // --- PathIndex tests ---
+
+    #[test]
+    fn test_index_records_child_dirs_only() {
+        let index = PathIndex::default();
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d1".into(), "movies".into(), "0".into(), "/".into()), QuarkFile::new_file("f1", "a.txt", "/")]);
+        assert_eq!(index.fid("/"), Some("0".to_string()));
+        assert_eq!(index.fid("/movies"), Some("d1".to_string()));
+        assert!(index.fid("/a.txt").is_none());
+    }
+
+    #[test]
+    fn test_index_paths_by_fid() {
+        let index = PathIndex::default();
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d1".into(), "movies".into(), "0".into(), "/".into())]);
+        index.record_listing("/movies", "d1", &[QuarkFile::new_dir("d2".into(), "2024".into(), "0".into(), "/".into())]);
+        let paths = index.paths_by_fid();
+        assert_eq!(paths.get("0").map(String::as_str), Some("/"));
+        assert_eq!(paths.get("d2").map(String::as_str), Some("/movies/2024"));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_index_records_folder_path() {
+        let index = PathIndex::default();
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d1".into(), "a".into(), "0".into(), "/".into())]);
+        let folder = |fid: &str, name: &str| PathFolder { fid: fid.to_string(), file_name: name.to_string() };
+        let path = index.record_folder_path(&[folder("d1", "a"), folder("d2", "b")]);
+        assert_eq!(path, "/a/b");
+        assert_eq!(index.fid("/a/b"), Some("d2".to_string()));
+        // a later listing of /a without b forgets it again
+        index.record_listing("/a", "d1", &[]);
+        assert!(index.fid("/a/b").is_none());
+    }
+
+    #[test]
+    fn test_index_forget_subtree() {
+        let index = PathIndex::default();
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d1".into(), "a".into(), "0".into(), "/".into()), QuarkFile::new_dir("d2".into(), "other".into(), "0".into(), "/".into())]);
+        index.record_listing("/a", "d1", &[QuarkFile::new_dir("d3".into(), "b".into(), "0".into(), "/".into())]);
+        index.record_listing("/a/b", "d3", &[QuarkFile::new_dir("d4".into(), "c".into(), "0".into(), "/".into())]);
+        index.forget("/a");
+        assert!(index.fid("/a").is_none());
+        assert!(index.fid("/a/b").is_none());
+        assert!(index.fid("/a/b/c").is_none());
+        assert_eq!(index.fid("/other"), Some("d2".to_string()));
+    }
+
+    #[test]
+    fn test_index_relisting_drops_removed_dirs() {
+        let index = PathIndex::default();
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d1".into(), "a".into(), "0".into(), "/".into()), QuarkFile::new_dir("d2".into(), "b".into(), "0".into(), "/".into())]);
+        index.record_listing("/a", "d1", &[QuarkFile::new_dir("d3".into(), "deep".into(), "0".into(), "/".into())]);
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d2".into(), "b".into(), "0".into(), "/".into())]);
+        assert!(index.fid("/a").is_none());
+        assert!(index.fid("/a/deep").is_none());
+        assert_eq!(index.fid("/b"), Some("d2".to_string()));
+    }
+
+    #[test]
+    fn test_index_replaced_dir_gets_new_fid() {
+        let index = PathIndex::default();
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d1".into(), "a".into(), "0".into(), "/".into())]);
+        index.record_listing("/a", "d1", &[QuarkFile::new_dir("d3".into(), "deep".into(), "0".into(), "/".into())]);
+        index.record_listing("/", "0", &[QuarkFile::new_dir("d9".into(), "a".into(), "0".into(), "/".into())]);
+        assert_eq!(index.fid("/a"), Some("d9".to_string()));
+        assert!(index.fid("/a/deep").is_none());
+    }
+
+    // --- revalidation tests ---
+
+    #[test]
+    fn test_changed_dirs() {
+        let mut touched = QuarkFile::new_dir("d2".into(), "touched".into(), "0".into(), "/".into());
+        let old = vec![
+            QuarkFile::new_dir("d1".into(), "same".into(), "0".into(), "/".into()),
+            touched.clone(),
+            QuarkFile::new_dir("d3".into(), "removed".into(), "0".into(), "/".into()),
+            QuarkFile::new_dir("d4".into(), "replaced".into(), "0".into(), "/".into()),
+            QuarkFile::new_file("f1", "file.txt", "/"),
+        ];
+        touched.updated_at = 10;
+        let new = vec![
+            QuarkFile::new_dir("d1".into(), "same".into(), "0".into(), "/".into()),
+            touched,
+            QuarkFile::new_dir("d9".into(), "replaced".into(), "0".into(), "/".into()),
+            QuarkFile::new_file("f2", "file.txt", "/"),
+        ];
+        let mut changed = changed_dirs(&old, &new);
+        changed.sort();
+        assert_eq!(changed, vec!["removed", "replaced", "touched"]);
+    }
+
+    #[test]
+    fn test_listing_age() {
+        let fresh = Listing { files: vec![], fetched_at: Some(Instant::now()) };
+        assert!(!fresh.is_older_than(Duration::from_secs(60)));
+        assert!(fresh.is_older_than(Duration::ZERO));
+        let restored = Listing { files: vec![], fetched_at: None };
+        assert!(restored.is_older_than(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_subtree_keeps_siblings() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        for key in ["/a", "/a/b", "/a/b/c", "/ab", "/other"] {
+            cache.insert(key.to_string(), vec![]).await;
+        }
+        cache.invalidate_subtree("/a/b").await;
+        assert!(cache.get("/a").await.is_some());
+        assert!(cache.get("/a/b").await.is_none());
+        assert!(cache.get("/a/b/c").await.is_none());
+        assert!(cache.get("/ab").await.is_some());
+        assert!(cache.get("/other").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fresh_hit_does_not_revalidate() {
+        let mut cache = Cache::new(100, 60, create_test_drive());
+        cache.set_refresh_after(60);
+        cache.index.record_listing("/", "0", &[]);
+        cache.insert("/".to_string(), vec![QuarkFile::new_file("f1", "a.txt", "/")]).await;
+        let files = cache.get_or_insert("/").await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(cache.revalidating.is_empty());
+        assert!(cache.accessed.contains("/"));
+    }
+
+    // --- ListingPager tests ---
+
+    fn pager_at(total: u32, seen: usize, page_no: u32) -> ListingPager {
+        let mut pager = ListingPager::new(create_test_drive(), "d1", "/big");
+        pager.total = total;
+        pager.page_no = page_no;
+        pager.seen = (0..seen).map(|i| i.to_string()).collect();
+        pager
+    }
+
+    #[test]
+    fn test_pager_small_dir_done_on_short_page() {
+        let mut pager = pager_at(120, 120, 1);
+        pager.advance(120);
+        assert!(pager.done);
+    }
+
+    #[test]
+    fn test_pager_next_page() {
+        let mut pager = pager_at(1200, 500, 1);
+        pager.advance(500);
+        assert!(!pager.done);
+        assert_eq!(pager.page_no, 2);
+        assert_eq!(pager.order, 0);
+    }
+
+    #[test]
+    fn test_pager_falls_back_past_cap() {
+        let mut pager = pager_at(15_000, 10_000, MAX_PAGES);
+        pager.advance(500);
+        assert!(!pager.done);
+        assert_eq!(pager.order, 1);
+        assert_eq!(pager.page_no, 1);
+
+        // the server may also cut the page short at the cap
+        let mut pager = pager_at(15_000, 10_000, MAX_PAGES + 1);
+        pager.advance(0);
+        assert_eq!(pager.order, 1);
+    }
+
+    #[test]
+    fn test_pager_stops_once_all_seen() {
+        let mut pager = pager_at(15_000, 15_000, 10);
+        pager.order = 1;
+        pager.advance(500);
+        assert!(pager.done);
+    }
+
+    #[test]
+    fn test_pager_gives_up_after_all_orders() {
+        let mut pager = pager_at(50_000, 40_000, MAX_PAGES);
+        pager.order = FALLBACK_SORTS.len();
+        pager.advance(500);
+        assert!(pager.done);
+        assert!(!pager.missing);
+    }
+
+    // --- coalescing and negative cache tests ---
+
+    #[tokio::test]
+    async fn test_missing_path_short_circuits() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        cache.missing.insert("/a/nope".to_string(), ()).await;
+        assert!(cache.get_or_insert("/a/nope").await.is_none());
+        assert!(cache.stream_listing("/a/nope").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_clears_missing_below_dir() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        for key in ["/a/new", "/a/new/deeper", "/ab", "/other"] {
+            cache.missing.insert(key.to_string(), ()).await;
+        }
+        cache.invalidate(Path::new("/a")).await;
+        assert!(!cache.missing.contains_key("/a/new"));
+        assert!(!cache.missing.contains_key("/a/new/deeper"));
+        assert!(cache.missing.contains_key("/ab"));
+        assert!(cache.missing.contains_key("/other"));
+    }
+
+    #[tokio::test]
+    async fn test_store_clears_listed_dirs() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        cache.missing.insert("/a/sub".to_string(), ()).await;
+        cache.missing.insert("/a/gone".to_string(), ()).await;
+        cache.store("/a", "d1", vec![QuarkFile::new_dir("d2".into(), "sub".into(), "0".into(), "/".into())]).await;
+        assert!(!cache.missing.contains_key("/a/sub"));
+        assert!(cache.missing.contains_key("/a/gone"));
+    }
+
+    #[tokio::test]
+    async fn test_waiter_reuses_concurrent_fetch() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        let lock = cache.inflight_lock("/a");
+        let guard = lock.lock().await;
+        let waiter = {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.fetch_coalesced("d1", "/a").await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        cache.insert("/a".to_string(), vec![QuarkFile::new_file("f1", "a.txt", "/")]).await;
+        drop(guard);
+        drop(lock);
+        let files = waiter.await.unwrap().unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(cache.inflight.is_empty());
+    }
+
+    // --- write overlay tests ---
+
+    #[tokio::test]
+    async fn test_put_and_remove_patch_cached_listing() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        cache.insert("/a".to_string(), vec![QuarkFile::new_file("f1", "old.txt", "/")]).await;
+        cache.put_entry(Path::new("/a"), QuarkFile::new_file("f1", "new.txt", "/")).await;
+        cache.put_entry(Path::new("/a"), QuarkFile::new_dir("d2".into(), "sub".into(), "0".into(), "/".into())).await;
+        let names: Vec<String> = cache.get("/a").await.unwrap().into_iter().map(|f| f.file_name).collect();
+        assert_eq!(names, vec!["new.txt", "sub"]);
+        assert_eq!(cache.index.fid("/a/sub"), Some("d2".to_string()));
+
+        cache.remove_entry(Path::new("/a"), "f1").await;
+        let files = cache.get("/a").await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].parent_path.as_deref(), Some("/a"));
+    }
+
+    #[tokio::test]
+    async fn test_overlay_wins_over_stale_server_listing() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        cache.put_entry(Path::new("/a"), QuarkFile::new_file("f2", "report.txt", "/")).await;
+        cache.remove_entry(Path::new("/a"), "f3").await;
+        // the server still returns the replaced file and the deleted one
+        let stale = vec![QuarkFile::new_file("f1", "report.txt", "/"), QuarkFile::new_file("f3", "deleted.txt", "/")];
+        cache.store("/a", "d1", stale).await;
+        let files = cache.get("/a").await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].fid, "f2");
+    }
+
+    #[tokio::test]
+    async fn test_overlay_expires() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        let at = Instant::now().checked_sub(OVERLAY_WINDOW).unwrap();
+        cache.overlay.insert(
+            "/a".to_string(),
+            vec![PendingChange { change: Change::Remove("f1".to_string()), at }],
+        );
+        cache.store("/a", "d1", vec![QuarkFile::new_file("f1", "a.txt", "/")]).await;
+        assert_eq!(cache.get("/a").await.unwrap().len(), 1);
+        assert!(cache.overlay.is_empty());
+    }
+
+    // --- duplicate name tests ---
+
+    #[tokio::test]
+    async fn test_listing_disambiguates_duplicates() {
+        let cache = Cache::new(100, 60, create_test_drive());
+        let mut older = QuarkFile::new_file("f2", "a.txt", "/");
+        older.created_at = 10;
+        let mut newer = QuarkFile::new_file("f1", "a.txt", "/");
+        newer.created_at = 20;
+        cache.store("/a", "d1", vec![newer, older]).await;
+        let files = cache.get("/a").await.unwrap();
+        let names: Vec<(&str, &str)> = files.iter().map(|f| (f.fid.as_str(), f.file_name.as_str())).collect();
+        assert_eq!(names, vec![("f1", "a (2).txt"), ("f2", "a.txt")]);
+
+        // deleting the older one hands the plain name on
+        cache.remove_entry(Path::new("/a"), "f2").await;
+        let files = cache.get("/a").await.unwrap();
+        assert_eq!(files[0].file_name, "a.txt");
+        assert!(files[0].real_name.is_none());
+    }
+}
@@ -0,0 +1,54 @@
+use crate::stream::{now_secs, StreamSigner};
+
+/// How long a token handed out with a page stays good for at least.
+const WINDOW: u64 = 3600;
+
+/// Tokens guarding the forms of the browser UI against cross-site posts.
+///
+/// A token is a [`StreamSigner`] token over the user it was issued to. It is
+/// the same for a whole hour, so the page it is embedded in keeps its ETag,
+/// and accepted for an hour after that.
+#[derive(Clone)]
+pub struct CsrfTokens {
+    signer: StreamSigner,
+}
+
+impl Default for CsrfTokens {
+    fn default() -> Self {
+        Self {
+            signer: StreamSigner::with_random_secret(2 * WINDOW, None),
+        }
+    }
+}
+
+impl CsrfTokens {
+    /// The token for pages served to `principal`, empty without authentication.
+    pub fn issue(&self, principal: &str) -> String {
+        let now = now_secs();
+        self.signer.sign(&format!("csrf:{}", principal), now - now % WINDOW)
+    }
+
+    pub fn verify(&self, token: &str, principal: &str) -> bool {
+        self.signer
+            .verify(token, now_secs())
+            .is_some_and(|subject| subject.strip_prefix("csrf:") == Some(principal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csrf_tokens() {
+        let tokens = CsrfTokens::default();
+        let token = tokens.issue("alice");
+        assert_eq!(token, tokens.issue("alice"));
+        assert!(tokens.verify(&token, "alice"));
+        assert!(!tokens.verify(&token, "bob"));
+        assert!(!tokens.verify(&token, ""));
+        assert!(!tokens.verify("", "alice"));
+        assert!(!CsrfTokens::default().verify(&token, "alice"));
+        assert!(tokens.verify(&tokens.issue(""), ""));
+    }
+}
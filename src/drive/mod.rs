@@ -36,6 +36,11 @@ pub use model::{QuarkFile};
 const ORIGIN: &str = "https://pan.quark.cn";
 const REFERER: &str = "https://pan.quark.cn/";
 const UA: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) quark-cloud-drive/2.5.20 Chrome/100.0.4896.160 Electron/18.3.5.4-b478491100 Safari/537.36 Channel/pckk_other_ch";
+pub const DEFAULT_FILES_SORT: &str = "file_type:asc,updated_at:desc";
+const TASK_MAX_POLLS: u32 = 30;
+const TASK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const SHARES_PAGE_SIZE: u32 = 50;
+const OFFLINE_PAGE_SIZE: u32 = 50;
 
 
 #[derive(Debug, Clone)]
@@ -68,6 +73,15 @@ impl DavMetaData for QuarkFile {
     fn created(&self) -> FsResult<SystemTime> {
         Ok(SystemTime::UNIX_EPOCH + Duration::from_millis(self.created_at))
     }
+
+    /// Strong tag: the content hash the drive keeps for a file, otherwise the
+    /// fid with the modification time, which is all a folder has.
+    fn etag(&self) -> Option<String> {
+        match &self.content_hash {
+            Some(hash) if !self.dir && !hash.is_empty() => Some(hash.clone()),
+            _ => Some(format!("{}-{:x}", self.fid, self.updated_at)),
+        }
+    }
 }
 
 impl DavDirEntry for QuarkFile {
@@ -319,15 +333,21 @@ impl QuarkDrive {
 
 
     pub async fn get_files_by_pdir_fid(&self, pdir_fid: &str, page:u32, size:u32) -> Result<(Option<QuarkFiles>, u32)> {
-        debug!(pdir_fid = %pdir_fid, page = %page, size = %size,  "get file");
+        self.get_files_by_pdir_fid_sorted(pdir_fid, page, size, DEFAULT_FILES_SORT).await
+    }
+
+    /// Lists a page of `pdir_fid` in the given `_sort` order, e.g. `file_name:asc`.
+    pub async fn get_files_by_pdir_fid_sorted(&self, pdir_fid: &str, page:u32, size:u32, sort: &str) -> Result<(Option<QuarkFiles>, u32)> {
+        debug!(pdir_fid = %pdir_fid, page = %page, size = %size, sort = %sort, "get file");
 
         let res: Result<GetFilesResponse> = self
             .get_request(
-                format!("{}/1/clouddrive/file/sort?pr=ucpro&fr=pc&&pdir_fid={}&_page={}&_size={}&_fetch_total=1&_fetch_sub_dirs=0&_sort=file_type:asc,updated_at:desc,"
+                format!("{}/1/clouddrive/file/sort?pr=ucpro&fr=pc&&pdir_fid={}&_page={}&_size={}&_fetch_total=1&_fetch_sub_dirs=0&_sort={},"
                         , self.config.api_base_url
                         , pdir_fid
                         , page
-                        , size),
+                        , size
+                        , sort),
                 None
             )
             .await
@@ -351,6 +371,40 @@ impl QuarkDrive {
         }
     }
 
+    /// The folders from the root down to `fid`, itself included, for an entry
+    /// found by fid whose folder was never listed.
+    pub async fn folder_path(&self, fid: &str) -> Result<Vec<PathFolder>> {
+        debug!(fid = %fid, "get folder path");
+        let res: FolderPathResponse = self
+            .get_request(
+                format!("{}/1/clouddrive/file/sort?pr=ucpro&fr=pc&pdir_fid={}&_page=1&_size=1&_fetch_total=1&_fetch_sub_dirs=0&_fetch_full_path=1"
+                        , self.config.api_base_url
+                        , fid),
+                None
+            )
+            .await?
+            .context("expect response")?;
+        Ok(res.data.full_path)
+    }
+
+    /// A page of the drive's own search over file and folder names, newest first.
+    pub async fn search(&self, keyword: &str, page: u32, size: u32) -> Result<QuarkFiles> {
+        debug!(keyword = %keyword, page = %page, size = %size, "search files");
+        let keyword = percent_encoding::utf8_percent_encode(keyword, percent_encoding::NON_ALPHANUMERIC);
+        let res: GetFilesResponse = self
+            .get_request(
+                format!("{}/1/clouddrive/file/search?pr=ucpro&fr=pc&q={}&_page={}&_size={}&_fetch_total=1&_sort=file_type:desc,updated_at:desc&_is_hl=0"
+                        , self.config.api_base_url
+                        , keyword
+                        , page
+                        , size),
+                None
+            )
+            .await?
+            .context("expect response")?;
+        Ok(res.into())
+    }
+
     pub async fn get_download_urls(&self, fids: Vec<String>) -> Result<HashMap<String, String>> {
         debug!(fids = ?fids, "get download url");
         let req = GetFilesDownloadUrlsRequest { fids };
@@ -378,6 +432,16 @@ impl QuarkDrive {
         self.md5_cache.get(fid).map(|v| v.clone())
     }
 
+    pub fn export_md5_cache(&self) -> Vec<(String, String)> {
+        self.md5_cache.iter().map(|e| (e.key().clone(), e.value().clone())).collect()
+    }
+
+    pub fn import_md5_cache(&self, entries: Vec<(String, String)>) {
+        for (fid, md5) in entries {
+            self.md5_cache.insert(fid, md5);
+        }
+    }
+
     pub async fn get_download_url(&self, fid: &str) -> Result<String> {
         debug!(fid = %fid, "get download url");
         self.get_download_urls(vec![fid.to_string()]).await?.iter().next()
@@ -403,6 +467,25 @@ impl QuarkDrive {
         Ok(res.data.into_iter().next().and_then(|item| item.md5))
     }
 
+    /// URL of the thumbnail the drive made of an image or video, `None` for
+    /// anything else.
+    pub async fn get_thumbnail_url(&self, fid: &str) -> Result<Option<String>> {
+        debug!(fid = %fid, "get thumbnail url");
+        let req = GetFilesDownloadUrlsRequest { fids: vec![fid.to_string()] };
+        let res: GetFilesDownloadUrlsResponse = self
+            .post_request(
+                format!(
+                    "{}/1/clouddrive/file/download?pr=ucpro&fr=pc",
+                    self.config.api_base_url
+                ),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        Ok(res.data.into_iter().next().and_then(|item| item.thumbnail).filter(|url| !url.is_empty()))
+    }
+
     pub async fn download<U: IntoUrl>(&self, url: U, range: Option<(u64, usize)>) -> Result<Bytes> {
         use reqwest::header::RANGE;
         let cookie = self.resolve_cookies().await;
@@ -426,6 +509,28 @@ impl QuarkDrive {
         Ok(res.bytes().await?)
     }
 
+    /// Sends a download request and returns the raw response so the body can be streamed.
+    /// `range` is forwarded verbatim as the `Range` header.
+    pub async fn download_response<U: IntoUrl>(&self, url: U, range: Option<&str>, head: bool) -> Result<reqwest::Response> {
+        use reqwest::header::RANGE;
+        let cookie = self.resolve_cookies().await;
+        let url = url.into_url()?;
+        debug!(url = %url, range = ?range, head = head, "download file response");
+        let mut req = if head {
+            self.download_client.head(url)
+        } else {
+            self.download_client.get(url)
+        };
+        if let Some(range) = range {
+            req = req.header(RANGE, range);
+        }
+        let res = req.header("Cookie", cookie).send().await?;
+        // client errors such as 416 are for the caller to pass on as they are
+        let res = if res.status().is_client_error() { res } else { res.error_for_status()? };
+        self.update_cookie_from_response(&res).await;
+        Ok(res)
+    }
+
     pub async fn remove_file(&self, file_id: &str, trash: bool) -> Result<()> {
         // no untrash api in quark
         self.delete_file(file_id).await?;
@@ -503,7 +608,8 @@ impl QuarkDrive {
 
 
 
-    pub async fn create_folder(&self, parent_file_id: &str, name: &str) -> Result<()> {
+    /// Creates a folder and returns its fid.
+    pub async fn create_folder(&self, parent_file_id: &str, name: &str) -> Result<String> {
         debug!(parent_file_id = %parent_file_id, name = %name, "create folder");
         let req = CreateFolderRequest {
             pdir_fid: parent_file_id.to_string(),
@@ -522,7 +628,7 @@ impl QuarkDrive {
         if res.status != 200 {
             return Err(anyhow::anyhow!("delete file failed: {}", res.message));
         }
-        Ok(())
+        Ok(res.data.fid)
     }
 
 
@@ -543,6 +649,289 @@ impl QuarkDrive {
         ))
     }
 
+    /// Waits for a background task of the drive, such as creating a share or
+    /// saving one, to finish.
+    async fn wait_task(&self, task_id: &str) -> Result<TaskData> {
+        for retry_index in 0..TASK_MAX_POLLS {
+            let res: TaskResponse = self
+                .get_request(
+                    format!("{}/1/clouddrive/task?pr=ucpro&fr=pc&task_id={}&retry_index={}"
+                            , self.config.api_base_url
+                            , task_id
+                            , retry_index),
+                    None
+                )
+                .await?
+                .context("expect response")?;
+            if res.data.status == 2 {
+                return Ok(res.data);
+            }
+            time::sleep(TASK_POLL_INTERVAL).await;
+        }
+        Err(anyhow::anyhow!("task {} did not finish", task_id))
+    }
+
+    /// Shares `fid` under `title`, with a passcode when one is given, for
+    /// `expire_days` of 1, 7 or 30 days or for good.
+    pub async fn create_share(&self, fid: &str, title: &str, passcode: Option<&str>, expire_days: Option<u32>) -> Result<ShareInfo> {
+        debug!(fid = %fid, title = %title, expire_days = ?expire_days, "create share");
+        let expired_type = match expire_days {
+            None => 1,
+            Some(1) => 2,
+            Some(7) => 3,
+            Some(30) => 4,
+            Some(days) => return Err(anyhow::anyhow!("shares expire after 1, 7 or 30 days, not {}", days)),
+        };
+        let req = CreateShareRequest {
+            fid_list: vec![fid.to_string()],
+            title: title.to_string(),
+            url_type: if passcode.is_some() { 2 } else { 1 },
+            expired_type,
+            passcode: passcode.map(str::to_string),
+        };
+        let res: TaskIdResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/share?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        if res.status != 200 {
+            return Err(anyhow::anyhow!("create share failed: {}", res.message));
+        }
+        let share_id = self
+            .wait_task(&res.data.task_id)
+            .await?
+            .share_id
+            .context("share task without share_id")?;
+        let req = SharePasswordRequest { share_id };
+        let res: ShareResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/share/password?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        let mut share = res.data;
+        if share.first_fid.is_empty() {
+            share.first_fid = fid.to_string();
+        }
+        Ok(share)
+    }
+
+    /// All share links of the account, newest first.
+    pub async fn list_shares(&self) -> Result<Vec<ShareInfo>> {
+        let mut shares = Vec::new();
+        for page in 1.. {
+            debug!(page = %page, "list shares");
+            let res: ListSharesResponse = self
+                .get_request(
+                    format!("{}/1/clouddrive/share/mypage/detail?pr=ucpro&fr=pc&_page={}&_size={}&_order_field=created_at&_order_type=desc&_fetch_total=1"
+                            , self.config.api_base_url
+                            , page
+                            , SHARES_PAGE_SIZE),
+                    None
+                )
+                .await?
+                .context("expect response")?;
+            let count = res.data.list.len();
+            shares.extend(res.data.list);
+            if count < SHARES_PAGE_SIZE as usize || shares.len() >= res.metadata.total as usize {
+                break;
+            }
+        }
+        Ok(shares)
+    }
+
+    pub async fn delete_share(&self, share_id: &str) -> Result<()> {
+        debug!(share_id = %share_id, "delete share");
+        let req = DeleteSharesRequest {
+            share_ids: vec![share_id.to_string()],
+        };
+        let res: CommonResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/share/delete?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        if res.status != 200 {
+            return Err(anyhow::anyhow!("delete share failed: {}", res.message));
+        }
+        Ok(())
+    }
+
+    /// Has the drive download `url`, an HTTP(S) URL or magnet link, into the
+    /// folder `to_pdir_fid`. Returns the task id.
+    pub async fn add_offline_task(&self, url: &str, to_pdir_fid: &str) -> Result<String> {
+        debug!(url = %url, to_pdir_fid = %to_pdir_fid, "add offline task");
+        let req = CreateOfflineTaskRequest {
+            url: url.to_string(),
+            to_pdir_fid: to_pdir_fid.to_string(),
+        };
+        let res: TaskIdResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/offline/task/create?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        if res.status != 200 {
+            return Err(anyhow::anyhow!("add offline task failed: {}", res.message));
+        }
+        Ok(res.data.task_id)
+    }
+
+    pub async fn list_offline_tasks(&self) -> Result<Vec<OfflineTask>> {
+        let mut tasks = Vec::new();
+        for page in 1.. {
+            debug!(page = %page, "list offline tasks");
+            let res: OfflineTasksResponse = self
+                .get_request(
+                    format!("{}/1/clouddrive/offline/task/list?pr=ucpro&fr=pc&_page={}&_size={}&_fetch_total=1"
+                            , self.config.api_base_url
+                            , page
+                            , OFFLINE_PAGE_SIZE),
+                    None
+                )
+                .await?
+                .context("expect response")?;
+            let count = res.data.list.len();
+            tasks.extend(res.data.list);
+            if count < OFFLINE_PAGE_SIZE as usize || tasks.len() >= res.metadata.total as usize {
+                break;
+            }
+        }
+        Ok(tasks)
+    }
+
+    pub async fn cancel_offline_task(&self, task_id: &str) -> Result<()> {
+        debug!(task_id = %task_id, "cancel offline task");
+        let req = DeleteOfflineTasksRequest {
+            task_ids: vec![task_id.to_string()],
+        };
+        let res: CommonResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/offline/task/delete?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        if res.status != 200 {
+            return Err(anyhow::anyhow!("cancel offline task failed: {}", res.message));
+        }
+        Ok(())
+    }
+
+    /// URL of the HLS playlist the drive transcoded video `fid` to at
+    /// `resolution`, one of low, normal, high, super, 2k and 4k.
+    pub async fn play_url(&self, fid: &str, resolution: &str) -> Result<String> {
+        debug!(fid = %fid, resolution = %resolution, "play url");
+        let req = PlayRequest {
+            fid: fid.to_string(),
+            resolutions: "low,normal,high,super,2k,4k".to_string(),
+            supports: "fmp4".to_string(),
+        };
+        let res: PlayResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/file/v2/play?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        if res.status != 200 {
+            return Err(anyhow::anyhow!("play failed: {}", res.message));
+        }
+        res.data
+            .video_list
+            .into_iter()
+            .filter(|video| video.resolution == resolution)
+            .find_map(|video| video.video_info.map(|info| info.url).filter(|url| !url.is_empty()))
+            .ok_or_else(|| anyhow::anyhow!("no {} transcode of {}", resolution, fid))
+    }
+
+    /// Opens someone else's share link `pwd_id`, returns the token to list and save it with.
+    pub async fn share_token(&self, pwd_id: &str, passcode: Option<&str>) -> Result<String> {
+        debug!(pwd_id = %pwd_id, "share token");
+        let req = ShareTokenRequest {
+            pwd_id: pwd_id.to_string(),
+            passcode: passcode.unwrap_or_default().to_string(),
+        };
+        let res: ShareTokenResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/share/sharepage/token?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        if res.status != 200 {
+            return Err(anyhow::anyhow!("open share failed: {}", res.message));
+        }
+        Ok(res.data.stoken)
+    }
+
+    /// Entries of the folder `pdir_fid` of a share link, "0" being its top.
+    pub async fn shared_files(&self, pwd_id: &str, stoken: &str, pdir_fid: &str) -> Result<Vec<SharedFile>> {
+        let stoken = percent_encoding::utf8_percent_encode(stoken, percent_encoding::NON_ALPHANUMERIC);
+        let mut files = Vec::new();
+        for page in 1.. {
+            debug!(pwd_id = %pwd_id, pdir_fid = %pdir_fid, page = %page, "list shared files");
+            let res: SharedFilesResponse = self
+                .get_request(
+                    format!("{}/1/clouddrive/share/sharepage/detail?pr=ucpro&fr=pc&pwd_id={}&stoken={}&pdir_fid={}&force=0&_page={}&_size={}&_fetch_total=1&_sort=file_type:asc,file_name:asc"
+                            , self.config.api_base_url
+                            , pwd_id
+                            , stoken
+                            , pdir_fid
+                            , page
+                            , SHARES_PAGE_SIZE),
+                    None
+                )
+                .await?
+                .context("expect response")?;
+            let count = res.data.list.len();
+            files.extend(res.data.list);
+            if count < SHARES_PAGE_SIZE as usize || files.len() >= res.metadata.total as usize {
+                break;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Copies `files` of a share link into the folder `to_pdir_fid`, returns the fids of the copies.
+    pub async fn save_shared_files(&self, pwd_id: &str, stoken: &str, files: &[SharedFile], to_pdir_fid: &str) -> Result<Vec<String>> {
+        debug!(pwd_id = %pwd_id, count = files.len(), to_pdir_fid = %to_pdir_fid, "save shared files");
+        let req = SaveSharedFilesRequest {
+            fid_list: files.iter().map(|f| f.fid.clone()).collect(),
+            fid_token_list: files.iter().map(|f| f.share_fid_token.clone()).collect(),
+            to_pdir_fid: to_pdir_fid.to_string(),
+            pwd_id: pwd_id.to_string(),
+            stoken: stoken.to_string(),
+            pdir_fid: "0".to_string(),
+            scene: "link".to_string(),
+        };
+        let res: TaskIdResponse = self
+            .post_request(
+                format!("{}/1/clouddrive/share/sharepage/save?pr=ucpro&fr=pc", self.config.api_base_url),
+                &req,
+                None
+            )
+            .await?
+            .context("expect response")?;
+        if res.status != 200 {
+            return Err(anyhow::anyhow!("save shared files failed: {}", res.message));
+        }
+        let task = self.wait_task(&res.data.task_id).await?;
+        Ok(task.save_as.map(|s| s.save_as_top_fids).unwrap_or_default())
+    }
+
     pub async fn up_pre(&self, file_name: &str, size: u64, pdir_fid: &str) -> Result<UpPreResponse> {
 
         let format_type = get_format_type(file_name);
@@ -849,7 +1238,7 @@ impl QuarkDrive {
 }
 
 
-fn get_format_type(file_name: &str) -> &str {
+pub(crate) fn get_format_type(file_name: &str) -> &str {
     if let Some(ext) = file_name.rsplit('.').next() {
         let ext = ext.to_lowercase();
         match ext.as_str() {
@@ -859,6 +1248,10 @@ fn get_format_type(file_name: &str) -> &str {
             "mp4" => "video/mp4",
             "avi" => "video/x-msvideo",
             "mov" => "video/quicktime",
+            "mkv" => "video/x-matroska",
+            "webm" => "video/webm",
+            "flv" => "video/x-flv",
+            "wmv" => "video/x-ms-wmv",
             "mp3" => "audio/mpeg",
             "wav" => "audio/wav",
             "pdf" => "application/pdf",
@@ -876,6 +1269,24 @@ fn get_format_type(file_name: &str) -> &str {
     }
 }
 
+/// A drive with a dummy cookie talking to the real API, for tests.
+#[cfg(test)]
+pub(crate) fn create_test_drive() -> QuarkDrive {
+    create_test_drive_at("https://drive.quark.cn")
+}
+
+/// A drive with a dummy cookie talking to `api_base_url`, for tests.
+#[cfg(test)]
+pub(crate) fn create_test_drive_at(api_base_url: &str) -> QuarkDrive {
+    let cookie = Arc::new(DashMap::new());
+    cookie.insert("test".to_string(), "value".to_string());
+    let config = DriveConfig {
+        api_base_url: api_base_url.to_string(),
+        cookie,
+    };
+    QuarkDrive::new(config).unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -896,6 +1307,7 @@ mod tests {
         assert_eq!(get_format_type("movie.mp4"), "video/mp4");
         assert_eq!(get_format_type("movie.avi"), "video/x-msvideo");
         assert_eq!(get_format_type("movie.mov"), "video/quicktime");
+        assert_eq!(get_format_type("movie.MKV"), "video/x-matroska");
     }
 
     #[test]
@@ -931,13 +1343,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_up_part_auth_meta_format() {
-        let cookie = Arc::new(DashMap::new());
-        cookie.insert("test".to_string(), "value".to_string());
-        let config = DriveConfig {
-            api_base_url: "https://drive.quark.cn".to_string(),
-            cookie,
-        };
-        let drive = QuarkDrive::new(config).unwrap();
+        let drive = create_test_drive();
 
         let result = drive
             .up_part_auth_meta(
@@ -1002,16 +1408,6 @@ mod tests {
 
     // --- Helper ---
 
-    fn create_test_drive() -> QuarkDrive {
-        let cookie = Arc::new(DashMap::new());
-        cookie.insert("test".to_string(), "value".to_string());
-        let config = DriveConfig {
-            api_base_url: "https://drive.quark.cn".to_string(),
-            cookie,
-        };
-        QuarkDrive::new(config).unwrap()
-    }
-
     fn create_drive_from_env() -> QuarkDrive {
         let cookie_str = std::env::var("QUARK_COOKIE").unwrap();
         let cookie = Arc::new(DashMap::new());
@@ -1060,6 +1456,22 @@ mod tests {
         assert_eq!(drive.get_cached_md5("fid_1"), Some("new_md5".to_string()));
     }
 
+    // --- etag tests ---
+
+    #[test]
+    fn test_quark_file_etag() {
+        let mut file = QuarkFile::new_dir("fid_1".to_string(), "docs".to_string(), "0".to_string(), "/".to_string());
+        file.updated_at = 0x1234;
+        assert_eq!(file.etag(), Some("fid_1-1234".to_string()));
+        file.dir = false;
+        file.file = true;
+        assert_eq!(file.etag(), Some("fid_1-1234".to_string()));
+        file.content_hash = Some("abc123sha1hash".to_string());
+        assert_eq!(file.etag(), Some("abc123sha1hash".to_string()));
+        file.updated_at = 0x5678;
+        assert_eq!(file.etag(), Some("abc123sha1hash".to_string()));
+    }
+
     // --- up_commit_auth_meta tests ---
 
     #[test]
@@ -1133,6 +1545,18 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    #[ignore]
+    async fn test_search() {
+        let drive = create_drive_from_env();
+        let (root_files, _) = drive.get_files_by_pdir_fid("0", 1, 50).await.unwrap();
+        let Some(name) = root_files.unwrap().list.into_iter().map(|f| f.file_name).next() else {
+            return;
+        };
+        let files = drive.search(&name, 1, 50).await.unwrap();
+        assert!(files.list.iter().any(|f| f.file_name == name), "search should find {}", name);
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_list_nonexistent_dir() {
@@ -28,6 +28,9 @@ pub struct QuarkFile {
     pub download_url:Option<String>,
     pub content_hash: Option<String>,
     pub parent_path: Option<String>,
+    // name on the drive when `file_name` was changed to tell duplicates apart
+    #[serde(skip)]
+    pub real_name: Option<String>,
 }
 
 
@@ -48,6 +51,27 @@ impl QuarkFile {
             fid: "0".to_string(),
             download_url: None,
             content_hash: None,
+            real_name: None,
+        }
+    }
+
+    pub fn new_dir(fid: String, file_name: String, pdir_fid: String, parent_path: String) -> Self {
+        Self {
+            fid,
+            file_name,
+            pdir_fid,
+            parent_path: Some(parent_path),
+            ..Self::new_root()
+        }
+    }
+
+    /// An empty file `file_name` in `parent_path`, for tests.
+    #[cfg(test)]
+    pub fn new_file(fid: &str, file_name: &str, parent_path: &str) -> Self {
+        Self {
+            dir: false,
+            file: true,
+            ..Self::new_dir(fid.to_string(), file_name.to_string(), "0".to_string(), parent_path.to_string())
         }
     }
 }
@@ -106,6 +130,64 @@ pub struct MoveFileRequest {
     pub to_pdir_fid: String,
 }
 
+#[derive(Debug, Serialize, Clone)]
+pub struct CreateShareRequest {
+    pub fid_list: Vec<String>,
+    pub title: String,
+    /// 1 public, 2 with a passcode
+    pub url_type: u8,
+    /// 1 never, 2 one day, 3 seven days, 4 thirty days
+    pub expired_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passcode: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SharePasswordRequest {
+    pub share_id: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DeleteSharesRequest {
+    pub share_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CreateOfflineTaskRequest {
+    pub url: String,
+    pub to_pdir_fid: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DeleteOfflineTasksRequest {
+    pub task_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PlayRequest {
+    pub fid: String,
+    /// comma separated, the transcodes to return
+    pub resolutions: String,
+    pub supports: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ShareTokenRequest {
+    pub pwd_id: String,
+    pub passcode: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SaveSharedFilesRequest {
+    pub fid_list: Vec<String>,
+    pub fid_token_list: Vec<String>,
+    pub to_pdir_fid: String,
+    pub pwd_id: String,
+    pub stoken: String,
+    pub pdir_fid: String,
+    pub scene: String,
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct UpPreRequest {
     pub file_name: String,
@@ -175,6 +257,24 @@ pub type AuthResponse = Response<AuthResponseData, EmptyMetadata>;
 
 pub type FinishResponse = Response<EmptyData, EmptyMetadata>;
 
+pub type TaskIdResponse = Response<TaskIdData, EmptyMetadata>;
+
+pub type TaskResponse = Response<TaskData, EmptyMetadata>;
+
+pub type ShareResponse = Response<ShareInfo, EmptyMetadata>;
+
+pub type ListSharesResponse = Response<SharesData, FilesMetadata>;
+
+pub type OfflineTasksResponse = Response<OfflineTasksData, FilesMetadata>;
+
+pub type PlayResponse = Response<PlayData, EmptyMetadata>;
+
+pub type ShareTokenResponse = Response<ShareTokenData, EmptyMetadata>;
+
+pub type SharedFilesResponse = Response<SharedFilesData, FilesMetadata>;
+
+pub type FolderPathResponse = Response<FolderPathData, EmptyMetadata>;
+
 
 impl GetFilesDownloadUrlsResponse {
     pub fn into_map(self) -> HashMap<String, String> {
@@ -228,12 +328,152 @@ pub struct DeleteFilesMetadata {
 }
 
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaskIdData {
+    pub task_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaskData {
+    /// 2 once the task is done
+    pub status: u8,
+    #[serde(default)]
+    pub share_id: Option<String>,
+    #[serde(default)]
+    pub save_as: Option<SaveAsData>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SaveAsData {
+    /// fids of the saved copies
+    #[serde(default)]
+    pub save_as_top_fids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShareTokenData {
+    pub stoken: String,
+}
+
+/// An entry of someone else's share link.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedFile {
+    pub fid: String,
+    #[serde(deserialize_with = "deserialize_file_name")]
+    pub file_name: String,
+    #[serde(default)]
+    pub pdir_fid: String,
+    /// has to accompany the fid when saving
+    pub share_fid_token: String,
+    #[serde(default)]
+    pub dir: bool,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SharedFilesData {
+    pub list: Vec<SharedFile>,
+}
+
+/// A share link of the drive; `first_fid` is the shared file or folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareInfo {
+    pub share_id: String,
+    pub share_url: String,
+    #[serde(default)]
+    pub passcode: Option<String>,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub first_fid: String,
+    /// unix milliseconds, 0 for links that do not expire
+    #[serde(default)]
+    pub expired_at: u64,
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SharesData {
+    pub list: Vec<ShareInfo>,
+}
+
+/// A URL or magnet link the drive downloads into `to_pdir_fid`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineTask {
+    pub task_id: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub file_name: String,
+    #[serde(default)]
+    pub to_pdir_fid: String,
+    /// 0 queued, 1 downloading, 2 done, 3 failed
+    pub status: u32,
+    /// percent
+    #[serde(default)]
+    pub progress: u32,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+impl OfflineTask {
+    pub fn is_finished(&self) -> bool {
+        self.status >= 2
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OfflineTasksData {
+    pub list: Vec<OfflineTask>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayData {
+    #[serde(default)]
+    pub video_list: Vec<PlayVideo>,
+}
+
+/// One transcode of a video; `video_info` is missing for resolutions the
+/// drive has not transcoded, or that need a membership.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayVideo {
+    pub resolution: String,
+    #[serde(default)]
+    pub video_info: Option<PlayVideoInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayVideoInfo {
+    /// the HLS playlist
+    #[serde(default)]
+    pub url: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct CreateFolderData {
     pub finish: bool,
     pub fid: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct FolderPathData {
+    /// the folders from the root down to the listed one, itself included
+    #[serde(default)]
+    pub full_path: Vec<PathFolder>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PathFolder {
+    pub fid: String,
+    pub file_name: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct EmptyMetadata {
 
@@ -257,6 +497,9 @@ pub struct FileDownloadUrlItem {
     pub download_url: String,
     #[serde(default)]
     pub md5: Option<String>,
+    /// only for images and videos
+    #[serde(default)]
+    pub thumbnail: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -413,11 +656,13 @@ mod tests {
                     fid: "fid1".to_string(),
                     download_url: "https://example.com/1".to_string(),
                     md5: None,
+                    thumbnail: None,
                 },
                 FileDownloadUrlItem {
                     fid: "fid2".to_string(),
                     download_url: "https://example.com/2".to_string(),
                     md5: None,
+                    thumbnail: None,
                 },
             ],
             metadata: FileDownloadUrlMetadata {},
@@ -516,6 +761,17 @@ mod tests {
         assert!(item.md5.is_none());
     }
 
+    #[test]
+    fn test_file_download_url_item_with_thumbnail() {
+        let json = r#"{
+            "fid": "file_123",
+            "download_url": "https://cdn.example.com/file",
+            "thumbnail": "https://image.example.com/thumb?x=1"
+        }"#;
+        let item: FileDownloadUrlItem = serde_json::from_str(json).unwrap();
+        assert_eq!(item.thumbnail.as_deref(), Some("https://image.example.com/thumb?x=1"));
+    }
+
     #[test]
     fn test_file_download_url_item_null_md5() {
         let json = r#"{
@@ -646,6 +902,81 @@ mod tests {
         assert_eq!(data.use_capacity, 1234567890);
     }
 
+    // --- share tests ---
+
+    #[test]
+    fn test_share_responses() {
+        let json = r#"{
+            "status": 200, "code": 0, "message": "ok", "timestamp": 1700000000,
+            "data": {"status": 2, "task_type": 28, "share_id": "s1"},
+            "metadata": {"tq_gap": 500}
+        }"#;
+        let res: TaskResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(res.data.status, 2);
+        assert_eq!(res.data.share_id.as_deref(), Some("s1"));
+
+        let json = r#"{
+            "status": 200, "code": 0, "message": "ok", "timestamp": 1700000000,
+            "data": {"list": [
+                {"share_id": "s1", "share_url": "https://pan.quark.cn/s/s1", "title": "a.txt",
+                 "first_fid": "f1", "expired_at": 0, "created_at": 1700000000000, "url_type": 1},
+                {"share_id": "s2", "share_url": "https://pan.quark.cn/s/s2", "passcode": "ab12"}
+            ]},
+            "metadata": {"_total": 2, "_count": 2, "_page": 1}
+        }"#;
+        let res: ListSharesResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(res.data.list.len(), 2);
+        assert_eq!(res.data.list[0].first_fid, "f1");
+        assert_eq!(res.data.list[1].passcode.as_deref(), Some("ab12"));
+        assert_eq!(res.metadata.total, 2);
+
+        let req = CreateShareRequest {
+            fid_list: vec!["f1".to_string()],
+            title: "a.txt".to_string(),
+            url_type: 1,
+            expired_type: 1,
+            passcode: None,
+        };
+        let json = serde_json::to_value(&req).unwrap();
+        assert!(json.get("passcode").is_none());
+    }
+
+    #[test]
+    fn test_offline_tasks_response() {
+        let json = r#"{
+            "status": 200, "code": 0, "message": "ok", "timestamp": 1700000000,
+            "data": {"list": [
+                {"task_id": "t1", "url": "magnet:?xt=urn:btih:abc", "file_name": "a.mkv",
+                 "to_pdir_fid": "d1", "status": 1, "progress": 42, "size": 1024, "created_at": 1700000000000},
+                {"task_id": "t2", "status": 2}
+            ]},
+            "metadata": {"_total": 2, "_count": 2, "_page": 1}
+        }"#;
+        let res: OfflineTasksResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(res.data.list.len(), 2);
+        assert_eq!(res.data.list[0].progress, 42);
+        assert!(!res.data.list[0].is_finished());
+        assert!(res.data.list[1].is_finished());
+        assert!(res.data.list[1].to_pdir_fid.is_empty());
+    }
+
+    #[test]
+    fn test_play_response() {
+        let json = r#"{
+            "status": 200, "code": 0, "message": "ok", "timestamp": 1700000000,
+            "data": {"video_list": [
+                {"resolution": "super", "video_info": {"url": "https://video.example.com/a.m3u8?auth_key=x",
+                 "duration": 5400, "width": 1920, "height": 1080, "size": 123}},
+                {"resolution": "4k", "right": "svip"}
+            ]},
+            "metadata": {}
+        }"#;
+        let res: PlayResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(res.data.video_list.len(), 2);
+        assert_eq!(res.data.video_list[0].video_info.as_ref().unwrap().url, "https://video.example.com/a.m3u8?auth_key=x");
+        assert!(res.data.video_list[1].video_info.is_none());
+    }
+
     // --- QuarkFiles from GetFilesResponse ---
 
     #[test]
@@ -0,0 +1,339 @@
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use dav_server::fs::{DavDirEntry, DavMetaData, FsFuture, FsResult};
+use futures_util::FutureExt;
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Response, StatusCode};
+use tracing::{debug, error};
+use url::Url;
+
+use crate::drive::{get_format_type, QuarkDrive, QuarkFile};
+use crate::stream::{now_secs, proxy_response, status_response, StreamSigner};
+use crate::webdav::ResponseBody;
+
+pub const HLS_PATH: &str = "/hls/";
+pub const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+/// Length listed for a playlist. dav-server lists one for every file and the
+/// real one is only known once the playlist is fetched, while clients take a
+/// 0 for an empty file and never fetch it. GET and HEAD answer with the real one.
+pub const PLAYLIST_LISTED_LEN: u64 = 4096;
+
+/// A transcode the drive keeps of a video, listed as `<name>.<resolution>.m3u8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Resolution {
+    Low,
+    Normal,
+    High,
+    Super,
+    #[value(name = "2k")]
+    TwoK,
+    #[value(name = "4k")]
+    FourK,
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Low => "low",
+            Resolution::Normal => "normal",
+            Resolution::High => "high",
+            Resolution::Super => "super",
+            Resolution::TwoK => "2k",
+            Resolution::FourK => "4k",
+        }
+    }
+}
+
+pub fn is_video(file: &QuarkFile) -> bool {
+    !file.dir
+        && (file.format_type.starts_with("video/") || get_format_type(&file.file_name).starts_with("video/"))
+}
+
+/// `files` with a playlist entry per resolution after every video.
+pub fn with_playlists(files: Vec<QuarkFile>, resolutions: &[Resolution]) -> Vec<QuarkFile> {
+    if resolutions.is_empty() {
+        return files;
+    }
+    let mut listed = Vec::with_capacity(files.len());
+    for file in files {
+        // uploads in flight have no transcodes yet
+        let playlists: Vec<QuarkFile> = if !file.fid.is_empty() && is_video(&file) {
+            resolutions.iter().map(|res| playlist_entry(&file, *res)).collect()
+        } else {
+            Vec::new()
+        };
+        listed.push(file);
+        listed.extend(playlists);
+    }
+    listed
+}
+
+/// The virtual playlist of `video` at `resolution`. It is never looked up in
+/// the drive, only by name, and goes by `<video fid>:<resolution>` so it is
+/// not mistaken for the video by clients or locks. Its size is not known
+/// until it is fetched, see [`PlaylistEntry`].
+pub fn playlist_entry(video: &QuarkFile, resolution: Resolution) -> QuarkFile {
+    let mut file = video.clone();
+    file.fid = format!("{}:{}", video.fid, resolution.as_str());
+    file.file_name = format!("{}.{}.m3u8", video.file_name, resolution.as_str());
+    file.size = 0;
+    file.format_type = PLAYLIST_CONTENT_TYPE.to_string();
+    file.download_url = None;
+    file.content_hash = None;
+    file.real_name = None;
+    file
+}
+
+/// Whether `file` is a virtual playlist made by [`playlist_entry`]; fids of
+/// the drive never contain a colon.
+pub fn is_playlist(file: &QuarkFile) -> bool {
+    file.fid.contains(':')
+}
+
+/// Splits `<name>.<resolution>.m3u8` into the name of the video and one of `resolutions`.
+pub fn parse_playlist_name<'a>(name: &'a str, resolutions: &[Resolution]) -> Option<(&'a str, Resolution)> {
+    let (video, resolution) = name.strip_suffix(".m3u8")?.rsplit_once('.')?;
+    let resolution = resolutions.iter().find(|res| res.as_str() == resolution)?;
+    (!video.is_empty()).then_some((video, *resolution))
+}
+
+/// What dav-server lists for a playlist made by [`playlist_entry`]: the
+/// playlist with a length of [`PLAYLIST_LISTED_LEN`].
+#[derive(Clone, Debug)]
+pub struct PlaylistEntry(QuarkFile);
+
+impl DavMetaData for PlaylistEntry {
+    fn len(&self) -> u64 {
+        PLAYLIST_LISTED_LEN
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.0.modified()
+    }
+
+    fn is_dir(&self) -> bool {
+        false
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        self.0.created()
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.0.etag()
+    }
+}
+
+impl DavDirEntry for PlaylistEntry {
+    fn name(&self) -> Vec<u8> {
+        self.0.name()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let meta = self.clone();
+        async move { Ok(Box::new(meta) as Box<dyn DavMetaData>) }.boxed()
+    }
+}
+
+/// `file` as a directory entry of dav-server.
+pub fn dir_entry(file: QuarkFile) -> Box<dyn DavDirEntry> {
+    match is_playlist(&file) {
+        true => Box::new(PlaylistEntry(file)),
+        false => Box::new(file),
+    }
+}
+
+/// The metadata dav-server reports for `file`.
+pub fn metadata(file: QuarkFile) -> Box<dyn DavMetaData> {
+    match is_playlist(&file) {
+        true => Box::new(PlaylistEntry(file)),
+        false => Box::new(file),
+    }
+}
+
+/// Points every URI of a playlist fetched from `base` at `/_quarkdrive/hls/`,
+/// so the player fetches segments, keys and variant playlists through this server.
+pub fn rewrite_playlist(body: &str, base: &Url, signer: &StreamSigner) -> String {
+    let mut out = String::with_capacity(body.len() * 2);
+    for line in body.lines() {
+        let line = line.trim_end();
+        if !line.is_empty() && !line.starts_with('#') {
+            out.push_str(&proxied_url(line, base, signer));
+        } else if let Some(start) = line.find("URI=\"").map(|i| i + 5)
+            && let Some(len) = line[start..].find('"')
+        {
+            out.push_str(&line[..start]);
+            out.push_str(&proxied_url(&line[start..start + len], base, signer));
+            out.push_str(&line[start + len..]);
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn proxied_url(uri: &str, base: &Url, signer: &StreamSigner) -> String {
+    let Ok(url) = base.join(uri) else {
+        return uri.to_string();
+    };
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default()
+        .to_string();
+    signer.signed_url(HLS_PATH, url.as_str(), &name)
+}
+
+fn is_playlist_url(url: &Url) -> bool {
+    url.path().ends_with(".m3u8")
+}
+
+/// Serves the virtual playlists and the `/_quarkdrive/hls/` URLs they point
+/// at, fetching both from the drive's transcodes with the drive cookie.
+#[derive(Clone)]
+pub struct HlsProxy {
+    signer: StreamSigner,
+    drive: QuarkDrive,
+}
+
+impl HlsProxy {
+    pub fn new(signer: StreamSigner, drive: QuarkDrive) -> Self {
+        Self { signer, drive }
+    }
+
+    async fn fetch_playlist(&self, url: &Url) -> anyhow::Result<String> {
+        let body = self.drive.download_response(url.clone(), None, false).await?.error_for_status()?.text().await?;
+        Ok(rewrite_playlist(&body, url, &self.signer))
+    }
+
+    /// The playlist of the `resolution` transcode of video `fid`.
+    pub async fn playlist(&self, method: &Method, fid: &str, resolution: Resolution) -> Response<ResponseBody> {
+        let url = match self.drive.play_url(fid, resolution.as_str()).await {
+            Ok(url) => url,
+            Err(err) => {
+                error!(fid = %fid, resolution = %resolution.as_str(), error = %err, "hls: play url failed");
+                return status_response(StatusCode::NOT_FOUND);
+            }
+        };
+        self.serve_playlist(method, &url).await
+    }
+
+    async fn serve_playlist(&self, method: &Method, url: &str) -> Response<ResponseBody> {
+        let playlist = match Url::parse(url) {
+            Ok(url) => self.fetch_playlist(&url).await,
+            Err(err) => Err(err.into()),
+        };
+        match playlist {
+            Ok(playlist) => playlist_response(method, playlist),
+            Err(err) => {
+                error!(error = %err, "hls: fetch playlist failed");
+                status_response(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+
+    /// Serves a signed `/_quarkdrive/hls/<token>/<name>` URL handed out in a playlist.
+    pub async fn serve(&self, method: &Method, token: &str, range: Option<&str>) -> Response<ResponseBody> {
+        if method != Method::GET && method != Method::HEAD {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let Some(url) = self.signer.verify(token, now_secs()).and_then(|url| Url::parse(&url).ok()) else {
+            debug!(token = %token, "hls: invalid or expired token");
+            return status_response(StatusCode::FORBIDDEN);
+        };
+        if is_playlist_url(&url) {
+            return self.serve_playlist(method, url.as_str()).await;
+        }
+        match self.drive.download_response(url, range, method == Method::HEAD).await {
+            Ok(res) => proxy_response(res, method == Method::HEAD),
+            Err(err) => {
+                error!(error = %err, "hls: upstream request failed");
+                status_response(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+fn playlist_response(method: &Method, playlist: String) -> Response<ResponseBody> {
+    let len = playlist.len();
+    let body = if method == Method::HEAD { Bytes::new() } else { Bytes::from(playlist) };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", PLAYLIST_CONTENT_TYPE)
+        .header("Content-Length", len)
+        .header("Cache-Control", "no-cache")
+        .body(Full::new(body).map_err(|never| match never {}).boxed_unsync())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(name: &str) -> QuarkFile {
+        let mut file = QuarkFile::new_file("f1", name, "/");
+        file.size = 100;
+        file.content_hash = Some("hash".to_string());
+        file
+    }
+
+    #[test]
+    fn test_playlist_names() {
+        let resolutions = [Resolution::High, Resolution::TwoK];
+        let files = with_playlists(vec![video("a.mkv"), video("b.txt")], &resolutions);
+        let names: Vec<_> = files.iter().map(|f| f.file_name.as_str()).collect();
+        assert_eq!(names, vec!["a.mkv", "a.mkv.high.m3u8", "a.mkv.2k.m3u8", "b.txt"]);
+        assert_eq!(files[1].fid, "f1:high");
+        assert!(is_playlist(&files[1]) && !is_playlist(&files[0]));
+        assert_eq!(files[1].size, 0);
+        assert_eq!(files[1].format_type, PLAYLIST_CONTENT_TYPE);
+        assert!(files[1].content_hash.is_none());
+        assert_eq!(with_playlists(vec![video("a.mkv")], &[]).len(), 1);
+
+        assert_eq!(parse_playlist_name("a.mkv.high.m3u8", &resolutions), Some(("a.mkv", Resolution::High)));
+        assert_eq!(parse_playlist_name("a.mkv.2k.m3u8", &resolutions), Some(("a.mkv", Resolution::TwoK)));
+        assert!(parse_playlist_name("a.mkv.low.m3u8", &resolutions).is_none());
+        assert!(parse_playlist_name("a.m3u8", &resolutions).is_none());
+        assert!(parse_playlist_name(".high.m3u8", &resolutions).is_none());
+    }
+
+    #[test]
+    fn test_playlist_metadata() {
+        let files = with_playlists(vec![video("a.mkv")], &[Resolution::High]);
+        assert_eq!(metadata(files[0].clone()).len(), 100);
+        let meta = metadata(files[1].clone());
+        assert_eq!(meta.len(), PLAYLIST_LISTED_LEN);
+        assert!(!meta.is_dir());
+        assert_eq!(dir_entry(files[1].clone()).name(), b"a.mkv.high.m3u8");
+        // a real empty file keeps its length
+        let mut empty = video("b.m3u8");
+        empty.size = 0;
+        assert_eq!(metadata(empty).len(), 0);
+    }
+
+    #[test]
+    fn test_rewrite_playlist() {
+        let signer = StreamSigner::new(b"secret", 60, Some("/dav/_quarkdrive"));
+        let base = Url::parse("https://video.example.com/v/1/index.m3u8?auth_key=k").unwrap();
+        let body = "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x1\n\n#EXTINF:10.0,\nseg-0.ts?t=1\n#EXTINF:10.0,\nhttps://cdn.example.com/seg-1.ts\n#EXT-X-ENDLIST\n";
+        let out = rewrite_playlist(body, &base, &signer);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "#EXTM3U");
+        assert!(lines[1].starts_with("#EXT-X-KEY:METHOD=AES-128,URI=\"/dav/_quarkdrive/hls/"));
+        assert!(lines[1].ends_with("/key%2Ebin\",IV=0x1"));
+        assert_eq!(lines[2], "");
+        assert!(lines[4].starts_with("/dav/_quarkdrive/hls/") && lines[4].ends_with("/seg%2D0%2Ets"));
+        assert_eq!(lines[7], "#EXT-X-ENDLIST");
+
+        let token = StreamSigner::token_from_endpoint_path(lines[4].strip_prefix("/dav/_quarkdrive").unwrap(), HLS_PATH).unwrap();
+        assert_eq!(
+            signer.verify(token, now_secs()).as_deref(),
+            Some("https://video.example.com/v/1/seg-0.ts?t=1")
+        );
+        let token = StreamSigner::token_from_endpoint_path(lines[6].strip_prefix("/dav/_quarkdrive").unwrap(), HLS_PATH).unwrap();
+        assert_eq!(signer.verify(token, now_secs()).as_deref(), Some("https://cdn.example.com/seg-1.ts"));
+    }
+}
@@ -0,0 +1,585 @@
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dav_server::davpath::DavPath;
+use dav_server::ls::{DavLock, DavLockSystem, LsFuture};
+use futures_util::FutureExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+use xmltree::Element;
+
+use crate::snapshot::write_atomic;
+use crate::vfs::QuarkDriveFileSystem;
+
+pub const LOCKS_PATH: &str = "/locks";
+
+/// Timeout of locks requested without one, so a client that crashed while
+/// holding a lock does not keep the file locked forever.
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// A WebDAV lock as kept by [`LockTable`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredLock {
+    pub token: String,
+    /// fid of the locked entry, `None` while a locked new file is still being created
+    pub fid: Option<String>,
+    /// URL path of the locked entry, moved along when the entry is renamed
+    pub path: String,
+    pub principal: Option<String>,
+    /// `DAV:owner` element as sent by the client
+    pub owner: Option<String>,
+    pub timeout_secs: u64,
+    /// unix seconds
+    pub expires_at: u64,
+    pub shared: bool,
+    pub deep: bool,
+}
+
+impl StoredLock {
+    fn covers(&self, path: &str) -> bool {
+        self.path == path || (self.deep && is_below(path, &self.path))
+    }
+
+    fn held(&self, principal: Option<&str>, ignore_principal: bool, tokens: &[&str]) -> bool {
+        tokens.contains(&self.token.as_str())
+            && (ignore_principal || principal == self.principal.as_deref())
+    }
+
+    fn to_dav_lock(&self) -> DavLock {
+        DavLock {
+            token: self.token.clone(),
+            path: DavPath::new(&self.path).unwrap_or_else(|_| DavPath::new("/").unwrap()),
+            principal: self.principal.clone(),
+            owner: self
+                .owner
+                .as_ref()
+                .and_then(|owner| Element::parse(owner.as_bytes()).ok()),
+            timeout_at: Some(UNIX_EPOCH + Duration::from_secs(self.expires_at)),
+            timeout: Some(Duration::from_secs(self.timeout_secs)),
+            shared: self.shared,
+            deep: self.deep,
+        }
+    }
+}
+
+/// Path a lock is stored under: the URL path without a trailing slash, so a
+/// collection matches whether or not the client sent one.
+pub fn lock_key(path: &DavPath) -> String {
+    let url = path.as_url_string();
+    match url.trim_end_matches('/') {
+        "" => "/".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+fn is_below(path: &str, ancestor: &str) -> bool {
+    ancestor == "/" && path != "/"
+        || path
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// WebDAV locks, optionally saved to a file after every change so they
+/// survive a restart. Expired locks are dropped whenever the table is used.
+#[derive(Clone)]
+pub struct LockTable {
+    locks: Arc<Mutex<Vec<StoredLock>>>,
+    path: Option<PathBuf>,
+}
+
+impl LockTable {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            locks: Arc::new(Mutex::new(Vec::new())),
+            path,
+        }
+    }
+
+    /// Reads the lock file, without expired locks.
+    async fn read(&self) -> Result<Vec<StoredLock>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut locks: Vec<StoredLock> = serde_json::from_slice(&json)
+            .with_context(|| format!("parse {}", path.display()))?;
+        let now = now_secs();
+        locks.retain(|lock| lock.expires_at > now);
+        Ok(locks)
+    }
+
+    async fn save(&self, locks: &[StoredLock]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let json = serde_json::to_vec(locks).unwrap_or_default();
+        if let Err(err) = write_atomic(path, &json).await {
+            error!(path = %path.display(), error = %err, "locks: save failed");
+        }
+    }
+
+    /// Runs `f` on the unexpired locks and saves them when `f` reports a change.
+    async fn update<T>(&self, f: impl FnOnce(&mut Vec<StoredLock>) -> (T, bool)) -> T {
+        let mut locks = self.locks.lock().await;
+        let now = now_secs();
+        let before = locks.len();
+        locks.retain(|lock| lock.expires_at > now);
+        let (result, changed) = f(&mut locks);
+        if changed || locks.len() != before {
+            self.save(&locks).await;
+        }
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn lock(
+        &self,
+        path: &str,
+        fid: Option<String>,
+        principal: Option<&str>,
+        owner: Option<String>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> Result<StoredLock, StoredLock> {
+        self.update(|locks| {
+            if let Err(conflict) = find_conflict(locks, path, None, true, deep, &[], shared) {
+                return (Err(conflict.clone()), false);
+            }
+            let timeout = timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT).as_secs();
+            let lock = StoredLock {
+                token: uuid::Uuid::new_v4().urn().to_string(),
+                fid,
+                path: path.to_string(),
+                principal: principal.map(|p| p.to_string()),
+                owner,
+                timeout_secs: timeout,
+                expires_at: now_secs() + timeout,
+                shared,
+                deep,
+            };
+            debug!(path = %path, token = %lock.token, fid = ?lock.fid, "locks: locked");
+            locks.push(lock.clone());
+            (Ok(lock), true)
+        })
+        .await
+    }
+
+    /// Removes the lock `token` held on `path` or one of its ancestors.
+    pub async fn unlock(&self, path: &str, token: &str) -> Result<(), ()> {
+        self.update(|locks| {
+            match locks.iter().position(|l| l.token == token && (l.path == path || is_below(path, &l.path))) {
+                Some(i) => {
+                    locks.remove(i);
+                    (Ok(()), true)
+                }
+                None => (Err(()), false),
+            }
+        })
+        .await
+    }
+
+    pub async fn refresh(&self, path: &str, token: &str, timeout: Option<Duration>) -> Result<StoredLock, ()> {
+        self.update(|locks| {
+            match locks
+                .iter_mut()
+                .find(|l| l.token == token && (l.path == path || is_below(path, &l.path)))
+            {
+                Some(lock) => {
+                    lock.timeout_secs = timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT).as_secs();
+                    lock.expires_at = now_secs() + lock.timeout_secs;
+                    (Ok(lock.clone()), true)
+                }
+                None => (Err(()), false),
+            }
+        })
+        .await
+    }
+
+    pub async fn check(
+        &self,
+        path: &str,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        tokens: &[&str],
+    ) -> Result<(), StoredLock> {
+        self.update(|locks| {
+            let result = find_conflict(locks, path, principal, ignore_principal, deep, tokens, false);
+            (result.map_err(|lock| lock.clone()), false)
+        })
+        .await
+    }
+
+    /// Locks on `path` and its ancestors.
+    pub async fn discover(&self, path: &str) -> Vec<StoredLock> {
+        self.update(|locks| {
+            let found = locks
+                .iter()
+                .filter(|l| l.path == path || is_below(path, &l.path))
+                .cloned()
+                .collect();
+            (found, false)
+        })
+        .await
+    }
+
+    /// Drops the locks on `path` and everything below it.
+    pub async fn delete(&self, path: &str) {
+        self.update(|locks| {
+            locks.retain(|l| l.path != path && !is_below(&l.path, path));
+            ((), false)
+        })
+        .await
+    }
+
+    /// Moves the locks of the entry `fid`, and of everything below it, from
+    /// `from` to `to`. Locks left on an entry that was overwritten at `to` go.
+    pub async fn follow_rename(&self, fid: &str, from: &str, to: &str) {
+        self.update(|locks| {
+            let before = locks.len();
+            locks.retain(|l| l.path != to || l.fid.as_deref().is_none_or(|f| f == fid));
+            let mut changed = locks.len() != before;
+            for lock in locks.iter_mut() {
+                if lock.fid.as_deref() == Some(fid) || lock.path == from {
+                    lock.fid = Some(fid.to_string());
+                    lock.path = to.to_string();
+                } else if is_below(&lock.path, from) {
+                    lock.path = format!("{}{}", to.trim_end_matches('/'), &lock.path[from.trim_end_matches('/').len()..]);
+                } else {
+                    continue;
+                }
+                debug!(token = %lock.token, path = %lock.path, "locks: followed rename");
+                changed = true;
+            }
+            ((), changed)
+        })
+        .await
+    }
+
+    pub async fn list(&self) -> Vec<StoredLock> {
+        self.update(|locks| (locks.clone(), false)).await
+    }
+
+    /// Breaks a lock regardless of who holds it, returns whether it existed.
+    pub async fn break_lock(&self, token: &str) -> bool {
+        self.update(|locks| {
+            let before = locks.len();
+            locks.retain(|l| l.token != token);
+            let found = locks.len() != before;
+            if found {
+                info!(token = %token, "locks: broken");
+            }
+            (found, found)
+        })
+        .await
+    }
+}
+
+/// The first lock that keeps the caller from `path`, following the rules of
+/// dav-server's in-memory lock system: locks on the path and deep locks above
+/// it count, and for `deep` operations so do the locks below it.
+fn find_conflict<'a>(
+    locks: &'a [StoredLock],
+    path: &str,
+    principal: Option<&str>,
+    ignore_principal: bool,
+    deep: bool,
+    tokens: &[&str],
+    shared_ok: bool,
+) -> Result<(), &'a StoredLock> {
+    let mut holds_lock = false;
+    let mut first_shared = None;
+    for lock in locks.iter().filter(|l| l.covers(path)) {
+        if lock.held(principal, ignore_principal, tokens) {
+            holds_lock = true;
+        } else if !lock.shared {
+            return Err(lock);
+        } else if !shared_ok {
+            first_shared.get_or_insert(lock);
+        }
+    }
+    if !holds_lock && let Some(lock) = first_shared {
+        return Err(lock);
+    }
+    if deep
+        && let Some(lock) = locks.iter().find(|l| {
+            (l.path == path || is_below(&l.path, path))
+                && (!l.shared || !shared_ok)
+                && !l.held(principal, ignore_principal, tokens)
+        })
+    {
+        return Err(lock);
+    }
+    Ok(())
+}
+
+/// [`DavLockSystem`] over the lock table of the filesystem, which records the
+/// fid of what gets locked and moves locks along with renamed entries.
+#[derive(Clone)]
+pub struct FidLockSystem {
+    fs: QuarkDriveFileSystem,
+}
+
+impl Debug for FidLockSystem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FidLockSystem").finish()
+    }
+}
+
+impl FidLockSystem {
+    pub fn new(fs: QuarkDriveFileSystem) -> Box<Self> {
+        Box::new(Self { fs })
+    }
+
+    /// Restores saved locks, except those whose path now holds another entry
+    /// than the one that was locked.
+    pub async fn load(&self) -> Result<()> {
+        let table = &self.fs.locks;
+        let mut locks = table.read().await?;
+        let saved = locks.len();
+        let mut kept = Vec::with_capacity(saved);
+        for lock in locks.drain(..) {
+            if let (Some(fid), Ok(path)) = (&lock.fid, DavPath::new(&lock.path))
+                && let Some(current) = self.fs.fid_of(&path).await
+                && current != *fid
+            {
+                debug!(path = %lock.path, token = %lock.token, "locks: entry replaced, dropping lock");
+                continue;
+            }
+            kept.push(lock);
+        }
+        info!(saved = saved, kept = kept.len(), "locks: loaded");
+        table
+            .update(|locks| {
+                *locks = kept;
+                ((), true)
+            })
+            .await;
+        Ok(())
+    }
+}
+
+impl DavLockSystem for FidLockSystem {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> LsFuture<'_, Result<DavLock, DavLock>> {
+        let path = path.clone();
+        let principal = principal.map(|p| p.to_string());
+        let owner = owner.and_then(|owner| {
+            let mut xml = Vec::new();
+            owner.write(&mut xml).ok()?;
+            String::from_utf8(xml).ok()
+        });
+        async move {
+            let fid = self.fs.fid_of(&path).await;
+            self.fs
+                .locks
+                .lock(&lock_key(&path), fid, principal.as_deref(), owner, timeout, shared, deep)
+                .await
+                .map(|lock| lock.to_dav_lock())
+                .map_err(|lock| lock.to_dav_lock())
+        }
+        .boxed()
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> LsFuture<'_, Result<(), ()>> {
+        let key = lock_key(path);
+        let token = token.to_string();
+        async move { self.fs.locks.unlock(&key, &token).await }.boxed()
+    }
+
+    fn refresh(
+        &self,
+        path: &DavPath,
+        token: &str,
+        timeout: Option<Duration>,
+    ) -> LsFuture<'_, Result<DavLock, ()>> {
+        let key = lock_key(path);
+        let token = token.to_string();
+        async move {
+            self.fs
+                .locks
+                .refresh(&key, &token, timeout)
+                .await
+                .map(|lock| lock.to_dav_lock())
+        }
+        .boxed()
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> LsFuture<'_, Result<(), DavLock>> {
+        let key = lock_key(path);
+        let principal = principal.map(|p| p.to_string());
+        let tokens: Vec<String> = submitted_tokens.iter().map(|t| t.to_string()).collect();
+        async move {
+            let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+            self.fs
+                .locks
+                .check(&key, principal.as_deref(), ignore_principal, deep, &tokens)
+                .await
+                .map_err(|lock| lock.to_dav_lock())
+        }
+        .boxed()
+    }
+
+    fn discover(&self, path: &DavPath) -> LsFuture<'_, Vec<DavLock>> {
+        let key = lock_key(path);
+        async move {
+            self.fs
+                .locks
+                .discover(&key)
+                .await
+                .iter()
+                .map(StoredLock::to_dav_lock)
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn delete(&self, path: &DavPath) -> LsFuture<'_, Result<(), ()>> {
+        let key = lock_key(path);
+        async move {
+            self.fs.locks.delete(&key).await;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn take(table: &LockTable, path: &str, fid: Option<&str>, shared: bool, deep: bool) -> Result<StoredLock, StoredLock> {
+        table
+            .lock(path, fid.map(|f| f.to_string()), Some("alice"), None, None, shared, deep)
+            .await
+    }
+
+    #[test]
+    fn test_lock_key_and_is_below() {
+        assert_eq!(lock_key(&DavPath::new("/dir/").unwrap()), "/dir");
+        assert_eq!(lock_key(&DavPath::new("/").unwrap()), "/");
+        assert_eq!(lock_key(&DavPath::new("/a%20b.txt").unwrap()), "/a%20b.txt");
+        assert!(is_below("/dir/a.txt", "/dir"));
+        assert!(is_below("/dir/a.txt", "/"));
+        assert!(!is_below("/dir2/a.txt", "/dir"));
+        assert!(!is_below("/dir", "/dir"));
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_and_deep_conflicts() {
+        let table = LockTable::new(None);
+        let dir = take(&table, "/dir", Some("d1"), false, true).await.unwrap();
+        // a deep lock covers everything below it
+        assert!(take(&table, "/dir/a.txt", Some("f1"), false, false).await.is_err());
+        assert!(table.check("/dir/a.txt", Some("alice"), false, false, &[]).await.is_err());
+        assert!(table.check("/dir/a.txt", Some("alice"), false, false, &[&dir.token]).await.is_ok());
+        // but not next to it
+        assert!(take(&table, "/other.txt", Some("f2"), false, false).await.is_ok());
+        // a deep check on the root finds the lock below it
+        assert!(table.check("/", Some("alice"), false, true, &[]).await.is_err());
+        assert!(table.check("/", Some("alice"), false, false, &[]).await.is_ok());
+        table.unlock("/dir", &dir.token).await.unwrap();
+        assert!(table.unlock("/dir", &dir.token).await.is_err());
+        assert!(take(&table, "/dir/a.txt", Some("f1"), false, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shared_locks() {
+        let table = LockTable::new(None);
+        let first = take(&table, "/a.txt", Some("f1"), true, false).await.unwrap();
+        assert!(take(&table, "/a.txt", Some("f1"), true, false).await.is_ok());
+        assert!(take(&table, "/a.txt", Some("f1"), false, false).await.is_err());
+        assert!(table.check("/a.txt", Some("alice"), false, false, &[]).await.is_err());
+        assert!(table.check("/a.txt", Some("alice"), false, false, &[&first.token]).await.is_ok());
+        // a token is only good for the principal that took the lock
+        assert!(table.check("/a.txt", Some("bob"), false, false, &[&first.token]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired_locks_are_dropped() {
+        let table = LockTable::new(None);
+        let lock = take(&table, "/a.txt", Some("f1"), false, false).await.unwrap();
+        assert_eq!(lock.timeout_secs, DEFAULT_LOCK_TIMEOUT.as_secs());
+        table.locks.lock().await[0].expires_at = now_secs() - 1;
+        assert!(table.list().await.is_empty());
+        assert!(table.refresh("/a.txt", &lock.token, None).await.is_err());
+        assert!(take(&table, "/a.txt", Some("f1"), false, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_locks_follow_rename() {
+        let table = LockTable::new(None);
+        let file = take(&table, "/docs/report.docx", Some("f1"), false, false).await.unwrap();
+        let child = take(&table, "/docs/sub/x.txt", Some("f2"), false, false).await.unwrap();
+        let replaced = take(&table, "/archive/docs", Some("d9"), false, false).await.unwrap();
+
+        // Office saves by renaming the locked file away and a new one into place
+        table.follow_rename("f1", "/docs/report.docx", "/docs/backup.docx").await;
+        table.follow_rename("d1", "/docs", "/archive/docs").await;
+        let locks = table.list().await;
+        let path_of = |token: &str| locks.iter().find(|l| l.token == token).map(|l| l.path.clone());
+        assert_eq!(path_of(&file.token).as_deref(), Some("/archive/docs/backup.docx"));
+        assert_eq!(path_of(&child.token).as_deref(), Some("/archive/docs/sub/x.txt"));
+        // the lock of the folder that was overwritten went with it
+        assert!(path_of(&replaced.token).is_none());
+
+        // dav-server drops the locks of the source after a MOVE, nothing is left there
+        table.delete("/docs").await;
+        assert_eq!(table.list().await.len(), 2);
+        assert!(table.break_lock(&file.token).await);
+        assert!(!table.break_lock(&file.token).await);
+    }
+
+    #[tokio::test]
+    async fn test_locks_survive_restart() {
+        let path = std::env::temp_dir().join(format!("quarkdrive-locks-{}.json", std::process::id()));
+        let table = LockTable::new(Some(path.clone()));
+        let owner = "<D:owner xmlns:D=\"DAV:\">alice</D:owner>".to_string();
+        let lock = table
+            .lock("/a.txt", Some("f1".to_string()), Some("alice"), Some(owner), Some(Duration::from_secs(600)), false, false)
+            .await
+            .unwrap();
+        table.lock("/b.txt", None, None, None, None, false, false).await.unwrap();
+        table.locks.lock().await[1].expires_at = now_secs() - 1;
+        table.list().await;
+
+        let restored = LockTable::new(Some(path.clone())).read().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].token, lock.token);
+        assert_eq!(restored[0].fid.as_deref(), Some("f1"));
+        let dav_lock = restored[0].to_dav_lock();
+        assert_eq!(dav_lock.timeout, Some(Duration::from_secs(600)));
+        assert_eq!(dav_lock.owner.unwrap().name, "owner");
+    }
+}
@@ -1,25 +1,44 @@
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc};
 use std::time::Duration;
 use anyhow::bail;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use dashmap::DashMap;
-use dav_server::{memls::MemLs, DavHandler};
+use dav_server::DavHandler;
 #[cfg(unix)]
 use futures_util::stream::StreamExt;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 #[cfg(unix)]
 use {signal_hook::consts::signal::*, signal_hook_tokio::Signals};
 
 use cache::Cache;
 use drive::*;
+use hls::{HlsProxy, Resolution};
+use locks::FidLockSystem;
+use names::DuplicateNames;
+use offline::start_offline_watcher;
+use shares::ShareLink;
+use snapshot::{start_periodic_snapshot, Snapshot};
+use stream::{StreamProxy, StreamSigner};
 use vfs::QuarkDriveFileSystem;
 use webdav::WebDavServer;
 
 mod cache;
+mod csrf;
 mod drive;
+mod hls;
+mod locks;
+mod multipart;
+mod names;
+mod offline;
+mod props;
+mod search;
+mod shares;
+mod snapshot;
+mod stream;
+mod thumbs;
 mod vfs;
 mod webdav;
 use tokio::time::interval;
@@ -78,6 +97,10 @@ struct Opt {
     /// Prefix to be stripped off when handling request.
     #[arg(long, env = "WEBDAV_STRIP_PREFIX")]
     strip_prefix: Option<String>,
+    /// Path the server's own endpoints (locks, shares, thumbnails, streams) are served below,
+    /// a drive folder of that name is not reachable
+    #[arg(long, env = "RESERVED_PREFIX", default_value = "/_quarkdrive")]
+    reserved_prefix: String,
     /// Enable debug log
     #[arg(long)]
     debug: bool,
@@ -93,12 +116,47 @@ struct Opt {
     /// Enable 302 redirect when possible
     #[arg(long)]
     redirect: bool,
+    /// Redirect downloads to signed /_quarkdrive/stream/ URLs proxied by this server, usable without WebDAV auth
+    #[arg(long)]
+    redirect_proxy: bool,
+    /// Secret used to sign stream URLs, a random one is generated on every start if not set
+    #[arg(long, env = "STREAM_SECRET")]
+    stream_secret: Option<String>,
+    /// Signed stream URL lifetime in seconds
+    #[arg(long, default_value = "14400")]
+    stream_url_ttl: u64,
+    /// Also list videos as `<name>.<resolution>.m3u8` playlists of the drive's transcodes, e.g. `high,super`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    hls_resolutions: Vec<Resolution>,
+    /// How files sharing a name within one folder are listed
+    #[arg(long, value_enum, default_value_t = DuplicateNames::Numbered)]
+    duplicate_names: DuplicateNames,
 
     #[command(subcommand)]
     subcommands: Option<Commands>,
 
+    /// Seconds after which a cached directory listing is refreshed in the background
     #[arg(long, env = "REFRESH_CACHE_SECS_INTERVAL", default_value = "300")]
     refresh_cache_secs_interval: u64,
+
+    /// Metadata snapshot file, loaded on startup and saved periodically and on shutdown
+    #[arg(long, env = "SNAPSHOT_PATH")]
+    snapshot_path: Option<PathBuf>,
+    /// Metadata snapshot save interval in seconds
+    #[arg(long, default_value = "300")]
+    snapshot_interval: u64,
+    /// WebDAV lock file, locks are kept in memory only if not set
+    #[arg(long, env = "LOCK_PATH")]
+    lock_path: Option<PathBuf>,
+    /// Directory thumbnails are cached in, defaults to one in the system temp directory
+    #[arg(long, env = "THUMB_CACHE_DIR")]
+    thumb_cache_dir: Option<PathBuf>,
+    /// Thumbnail cache size limit in bytes, defaults to 256MB
+    #[arg(long, env = "THUMB_CACHE_SIZE", default_value = "268435456")]
+    thumb_cache_size: u64,
+    /// File storing properties set by PROPPATCH, kept in memory only if not set
+    #[arg(long, env = "PROPS_PATH")]
+    props_path: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -106,6 +164,71 @@ enum Commands {
     /// Scan QRCode
     #[command(subcommand)]
     Qr(QrCommand),
+    /// Save someone else's share link into the drive
+    #[command(subcommand)]
+    Share(ShareCommand),
+    /// Have the drive download URLs and magnet links
+    #[command(subcommand)]
+    Offline(OfflineCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ShareCommand {
+    /// List the top level entries of a share link
+    List(ShareArgs),
+    /// Save a share link into a folder of the drive
+    Save {
+        #[command(flatten)]
+        share: ShareArgs,
+        /// Drive folder to save into
+        #[arg(long, default_value = "/")]
+        to: String,
+        /// Name of a top level entry to save, may be repeated; everything is saved if not set
+        #[arg(long = "item")]
+        items: Vec<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct ShareArgs {
+    /// Share link, https://pan.quark.cn/s/<id>
+    url: String,
+    /// Extraction code of the share link
+    #[arg(long)]
+    passcode: Option<String>,
+    #[command(flatten)]
+    cookie: CookieArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum OfflineCommand {
+    /// Download an HTTP(S) URL or magnet link into a folder of the drive
+    Add {
+        url: String,
+        /// Drive folder to download into
+        #[arg(long, default_value = "/")]
+        to: String,
+        #[command(flatten)]
+        cookie: CookieArgs,
+    },
+    /// List the offline downloads
+    List {
+        #[command(flatten)]
+        cookie: CookieArgs,
+    },
+    /// Cancel an offline download
+    Cancel {
+        task_id: String,
+        #[command(flatten)]
+        cookie: CookieArgs,
+    },
+}
+
+#[derive(Args, Debug)]
+struct CookieArgs {
+    ///  drive client_secret
+    #[arg(long, env = "QUARK_COOKIE")]
+    quark_cookie: String,
 }
 
 #[derive(Subcommand, Debug)]
@@ -123,12 +246,12 @@ enum QrCommand {
     },
 }
 
-pub fn start_periodic_invalidate(cache: Arc<Cache>, secs: u64) {
+pub fn start_periodic_revalidate(cache: Arc<Cache>, secs: u64) {
     tokio::spawn(async move {
         let mut ticker = interval(Duration::from_secs(secs));
         loop {
             ticker.tick().await;
-            cache.invalidate_all();
+            cache.revalidate_hot().await;
         }
     });
 }
@@ -148,19 +271,15 @@ async fn main() -> anyhow::Result<()> {
         .with_timer(tracing_subscriber::fmt::time::time())
         .init();
 
+    match opt.subcommands {
+        Some(Commands::Share(command)) => return run_share_command(command).await,
+        Some(Commands::Offline(command)) => return run_offline_command(command).await,
+        _ => {}
+    }
+
     let cookie_str = opt.quark_cookie.unwrap_or_else(||{ 
         panic!("QUARK_COOKIE must be specified. Please set it in the environment or use --quark-cookie option.");
     });
-    let init_cookie = Arc::new(DashMap::new());
-    for pair in cookie_str.split(';') {
-        if let Some((k, v)) = pair.trim().split_once('=') {
-            init_cookie.insert(k.trim().to_string(), v.trim().to_string());
-        }
-    }
-    let drive_config = DriveConfig {
-        api_base_url: "https://drive.quark.cn".to_string(),
-        cookie: init_cookie,
-    };
     let auth_user = opt.auth_user;
     let auth_password = opt.auth_password;
     if (auth_user.is_some() && auth_password.is_none())
@@ -174,23 +293,91 @@ async fn main() -> anyhow::Result<()> {
         (None, None) => None,
         _ => bail!("tls-cert and tls-key must be specified together."),
     };
-    let drive = QuarkDrive::new(drive_config)?;
+    let reserved_prefix = opt.reserved_prefix.trim_end_matches('/').to_string();
+    if !reserved_prefix.starts_with('/') {
+        bail!("reserved-prefix must start with / and must not be the root.");
+    }
+    // signed URLs point below the reserved prefix
+    let signer_base = format!(
+        "{}{}",
+        opt.strip_prefix.as_deref().unwrap_or_default().trim_end_matches('/'),
+        reserved_prefix
+    );
+    let drive = QuarkDrive::new(drive_config(&cookie_str))?;
+    let stream_signer = opt.redirect_proxy.then(|| match &opt.stream_secret {
+        Some(secret) => StreamSigner::new(secret.as_bytes(), opt.stream_url_ttl, Some(&signer_base)),
+        None => StreamSigner::with_random_secret(opt.stream_url_ttl, Some(&signer_base)),
+    });
+    let stream_proxy = stream_signer
+        .clone()
+        .map(|signer| StreamProxy::new(signer, drive.clone()));
+    let hls_proxy = (!opt.hls_resolutions.is_empty()).then(|| {
+        let signer = stream_signer.clone().unwrap_or_else(|| {
+            StreamSigner::with_random_secret(opt.stream_url_ttl, Some(&signer_base))
+        });
+        HlsProxy::new(signer, drive.clone())
+    });
+    // a listing has to be refreshed before moka drops it, or it is never revalidated
+    let refresh_secs = if opt.refresh_cache_secs_interval >= opt.cache_ttl {
+        let clamped = (opt.cache_ttl / 2).max(1);
+        warn!(
+            refresh_cache_secs_interval = opt.refresh_cache_secs_interval,
+            cache_ttl = opt.cache_ttl,
+            using = clamped,
+            "refresh interval is not below the cache ttl, lowering it"
+        );
+        clamped
+    } else {
+        opt.refresh_cache_secs_interval
+    };
     let mut fs = QuarkDriveFileSystem::new(drive, opt.root, opt.cache_size, opt.cache_ttl)?;
     fs.set_no_trash(opt.no_trash)
         .set_read_only(opt.read_only)
         .set_upload_buffer_size(opt.upload_buffer_size)
         .set_skip_upload_same_size(opt.skip_upload_same_size)
-        .set_prefer_http_download(opt.prefer_http_download);
+        .set_prefer_http_download(opt.prefer_http_download)
+        .set_stream_signer(stream_signer)
+        .set_cache_refresh_interval(refresh_secs)
+        .set_duplicate_names(opt.duplicate_names)
+        .set_hls_resolutions(opt.hls_resolutions.clone())
+        .set_lock_path(opt.lock_path.clone())
+        .set_props_path(opt.props_path.clone())
+        .set_thumb_cache_dir(opt.thumb_cache_dir.clone())
+        .set_thumb_cache_size(opt.thumb_cache_size);
+    if let Err(err) = fs.props.load().await {
+        error!(error = %err, "props: load failed, starting without properties");
+    }
+    let (shares, drive) = (fs.shares.clone(), fs.drive.clone());
+    tokio::spawn(async move {
+        if let Err(err) = shares.refresh(&drive).await {
+            error!(error = %err, "shares: listing failed, share urls unknown until the next change");
+        }
+    });
     let cache = Arc::new(fs.dir_cache.clone());
-    start_periodic_invalidate(cache.clone(), opt.refresh_cache_secs_interval);
+    let snapshot = opt
+        .snapshot_path
+        .clone()
+        .map(|path| Snapshot::new(path, fs.dir_cache.clone(), fs.drive.clone()));
+    if let Some(snapshot) = &snapshot {
+        if let Err(err) = snapshot.load().await {
+            error!(error = %err, "snapshot: load failed, starting cold");
+        }
+        start_periodic_snapshot(snapshot.clone(), opt.snapshot_interval);
+    }
+    start_periodic_revalidate(cache.clone(), refresh_secs);
+    start_offline_watcher(fs.offline.clone(), fs.drive.clone(), cache.clone());
+    let lock_system = FidLockSystem::new(fs.clone());
+    if let Err(err) = lock_system.load().await {
+        error!(error = %err, "locks: load failed, starting without locks");
+    }
     let fs_for_browser = fs.clone();
     let strip_prefix = opt.strip_prefix.clone();
     let mut dav_server_builder = DavHandler::builder()
         .filesystem(Box::new(fs))
-        .locksystem(MemLs::new())
+        .locksystem(lock_system)
         .read_buf_size(opt.read_buffer_size)
         .autoindex(opt.auto_index)
-        .redirect(opt.redirect);
+        .redirect(opt.redirect || opt.redirect_proxy);
     if let Some(prefix) = opt.strip_prefix {
         dav_server_builder = dav_server_builder.strip_prefix(prefix);
     }
@@ -211,15 +398,25 @@ async fn main() -> anyhow::Result<()> {
         handler: dav_server,
         fs: fs_for_browser,
         strip_prefix,
+        reserved_prefix,
+        stream_proxy,
+        hls_proxy,
     };
 
     #[cfg(not(unix))]
-    server.serve().await?;
+    tokio::select! {
+        res = server.serve() => res?,
+        _ = tokio::signal::ctrl_c() => {
+            if let Some(snapshot) = &snapshot {
+                save_snapshot(snapshot).await;
+            }
+        }
+    }
     #[cfg(unix)]
     {
-        let signals = Signals::new([SIGHUP])?;
+        let signals = Signals::new([SIGHUP, SIGINT, SIGTERM])?;
         let handle = signals.handle();
-        let signals_task = tokio::spawn(handle_signals(signals, cache));
+        let signals_task = tokio::spawn(handle_signals(signals, cache, snapshot));
 
         server.serve().await?;
 
@@ -230,14 +427,94 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+fn drive_config(cookie_str: &str) -> DriveConfig {
+    let init_cookie = Arc::new(DashMap::new());
+    for pair in cookie_str.split(';') {
+        if let Some((k, v)) = pair.trim().split_once('=') {
+            init_cookie.insert(k.trim().to_string(), v.trim().to_string());
+        }
+    }
+    DriveConfig {
+        api_base_url: "https://drive.quark.cn".to_string(),
+        cookie: init_cookie,
+    }
+}
+
+async fn run_share_command(command: ShareCommand) -> anyhow::Result<()> {
+    let share = match &command {
+        ShareCommand::List(share) | ShareCommand::Save { share, .. } => share,
+    };
+    let link = ShareLink::parse(&share.url, share.passcode.as_deref())?;
+    let drive = QuarkDrive::new(drive_config(&share.cookie.quark_cookie))?;
+    match &command {
+        ShareCommand::List(_) => {
+            let (_, files) = link.open(&drive).await?;
+            for file in files {
+                let kind = if file.dir { "dir " } else { "file" };
+                println!("{}\t{:>14}\t{}", kind, file.size, file.file_name);
+            }
+        }
+        ShareCommand::Save { to, items, .. } => {
+            let dir_fid = folder_fid(&drive, to).await?;
+            for file in link.save(&drive, items, &dir_fid).await? {
+                println!("saved {}", file.file_name);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_offline_command(command: OfflineCommand) -> anyhow::Result<()> {
+    let cookie = match &command {
+        OfflineCommand::Add { cookie, .. } | OfflineCommand::List { cookie } | OfflineCommand::Cancel { cookie, .. } => cookie,
+    };
+    let drive = QuarkDrive::new(drive_config(&cookie.quark_cookie))?;
+    match &command {
+        OfflineCommand::Add { url, to, .. } => {
+            let dir_fid = folder_fid(&drive, to).await?;
+            println!("{}", drive.add_offline_task(url, &dir_fid).await?);
+        }
+        OfflineCommand::List { .. } => {
+            for task in drive.list_offline_tasks().await? {
+                let name = if task.file_name.is_empty() { &task.url } else { &task.file_name };
+                println!("{}\t{}\t{:>3}%\t{}", task.task_id, task.status, task.progress, name);
+            }
+        }
+        OfflineCommand::Cancel { task_id, .. } => drive.cancel_offline_task(task_id).await?,
+    }
+    Ok(())
+}
+
+/// fid of the drive folder at `path`.
+async fn folder_fid(drive: &QuarkDrive, path: &str) -> anyhow::Result<String> {
+    let fs = QuarkDriveFileSystem::new(drive.clone(), "/".to_string(), 100, 60)?;
+    match fs.stat(Path::new(path)).await.filter(|f| f.dir) {
+        Some(dir) => Ok(dir.fid),
+        None => bail!("no such folder in the drive: {}", path),
+    }
+}
+
+async fn save_snapshot(snapshot: &Snapshot) {
+    match snapshot.save().await {
+        Ok(()) => info!("snapshot saved before shutdown"),
+        Err(err) => error!(error = %err, "snapshot: save failed"),
+    }
+}
+
 #[cfg(unix)]
-async fn handle_signals(mut signals: Signals, dir_cache: Arc<Cache>) {
+async fn handle_signals(mut signals: Signals, dir_cache: Arc<Cache>, snapshot: Option<Snapshot>) {
     while let Some(signal) = signals.next().await {
         match signal {
             SIGHUP => {
                 dir_cache.invalidate_all();
                 info!("directory cache invalidated by SIGHUP");
             }
+            SIGINT | SIGTERM => {
+                if let Some(snapshot) = &snapshot {
+                    save_snapshot(snapshot).await;
+                }
+                std::process::exit(0);
+            }
             _ => unreachable!(),
         }
     }
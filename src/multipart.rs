@@ -0,0 +1,275 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{Stream, StreamExt};
+
+/// Longest header block of a part that is accepted.
+const MAX_HEADERS_LEN: usize = 16 * 1024;
+
+/// `boundary` parameter of a `multipart/form-data` content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    split_params(params).into_iter().find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        let value = value.trim().trim_matches('"');
+        (key.trim().eq_ignore_ascii_case("boundary") && !value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// A form field as described by its `Content-Disposition`.
+#[derive(Debug, PartialEq)]
+pub struct Part {
+    pub name: String,
+    /// set for file inputs, the name of the file picked
+    pub file_name: Option<String>,
+}
+
+/// Reads a `multipart/form-data` body part by part without holding more than
+/// a boundary's worth of it, so a file can go on to the drive while it arrives.
+pub struct MultipartReader<S> {
+    stream: S,
+    buf: BytesMut,
+    /// `\r\n--<boundary>`
+    delimiter: Vec<u8>,
+    in_part: bool,
+    done: bool,
+}
+
+impl<S, E> MultipartReader<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new(stream: S, boundary: &str) -> Self {
+        // the body starts with the boundary itself, without the line break
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\r\n");
+        Self {
+            stream,
+            buf,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            in_part: false,
+            done: false,
+        }
+    }
+
+    /// Appends the next chunk of the body, `false` at its end.
+    async fn fill(&mut self) -> Result<bool> {
+        match self.stream.next().await {
+            Some(chunk) => {
+                self.buf.extend_from_slice(&chunk?);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Moves on to the next part, skipping what is left of the current one.
+    pub async fn next_part(&mut self) -> Result<Option<Part>> {
+        while self.in_part {
+            self.read_chunk().await?;
+        }
+        if self.done {
+            return Ok(None);
+        }
+        let start = loop {
+            if let Some(start) = find(&self.buf, &self.delimiter) {
+                break start;
+            }
+            if !self.fill().await? {
+                bail!("multipart: missing boundary");
+            }
+        };
+        self.buf.advance(start + self.delimiter.len());
+        while self.buf.len() < 2 {
+            if !self.fill().await? {
+                bail!("multipart: truncated boundary");
+            }
+        }
+        if self.buf.starts_with(b"--") {
+            self.done = true;
+            return Ok(None);
+        }
+        let headers_end = loop {
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buf.len() > MAX_HEADERS_LEN || !self.fill().await? {
+                bail!("multipart: bad part headers");
+            }
+        };
+        let headers = self.buf.split_to(headers_end + 4);
+        let part = parse_headers(&String::from_utf8_lossy(&headers))?;
+        self.in_part = true;
+        Ok(Some(part))
+    }
+
+    /// Next piece of the current part's content, `None` once it is all read.
+    pub async fn read_chunk(&mut self) -> Result<Option<Bytes>> {
+        if !self.in_part {
+            return Ok(None);
+        }
+        loop {
+            if let Some(end) = find(&self.buf, &self.delimiter) {
+                if end == 0 {
+                    self.in_part = false;
+                    return Ok(None);
+                }
+                return Ok(Some(self.buf.split_to(end).freeze()));
+            }
+            // all but what could be the start of the delimiter is content
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return Ok(Some(self.buf.split_to(safe).freeze()));
+            }
+            if !self.fill().await? {
+                bail!("multipart: body ended inside a part");
+            }
+        }
+    }
+
+    /// The whole content of the current part, for small text fields.
+    pub async fn read_text(&mut self, limit: usize) -> Result<String> {
+        let mut text = Vec::new();
+        while let Some(chunk) = self.read_chunk().await? {
+            text.extend_from_slice(&chunk);
+            if text.len() > limit {
+                bail!("multipart: field too long");
+            }
+        }
+        Ok(String::from_utf8(text)?)
+    }
+}
+
+fn find(buf: &[u8], needle: &[u8]) -> Option<usize> {
+    buf.windows(needle.len()).position(|window| window == needle)
+}
+
+fn parse_headers(headers: &str) -> Result<Part> {
+    let disposition = headers
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-disposition"))
+        .map(|(_, value)| value)
+        .ok_or_else(|| anyhow!("multipart: part without content-disposition"))?;
+    let mut name = None;
+    let mut file_name = None;
+    for param in split_params(disposition).into_iter().skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        // browsers escape quotes in file names as %22
+        let value = value.replace("%22", "\"");
+        match key.trim() {
+            "name" => name = Some(value),
+            "filename" => file_name = Some(value),
+            _ => {}
+        }
+    }
+    Ok(Part {
+        name: name.ok_or_else(|| anyhow!("multipart: part without a name"))?,
+        file_name,
+    })
+}
+
+/// Splits a header value at the semicolons outside quoted strings, a file
+/// name may contain one.
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"csrf\"\r\n\r\n\
+token\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a b.txt\"\r\n\
+Content-Type: text/plain\r\n\r\n\
+hello\r\n--Xy world\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"\"\r\n\r\n\
+\r\n\
+--XyZ--\r\n";
+
+    fn reader(chunk_size: usize) -> MultipartReader<impl Stream<Item = Result<Bytes, std::io::Error>> + Unpin> {
+        let chunks: Vec<Result<Bytes, std::io::Error>> =
+            BODY.chunks(chunk_size).map(|c| Ok(Bytes::copy_from_slice(c))).collect();
+        MultipartReader::new(futures_util::stream::iter(chunks), "XyZ")
+    }
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(boundary("multipart/form-data; boundary=XyZ").as_deref(), Some("XyZ"));
+        assert_eq!(boundary("multipart/form-data; charset=utf-8; boundary=\"a b\"").as_deref(), Some("a b"));
+        assert!(boundary("multipart/form-data").is_none());
+        assert!(boundary("application/x-www-form-urlencoded; boundary=XyZ").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_multipart_reader() {
+        for chunk_size in [1, 3, 7, BODY.len()] {
+            let mut reader = reader(chunk_size);
+            let part = reader.next_part().await.unwrap().unwrap();
+            assert_eq!(part, Part { name: "csrf".into(), file_name: None });
+            assert_eq!(reader.read_text(64).await.unwrap(), "token");
+
+            let part = reader.next_part().await.unwrap().unwrap();
+            assert_eq!(part.file_name.as_deref(), Some("a b.txt"));
+            let mut content = Vec::new();
+            while let Some(chunk) = reader.read_chunk().await.unwrap() {
+                content.extend_from_slice(&chunk);
+            }
+            assert_eq!(content, b"hello\r\n--Xy world", "chunk size {}", chunk_size);
+
+            // an empty file input, skipped without reading it
+            let part = reader.next_part().await.unwrap().unwrap();
+            assert_eq!(part.file_name.as_deref(), Some(""));
+            assert!(reader.next_part().await.unwrap().is_none());
+            assert!(reader.next_part().await.unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_parse_headers_quoted_semicolon() {
+        let part = parse_headers("Content-Disposition: form-data; name=\"file\"; filename=\"a;b.txt\"\r\n").unwrap();
+        assert_eq!(part, Part { name: "file".into(), file_name: Some("a;b.txt".into()) });
+        let part = parse_headers("Content-Disposition: form-data; filename=\"x\\\";y=\"; name=f\r\n").unwrap();
+        assert_eq!(part.name, "f");
+        assert_eq!(split_params("a; b=\"c;d\"; e"), vec!["a", " b=\"c;d\"", " e"]);
+    }
+
+    #[tokio::test]
+    async fn test_multipart_truncated() {
+        // cut off inside the content of the file
+        let end = BODY.windows(5).position(|w| w == b"hello").unwrap() + 3;
+        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![Ok(Bytes::from_static(&BODY[..end]))];
+        let mut reader = MultipartReader::new(futures_util::stream::iter(chunks), "XyZ");
+        reader.next_part().await.unwrap();
+        assert!(reader.next_part().await.unwrap().is_some());
+        assert!(reader.read_text(1024).await.is_err());
+    }
+}
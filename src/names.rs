@@ -0,0 +1,186 @@
+use std::collections::HashSet;
+
+use clap::ValueEnum;
+
+use crate::drive::QuarkFile;
+
+/// How entries sharing a name within one folder are told apart.
+///
+/// The oldest entry keeps the plain name, the others get a suffix in the
+/// order they were created. A duplicate that shows up later is numbered after
+/// the existing ones, so a name once handed out keeps naming the same file.
+/// Naming needs the complete listing, see [`DuplicateNames::needs_full_listing`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateNames {
+    /// `name (2).ext`, `name (3).ext`, ...
+    #[default]
+    Numbered,
+    /// `name [<fid prefix>].ext`, unaffected by other duplicates coming and going
+    Fid,
+    /// List every entry under its own name, only the first one is reachable
+    Off,
+}
+
+impl DuplicateNames {
+    /// Whether names depend on entries not listed yet, so a listing cannot be
+    /// handed out page by page as the drive returns it.
+    pub fn needs_full_listing(self) -> bool {
+        self != DuplicateNames::Off
+    }
+}
+
+/// Hands out unique display names in the order it is given entries. The
+/// original name of a renamed entry is kept in `QuarkFile::real_name`.
+struct Disambiguator {
+    policy: DuplicateNames,
+    taken: HashSet<String>,
+}
+
+impl Disambiguator {
+    fn new(policy: DuplicateNames) -> Self {
+        Self {
+            policy,
+            taken: HashSet::new(),
+        }
+    }
+
+    fn assign(&mut self, file: &mut QuarkFile) {
+        if self.policy == DuplicateNames::Off || self.taken.insert(file.file_name.clone()) {
+            return;
+        }
+        let name = match self.policy {
+            DuplicateNames::Fid => {
+                let short = with_suffix(file, &format!(" [{}]", &file.fid[..file.fid.len().min(8)]));
+                if self.taken.insert(short.clone()) {
+                    short
+                } else {
+                    let full = with_suffix(file, &format!(" [{}]", file.fid));
+                    self.taken.insert(full.clone());
+                    full
+                }
+            }
+            _ => (2..)
+                .map(|n| with_suffix(file, &format!(" ({})", n)))
+                .find(|candidate| self.taken.insert(candidate.clone()))
+                .unwrap(),
+        };
+        file.real_name = Some(std::mem::replace(&mut file.file_name, name));
+    }
+}
+
+/// Gives every entry of a complete listing a unique name, in place.
+pub fn disambiguate(files: &mut [QuarkFile], policy: DuplicateNames) {
+    for f in files.iter_mut() {
+        if let Some(real) = f.real_name.take() {
+            f.file_name = real;
+        }
+    }
+    if policy == DuplicateNames::Off {
+        return;
+    }
+    // folders first, then oldest first: neither changes when entries are added
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    order.sort_by(|&a, &b| {
+        let key = |f: &QuarkFile| (!f.dir, f.created_at, f.fid.clone());
+        key(&files[a]).cmp(&key(&files[b]))
+    });
+    let mut names = Disambiguator::new(policy);
+    for i in order {
+        names.assign(&mut files[i]);
+    }
+}
+
+fn with_suffix(file: &QuarkFile, suffix: &str) -> String {
+    let name = &file.file_name;
+    match name.rfind('.') {
+        Some(dot) if !file.dir && dot > 0 => format!("{}{}{}", &name[..dot], suffix, &name[dot..]),
+        _ => format!("{}{}", name, suffix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(fid: &str, name: &str, created_at: u64) -> QuarkFile {
+        let mut f = QuarkFile::new_file(fid, name, "/");
+        f.created_at = created_at;
+        // later changes to a file must not move its name
+        f.updated_at = 1_000 - created_at;
+        f
+    }
+
+    fn names(files: &[QuarkFile]) -> Vec<&str> {
+        files.iter().map(|f| f.file_name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_numbered_oldest_keeps_name() {
+        let mut files = vec![
+            entry("f1", "a.txt", 300),
+            entry("f2", "a.txt", 100),
+            entry("f3", "a.txt", 200),
+            entry("f4", "b.txt", 100),
+        ];
+        disambiguate(&mut files, DuplicateNames::Numbered);
+        assert_eq!(names(&files), vec!["a (3).txt", "a.txt", "a (2).txt", "b.txt"]);
+        assert_eq!(files[0].real_name.as_deref(), Some("a.txt"));
+        assert!(files[1].real_name.is_none());
+    }
+
+    #[test]
+    fn test_numbered_skips_taken_names() {
+        let mut files = vec![
+            entry("f1", "a.txt", 100),
+            entry("f2", "a.txt", 200),
+            entry("f3", "a (2).txt", 300),
+        ];
+        disambiguate(&mut files, DuplicateNames::Numbered);
+        let mut got = names(&files);
+        got.sort();
+        assert_eq!(got, vec!["a (2) (2).txt", "a (2).txt", "a.txt"]);
+    }
+
+    #[test]
+    fn test_fid_policy_and_dirs() {
+        let mut dir = entry("0123456789abcdef", "photos", 100);
+        dir.dir = true;
+        dir.file = false;
+        let mut files = vec![entry("f1", "photos", 1), dir, entry("abcdef0123456789", ".env", 2), entry("zz", ".env", 1)];
+        disambiguate(&mut files, DuplicateNames::Fid);
+        // folders are listed first and keep the plain name
+        assert_eq!(names(&files), vec!["photos [f1]", "photos", ".env [abcdef01]", ".env"]);
+    }
+
+    #[test]
+    fn test_redisambiguate_is_stable() {
+        let mut files = vec![entry("f1", "a.txt", 100), entry("f2", "a.txt", 300)];
+        disambiguate(&mut files, DuplicateNames::Numbered);
+        let first: Vec<String> = files.iter().map(|f| f.file_name.clone()).collect();
+        disambiguate(&mut files, DuplicateNames::Numbered);
+        assert_eq!(names(&files), first);
+    }
+
+    #[test]
+    fn test_off_restores_real_names() {
+        let mut files = vec![entry("f1", "a.txt", 100), entry("f2", "a.txt", 300)];
+        disambiguate(&mut files, DuplicateNames::Numbered);
+        disambiguate(&mut files, DuplicateNames::Off);
+        assert_eq!(names(&files), vec!["a.txt", "a.txt"]);
+    }
+
+    #[test]
+    fn test_new_duplicates_keep_existing_names() {
+        let mut files = vec![entry("f2", "a.txt", 200), entry("f1", "a.txt", 100)];
+        disambiguate(&mut files, DuplicateNames::Numbered);
+        assert_eq!(names(&files), vec!["a (2).txt", "a.txt"]);
+
+        // a newer upload of the same name, listed first by the drive
+        let mut files = vec![entry("f3", "a.txt", 300), files[0].clone(), files[1].clone()];
+        files[1].updated_at = 5_000;
+        disambiguate(&mut files, DuplicateNames::Numbered);
+        assert_eq!(names(&files), vec!["a (3).txt", "a (2).txt", "a.txt"]);
+        assert!(DuplicateNames::Numbered.needs_full_listing());
+        assert!(!DuplicateNames::Off.needs_full_listing());
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use dashmap::DashMap;
+use serde::Deserialize;
+use tokio::time::interval;
+use tracing::{debug, error, info};
+
+use crate::cache::Cache;
+use crate::drive::model::OfflineTask;
+use crate::drive::QuarkDrive;
+
+pub const OFFLINE_PATH: &str = "/offline";
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Body of `POST /_quarkdrive/offline`.
+#[derive(Debug, Deserialize)]
+pub struct NewOfflineTask {
+    /// HTTP(S) URL or magnet link for the drive to download
+    pub url: String,
+    /// URL path of the folder to download into, like the target of a request
+    pub path: String,
+}
+
+/// Offline downloads still running, by task id with the fid of the folder
+/// they download into, so that folder is re-listed once they finish.
+#[derive(Clone, Default)]
+pub struct OfflineTasks {
+    pending: Arc<DashMap<String, String>>,
+}
+
+impl OfflineTasks {
+    pub fn track(&self, task_id: &str, to_pdir_fid: &str) {
+        self.pending.insert(task_id.to_string(), to_pdir_fid.to_string());
+    }
+
+    pub fn forget(&self, task_id: &str) {
+        self.pending.remove(task_id);
+    }
+
+    /// Takes in the complete task list of the drive: tracks what is still
+    /// running and returns the folders of the tracked tasks that finished.
+    pub fn observe(&self, tasks: &[OfflineTask]) -> Vec<String> {
+        let listed: HashSet<&str> = tasks.iter().map(|t| t.task_id.as_str()).collect();
+        // cancelled elsewhere
+        self.pending.retain(|task_id, _| listed.contains(task_id.as_str()));
+        let mut finished = Vec::new();
+        for task in tasks {
+            if !task.is_finished() {
+                self.track(&task.task_id, &task.to_pdir_fid);
+            } else if let Some((_, to_pdir_fid)) = self.pending.remove(&task.task_id) {
+                finished.push(to_pdir_fid);
+            }
+        }
+        finished
+    }
+
+    /// Lists the drive's tasks and drops the listings of the folders that
+    /// finished downloads landed in.
+    pub async fn refresh(&self, drive: &QuarkDrive, cache: &Cache) -> Result<Vec<OfflineTask>> {
+        let tasks = drive.list_offline_tasks().await?;
+        let finished = self.observe(&tasks);
+        if !finished.is_empty() {
+            let paths = cache.index().paths_by_fid();
+            for fid in finished {
+                let path = if fid == "0" { Some("/") } else { paths.get(&fid).map(String::as_str) };
+                // a folder never listed has nothing cached
+                if let Some(path) = path {
+                    info!(path = %path, "offline: download finished");
+                    cache.invalidate(Path::new(path)).await;
+                }
+            }
+        }
+        Ok(tasks)
+    }
+}
+
+pub fn start_offline_watcher(tasks: OfflineTasks, drive: QuarkDrive, cache: Arc<Cache>) {
+    tokio::spawn(async move {
+        let mut ticker = interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if tasks.pending.is_empty() {
+                continue;
+            }
+            debug!(count = tasks.pending.len(), "offline: polling tasks");
+            if let Err(err) = tasks.refresh(&drive, &cache).await {
+                error!(error = %err, "offline: listing tasks failed");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(task_id: &str, to_pdir_fid: &str, status: u32) -> OfflineTask {
+        OfflineTask {
+            task_id: task_id.to_string(),
+            url: "magnet:?xt=urn:btih:abc".to_string(),
+            file_name: String::new(),
+            to_pdir_fid: to_pdir_fid.to_string(),
+            status,
+            progress: 0,
+            size: 0,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_offline_observe() {
+        let tasks = OfflineTasks::default();
+        tasks.track("t1", "d1");
+        // finished before anyone was watching
+        assert!(tasks.observe(&[task("t1", "d1", 1), task("t2", "d2", 2)]).is_empty());
+        assert_eq!(tasks.pending.len(), 1);
+
+        tasks.track("t3", "d3");
+        let finished = tasks.observe(&[task("t1", "d1", 3)]);
+        assert_eq!(finished, vec!["d1".to_string()]);
+        // t3 is gone from the drive, no longer tracked
+        assert!(tasks.pending.is_empty());
+    }
+}
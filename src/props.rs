@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use dav_server::fs::DavProp;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+use crate::drive::QuarkFile;
+use crate::snapshot::write_atomic;
+
+const WIN32_NS: &str = "urn:schemas-microsoft-com:";
+
+/// A dead property set by PROPPATCH, `xml` is the raw property element.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredProp {
+    name: String,
+    prefix: Option<String>,
+    namespace: Option<String>,
+    xml: Option<String>,
+}
+
+impl StoredProp {
+    fn to_dav_prop(&self, with_content: bool) -> DavProp {
+        DavProp {
+            name: self.name.clone(),
+            prefix: self.prefix.clone(),
+            namespace: self.namespace.clone(),
+            xml: with_content
+                .then(|| self.xml.as_ref().map(|xml| xml.clone().into_bytes()))
+                .flatten(),
+        }
+    }
+}
+
+fn prop_key(namespace: Option<&str>, name: &str) -> String {
+    format!("{}{}", namespace.unwrap_or_default(), name)
+}
+
+/// Parses an HTTP date, as used by `getlastmodified` and the `Win32*Time`
+/// properties, into unix milliseconds.
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let date = chrono::DateTime::parse_from_rfc2822(date.trim()).ok()?;
+    u64::try_from(date.timestamp_millis()).ok()
+}
+
+/// Modification time a PROPPATCH sets through `Win32LastModifiedTime`.
+fn win32_mtime(prop: &DavProp) -> Option<u64> {
+    if prop.namespace.as_deref() != Some(WIN32_NS) || prop.name != "Win32LastModifiedTime" {
+        return None;
+    }
+    let element = xmltree::Element::parse(prop.xml.as_deref()?).ok()?;
+    parse_http_date(&element.get_text()?)
+}
+
+#[derive(Deserialize)]
+struct PropFile {
+    props: HashMap<String, HashMap<String, StoredProp>>,
+    #[serde(default)]
+    mtimes: HashMap<String, u64>,
+}
+
+/// Dead WebDAV properties and client supplied modification times keyed by
+/// fid, so they stay with an entry when it is renamed or moved. Optionally
+/// saved to a file after every change.
+#[derive(Clone)]
+pub struct PropStore {
+    props: Arc<Mutex<HashMap<String, HashMap<String, StoredProp>>>>,
+    // unix milliseconds, read for every listed entry so kept outside the mutex
+    mtimes: Arc<DashMap<String, u64>>,
+    path: Option<PathBuf>,
+}
+
+impl PropStore {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            props: Arc::new(Mutex::new(HashMap::new())),
+            mtimes: Arc::new(DashMap::new()),
+            path,
+        }
+    }
+
+    pub async fn load(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let loaded: PropFile = serde_json::from_slice(&json)
+            .with_context(|| format!("parse {}", path.display()))?;
+        info!(
+            path = %path.display(),
+            entries = loaded.props.len(),
+            mtimes = loaded.mtimes.len(),
+            "props: loaded"
+        );
+        *self.props.lock().await = loaded.props;
+        self.mtimes.clear();
+        for (fid, mtime) in loaded.mtimes {
+            self.mtimes.insert(fid, mtime);
+        }
+        Ok(())
+    }
+
+    async fn save(&self, props: &HashMap<String, HashMap<String, StoredProp>>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mtimes: HashMap<String, u64> = self
+            .mtimes
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        let json = serde_json::to_vec(&serde_json::json!({ "props": props, "mtimes": mtimes }))
+            .unwrap_or_default();
+        if let Err(err) = write_atomic(path, &json).await {
+            error!(path = %path.display(), error = %err, "props: save failed");
+        }
+    }
+
+    /// All properties of `fid`, with their values only if `with_content`.
+    pub async fn get_all(&self, fid: &str, with_content: bool) -> Vec<DavProp> {
+        self.props
+            .lock()
+            .await
+            .get(fid)
+            .map(|props| props.values().map(|p| p.to_dav_prop(with_content)).collect())
+            .unwrap_or_default()
+    }
+
+    pub async fn get(&self, fid: &str, namespace: Option<&str>, name: &str) -> Option<Vec<u8>> {
+        self.props
+            .lock()
+            .await
+            .get(fid)?
+            .get(&prop_key(namespace, name))?
+            .xml
+            .as_ref()
+            .map(|xml| xml.clone().into_bytes())
+    }
+
+    /// Sets (`true`) or removes (`false`) properties of `fid`. Removing one
+    /// that is not there succeeds, like in dav-server's in-memory filesystem.
+    pub async fn patch(&self, fid: &str, patch: Vec<(bool, DavProp)>) -> Vec<(StatusCode, DavProp)> {
+        let mut all = self.props.lock().await;
+        let props = all.entry(fid.to_string()).or_default();
+        let mut result = Vec::with_capacity(patch.len());
+        for (set, prop) in patch {
+            let key = prop_key(prop.namespace.as_deref(), &prop.name);
+            if set && let Some(mtime) = win32_mtime(&prop) {
+                self.mtimes.insert(fid.to_string(), mtime);
+            }
+            let stored = StoredProp {
+                name: prop.name,
+                prefix: prop.prefix,
+                namespace: prop.namespace,
+                xml: prop.xml.map(|xml| String::from_utf8_lossy(&xml).into_owned()),
+            };
+            let status_prop = stored.to_dav_prop(false);
+            if set {
+                props.insert(key, stored);
+            } else {
+                props.remove(&key);
+            }
+            result.push((StatusCode::OK, status_prop));
+        }
+        if props.is_empty() {
+            all.remove(fid);
+        }
+        debug!(file_id = %fid, count = result.len(), "props: patched");
+        self.save(&all).await;
+        result
+    }
+
+    /// Drops the properties of a deleted entry.
+    pub async fn remove(&self, fid: &str) {
+        let mut all = self.props.lock().await;
+        let had_mtime = self.mtimes.remove(fid).is_some();
+        if all.remove(fid).is_some() || had_mtime {
+            self.save(&all).await;
+        }
+    }
+
+    /// Reports `mtime` (unix milliseconds) as the modification time of `fid`
+    /// instead of the time Quark stored it.
+    pub async fn set_mtime(&self, fid: &str, mtime: u64) {
+        let all = self.props.lock().await;
+        self.mtimes.insert(fid.to_string(), mtime);
+        debug!(file_id = %fid, mtime = mtime, "props: modification time set");
+        self.save(&all).await;
+    }
+
+    pub fn apply_mtime(&self, file: &mut QuarkFile) {
+        if let Some(mtime) = self.mtimes.get(&file.fid) {
+            file.updated_at = *mtime;
+        }
+    }
+
+    /// Hands the properties of `from` to `to`, when an upload replaced a file
+    /// with a new one that has a new fid. The modification time stays behind,
+    /// the new content has its own.
+    pub async fn transfer(&self, from: &str, to: &str) {
+        let mut all = self.props.lock().await;
+        if let Some(props) = all.remove(from) {
+            all.insert(to.to_string(), props);
+            self.save(&all).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prop(namespace: &str, name: &str, value: Option<&str>) -> DavProp {
+        DavProp {
+            name: name.to_string(),
+            prefix: Some("Z".to_string()),
+            namespace: Some(namespace.to_string()),
+            xml: value.map(|v| format!("<Z:{name} xmlns:Z=\"{namespace}\">{v}</Z:{name}>").into_bytes()),
+        }
+    }
+
+    const WIN: &str = "urn:schemas-microsoft-com:";
+
+    #[tokio::test]
+    async fn test_patch_get_and_remove() {
+        let store = PropStore::new(None);
+        let result = store
+            .patch("f1", vec![
+                (true, prop(WIN, "Win32FileAttributes", Some("00000020"))),
+                (true, prop("http://example.com/ns", "tags", Some("red"))),
+            ])
+            .await;
+        assert!(result.iter().all(|(status, p)| *status == StatusCode::OK && p.xml.is_none()));
+        let value = store.get("f1", Some(WIN), "Win32FileAttributes").await.unwrap();
+        assert!(String::from_utf8(value).unwrap().contains("00000020"));
+        assert!(store.get("f1", None, "Win32FileAttributes").await.is_none());
+        assert!(store.get("f2", Some(WIN), "Win32FileAttributes").await.is_none());
+
+        let all = store.get_all("f1", false).await;
+        assert_eq!(all.len(), 2);
+        assert!(all.iter().all(|p| p.xml.is_none()));
+        assert!(store.get_all("f1", true).await.iter().all(|p| p.xml.is_some()));
+
+        store.patch("f1", vec![(false, prop(WIN, "Win32FileAttributes", None))]).await;
+        // removing what is not there succeeds
+        let result = store.patch("f1", vec![(false, prop(WIN, "missing", None))]).await;
+        assert_eq!(result[0].0, StatusCode::OK);
+        assert_eq!(store.get_all("f1", true).await.len(), 1);
+
+        store.remove("f1").await;
+        assert!(store.get_all("f1", true).await.is_empty());
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:01 GMT"), Some(1000));
+        assert_eq!(parse_http_date(" Sun, 18 Oct 2026 10:00:00 GMT "), Some(1_792_317_600_000));
+        assert!(parse_http_date("yesterday").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mtime_overrides() {
+        let store = PropStore::new(None);
+        let mut file = QuarkFile::new_dir("f1".to_string(), "a.txt".to_string(), "0".to_string(), "/".to_string());
+        let uploaded = file.updated_at;
+        store.apply_mtime(&mut file);
+        assert_eq!(file.updated_at, uploaded);
+
+        // Windows sends the time along with its other attributes
+        store
+            .patch("f1", vec![(true, prop(WIN, "Win32LastModifiedTime", Some("Thu, 01 Jan 1970 00:00:02 GMT")))])
+            .await;
+        store.apply_mtime(&mut file);
+        assert_eq!(file.updated_at, 2000);
+
+        store.set_mtime("f1", 5000).await;
+        store.apply_mtime(&mut file);
+        assert_eq!(file.updated_at, 5000);
+
+        // a new version has new content, its time is not carried over
+        store.transfer("f1", "f2").await;
+        let mut new_version = QuarkFile::new_dir("f2".to_string(), "a.txt".to_string(), "0".to_string(), "/".to_string());
+        new_version.updated_at = 9000;
+        store.apply_mtime(&mut new_version);
+        assert_eq!(new_version.updated_at, 9000);
+
+        store.remove("f1").await;
+        let mut file = QuarkFile::new_dir("f1".to_string(), "a.txt".to_string(), "0".to_string(), "/".to_string());
+        file.updated_at = 7;
+        store.apply_mtime(&mut file);
+        assert_eq!(file.updated_at, 7);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_to_new_version() {
+        let store = PropStore::new(None);
+        store.patch("old", vec![(true, prop(WIN, "Win32CreationTime", Some("x")))]).await;
+        store.transfer("old", "new").await;
+        assert!(store.get_all("old", true).await.is_empty());
+        assert!(store.get("new", Some(WIN), "Win32CreationTime").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_props_survive_restart() {
+        let path = std::env::temp_dir().join(format!("quarkdrive-props-{}.json", std::process::id()));
+        let store = PropStore::new(Some(path.clone()));
+        store.patch("f1", vec![(true, prop("http://example.com/ns", "rating", Some("5")))]).await;
+        store.set_mtime("f2", 1234).await;
+
+        let restored = PropStore::new(Some(path.clone()));
+        restored.load().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        let value = restored.get("f1", Some("http://example.com/ns"), "rating").await.unwrap();
+        assert!(String::from_utf8(value).unwrap().contains(">5<"));
+        let mut file = QuarkFile::new_dir("f2".to_string(), "b.txt".to_string(), "0".to_string(), "/".to_string());
+        restored.apply_mtime(&mut file);
+        assert_eq!(file.updated_at, 1234);
+    }
+}
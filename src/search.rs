@@ -0,0 +1,382 @@
+//! WebDAV SEARCH (RFC 5323) with the `DAV:basicsearch` grammar. The drive can
+//! only search names, so a query has to look for one with `DAV:like` on
+//! `DAV:displayname`; sizes, modification times and the scope narrow down
+//! what the drive finds.
+
+use anyhow::{bail, Context, Result};
+use dav_server::fs::DavMetaData;
+use xmltree::{Element, XMLNode};
+
+use crate::drive::QuarkFile;
+use crate::props::parse_http_date;
+
+const DAV_NS: &str = "DAV:";
+
+/// One character of a `DAV:like` pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    /// `%`, any run of characters.
+    Any,
+    /// `_`, exactly one character.
+    One,
+    Char(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Comparison {
+    field: Field,
+    op: Op,
+    value: u64,
+}
+
+impl Comparison {
+    fn matches(&self, file: &QuarkFile) -> bool {
+        let (actual, value) = match self.field {
+            // folders have no DAV:getcontentlength to compare
+            Field::Size if file.dir => return false,
+            Field::Size => (file.size, self.value),
+            // HTTP dates have whole seconds
+            Field::Modified => (file.updated_at / 1000, self.value / 1000),
+        };
+        match self.op {
+            Op::Eq => actual == value,
+            Op::Lt => actual < value,
+            Op::Lte => actual <= value,
+            Op::Gt => actual > value,
+            Op::Gte => actual >= value,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    /// What the drive is asked for, the longest literal part of the pattern.
+    pub keyword: String,
+    pattern: Vec<Token>,
+    comparisons: Vec<Comparison>,
+    /// `DAV:href` of the scope, the request URI when there is none.
+    pub scope: Option<String>,
+    /// The whole subtree of the scope rather than just its entries.
+    pub deep: bool,
+    pub limit: Option<usize>,
+}
+
+impl SearchQuery {
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let root = Element::parse(body).context("malformed search request")?;
+        if root.name != "searchrequest" || root.namespace.as_deref() != Some(DAV_NS) {
+            bail!("expected a DAV:searchrequest");
+        }
+        let search = dav_child(&root, "basicsearch").context("only DAV:basicsearch is supported")?;
+        let mut query = SearchQuery {
+            deep: true,
+            ..Default::default()
+        };
+        if let Some(scope) = dav_child(search, "from").and_then(|from| dav_child(from, "scope")) {
+            query.scope = dav_child(scope, "href").and_then(text);
+            query.deep = dav_child(scope, "depth")
+                .and_then(text)
+                .is_none_or(|depth| depth.eq_ignore_ascii_case("infinity"));
+        }
+        if let Some(condition) = dav_child(search, "where").and_then(|w| elements(w).next()) {
+            query.add_condition(condition)?;
+        }
+        query.limit = dav_child(search, "limit")
+            .and_then(|limit| dav_child(limit, "nresults"))
+            .and_then(text)
+            .and_then(|n| n.parse().ok());
+        if query.keyword.is_empty() {
+            bail!("the search needs a DAV:like on DAV:displayname with some text to look for");
+        }
+        Ok(query)
+    }
+
+    fn add_condition(&mut self, condition: &Element) -> Result<()> {
+        let op = match condition.name.as_str() {
+            "and" => {
+                for condition in elements(condition) {
+                    self.add_condition(condition)?;
+                }
+                return Ok(());
+            }
+            "like" => {
+                if prop_name(condition) != Some("displayname") {
+                    bail!("DAV:like is only supported on DAV:displayname");
+                }
+                if !self.pattern.is_empty() {
+                    bail!("only one DAV:like is supported");
+                }
+                self.pattern = tokenize(&literal(condition)?.to_lowercase());
+                self.keyword = keyword(&self.pattern);
+                return Ok(());
+            }
+            "eq" => Op::Eq,
+            "lt" => Op::Lt,
+            "lte" => Op::Lte,
+            "gt" => Op::Gt,
+            "gte" => Op::Gte,
+            other => bail!("DAV:{} is not supported", other),
+        };
+        let literal = literal(condition)?;
+        let (field, value) = match prop_name(condition) {
+            Some("getcontentlength") => (Field::Size, literal.parse().context("size is not a number")?),
+            Some("getlastmodified") => (Field::Modified, parse_date(&literal).context("unknown date format")?),
+            other => bail!("comparing {} is not supported", other.unwrap_or("nothing")),
+        };
+        self.comparisons.push(Comparison { field, op, value });
+        Ok(())
+    }
+
+    pub fn matches(&self, file: &QuarkFile) -> bool {
+        let name: Vec<char> = file.file_name.to_lowercase().chars().collect();
+        like(&self.pattern, &name) && self.comparisons.iter().all(|c| c.matches(file))
+    }
+}
+
+fn dav_child<'a>(element: &'a Element, name: &str) -> Option<&'a Element> {
+    element.get_child((name, DAV_NS))
+}
+
+fn elements(element: &Element) -> impl Iterator<Item = &Element> {
+    element.children.iter().filter_map(XMLNode::as_element)
+}
+
+fn text(element: &Element) -> Option<String> {
+    Some(element.get_text()?.trim().to_string())
+}
+
+/// Name of the `DAV:prop` a condition is about.
+fn prop_name(condition: &Element) -> Option<&str> {
+    let prop = dav_child(condition, "prop")?;
+    elements(prop).next().map(|e| e.name.as_str())
+}
+
+fn literal(condition: &Element) -> Result<String> {
+    dav_child(condition, "literal")
+        .and_then(text)
+        .context("condition without a DAV:literal")
+}
+
+/// `DAV:getlastmodified` literals as HTTP dates or RFC 3339, in unix milliseconds.
+fn parse_date(value: &str) -> Option<u64> {
+    parse_http_date(value).or_else(|| {
+        let date = chrono::DateTime::parse_from_rfc3339(value).ok()?;
+        u64::try_from(date.timestamp_millis()).ok()
+    })
+}
+
+/// `\` escapes the next character, so `\%` is a literal percent sign.
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '%' => Token::Any,
+            '_' => Token::One,
+            '\\' => Token::Char(chars.next().unwrap_or('\\')),
+            c => Token::Char(c),
+        });
+    }
+    tokens
+}
+
+fn keyword(pattern: &[Token]) -> String {
+    pattern
+        .split(|t| !matches!(t, Token::Char(_)))
+        .max_by_key(|run| run.len())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|t| match t {
+            Token::Char(c) => Some(*c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// SQL `LIKE` matching, backtracking only to the last `%`.
+fn like(pattern: &[Token], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut last_any = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(Token::Any) => {
+                last_any = Some((p, n));
+                p += 1;
+            }
+            Some(Token::One) => {
+                p += 1;
+                n += 1;
+            }
+            Some(Token::Char(c)) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match last_any {
+                Some((any_p, any_n)) => {
+                    last_any = Some((any_p, any_n + 1));
+                    p = any_p + 1;
+                    n = any_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|t| *t == Token::Any)
+}
+
+/// `207 Multi-Status` body listing `hits`, pairs of href and entry, with the
+/// properties a file manager shows.
+pub fn multistatus(hits: &[(String, QuarkFile)]) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">"#);
+    for (href, file) in hits {
+        xml.push_str("<D:response><D:href>");
+        xml.push_str(&htmlescape::encode_minimal(href));
+        xml.push_str("</D:href><D:propstat><D:prop>");
+        xml.push_str(&format!(
+            "<D:displayname>{}</D:displayname>",
+            htmlescape::encode_minimal(&file.file_name)
+        ));
+        if file.dir {
+            xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            xml.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>",
+                file.size
+            ));
+        }
+        if let Some(modified) = chrono::DateTime::from_timestamp_millis(file.updated_at as i64) {
+            xml.push_str(&format!(
+                "<D:getlastmodified>{}</D:getlastmodified>",
+                modified.format("%a, %d %b %Y %H:%M:%S GMT")
+            ));
+        }
+        if let Some(etag) = file.etag() {
+            xml.push_str(&format!("<D:getetag>\"{}\"</D:getetag>", htmlescape::encode_minimal(&etag)));
+        }
+        xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>");
+    }
+    xml.push_str("</D:multistatus>");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, size: u64, updated_at: u64) -> QuarkFile {
+        let mut file = QuarkFile::new_file("fid", name, "/");
+        file.size = size;
+        file.updated_at = updated_at;
+        file
+    }
+
+    #[test]
+    fn test_like() {
+        let matches = |pattern: &str, name: &str| {
+            like(&tokenize(pattern), &name.chars().collect::<Vec<_>>())
+        };
+        assert!(matches("%report%", "2024 report.pdf"));
+        assert!(matches("report%", "report.pdf"));
+        assert!(!matches("report%", "old report.pdf"));
+        assert!(matches("%.mp_", "a.mp4"));
+        assert!(!matches("%.mp_", "a.mpeg"));
+        assert!(matches("%a%b%", "xxaxxbxx"));
+        assert!(!matches("%a%b%", "xxbxxaxx"));
+        assert!(matches("100\\%%", "100% done"));
+        assert!(!matches("100\\%%", "1000 done"));
+        assert!(matches("exact.txt", "exact.txt"));
+        assert!(!matches("exact.txt", "exact.txt.bak"));
+    }
+
+    #[test]
+    fn test_keyword() {
+        assert_eq!(keyword(&tokenize("%report%")), "report");
+        assert_eq!(keyword(&tokenize("a_longer%x")), "longer");
+        assert_eq!(keyword(&tokenize("%%")), "");
+    }
+
+    #[test]
+    fn test_parse_basicsearch() {
+        let body = br#"<?xml version="1.0"?>
+<d:searchrequest xmlns:d="DAV:">
+  <d:basicsearch>
+    <d:select><d:prop><d:displayname/></d:prop></d:select>
+    <d:from><d:scope><d:href>/dav/movies</d:href><d:depth>1</d:depth></d:scope></d:from>
+    <d:where>
+      <d:and>
+        <d:like><d:prop><d:displayname/></d:prop><d:literal>%Holiday%</d:literal></d:like>
+        <d:gte><d:prop><d:getcontentlength/></d:prop><d:literal>1000</d:literal></d:gte>
+        <d:lt><d:prop><d:getlastmodified/></d:prop><d:literal>Wed, 01 Jan 2025 00:00:00 GMT</d:literal></d:lt>
+      </d:and>
+    </d:where>
+    <d:limit><d:nresults>20</d:nresults></d:limit>
+  </d:basicsearch>
+</d:searchrequest>"#;
+        let query = SearchQuery::parse(body).unwrap();
+        assert_eq!(query.keyword, "holiday");
+        assert_eq!(query.scope.as_deref(), Some("/dav/movies"));
+        assert!(!query.deep);
+        assert_eq!(query.limit, Some(20));
+
+        let new_year = 1_735_689_600_000;
+        assert!(query.matches(&file("Holiday 2024.mp4", 5000, new_year - 1000)));
+        assert!(!query.matches(&file("Holiday 2024.mp4", 999, new_year - 1000)));
+        assert!(!query.matches(&file("Holiday 2025.mp4", 5000, new_year)));
+        assert!(!query.matches(&file("Work.mp4", 5000, new_year - 1000)));
+        let mut dir = file("Holiday", 5000, 0);
+        dir.dir = true;
+        assert!(!query.matches(&dir));
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported() {
+        let search = |condition: &str| {
+            SearchQuery::parse(
+                format!(
+                    r#"<d:searchrequest xmlns:d="DAV:"><d:basicsearch><d:where>{}</d:where></d:basicsearch></d:searchrequest>"#,
+                    condition
+                )
+                .as_bytes(),
+            )
+        };
+        assert!(search("").is_err());
+        assert!(search(r#"<d:or/>"#).is_err());
+        assert!(search(r#"<d:like><d:prop><d:getcontenttype/></d:prop><d:literal>%a%</d:literal></d:like>"#).is_err());
+        assert!(search(r#"<d:like><d:prop><d:displayname/></d:prop><d:literal>%</d:literal></d:like>"#).is_err());
+        let query = search(r#"<d:like><d:prop><d:displayname/></d:prop><d:literal>a%</d:literal></d:like>"#).unwrap();
+        assert!(query.deep);
+        assert!(query.scope.is_none());
+        assert!(SearchQuery::parse(b"<d:propfind xmlns:d=\"DAV:\"/>").is_err());
+        assert!(SearchQuery::parse(b"not xml").is_err());
+    }
+
+    #[test]
+    fn test_multistatus() {
+        let mut dir = file("a & b", 0, 1_700_000_000_000);
+        dir.dir = true;
+        let xml = multistatus(&[
+            ("/dav/a%20%26%20b/".to_string(), dir),
+            ("/dav/x.txt".to_string(), file("x.txt", 12, 1_700_000_000_000)),
+        ]);
+        assert!(xml.contains("<D:href>/dav/a%20%26%20b/</D:href>"));
+        assert!(xml.contains("<D:displayname>a &amp; b</D:displayname><D:resourcetype><D:collection/></D:resourcetype>"));
+        assert!(xml.contains("<D:getcontentlength>12</D:getcontentlength>"));
+        assert!(xml.contains("<D:getlastmodified>Tue, 14 Nov 2023 22:13:20 GMT</D:getlastmodified>"));
+        assert!(Element::parse(xml.as_bytes()).is_ok());
+    }
+}
@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use dashmap::DashMap;
+use serde::Deserialize;
+use tracing::debug;
+use url::Url;
+
+use crate::drive::model::{ShareInfo, SharedFile};
+use crate::drive::QuarkDrive;
+
+pub const SHARES_PATH: &str = "/shares";
+pub const IMPORT_PATH: &str = "/import";
+
+/// Body of `POST /_quarkdrive/shares`.
+#[derive(Debug, Deserialize)]
+pub struct NewShare {
+    /// URL path of the file or folder to share, like the target of a request
+    pub path: String,
+    /// Makes the link ask for this passcode, public when missing
+    #[serde(default)]
+    pub passcode: Option<String>,
+    /// 1, 7 or 30; the link does not expire when missing
+    #[serde(default)]
+    pub expire_days: Option<u32>,
+}
+
+/// Body of `POST /_quarkdrive/import`.
+#[derive(Debug, Deserialize)]
+pub struct ImportShare {
+    /// The share link, `https://pan.quark.cn/s/<id>`
+    pub url: String,
+    /// Extraction code, when the link asks for one and does not carry it
+    #[serde(default)]
+    pub passcode: Option<String>,
+    /// URL path of the folder to save into, like the target of a request
+    pub path: String,
+    /// Names of the top level entries to save, all of them when empty
+    #[serde(default)]
+    pub items: Vec<String>,
+}
+
+/// Someone else's share link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareLink {
+    pub pwd_id: String,
+    pub passcode: Option<String>,
+}
+
+impl ShareLink {
+    /// Parses `https://pan.quark.cn/s/<id>`, taking the extraction code from a
+    /// `pwd` query parameter when there is one. `passcode` wins over it.
+    pub fn parse(link: &str, passcode: Option<&str>) -> Result<Self> {
+        let url = Url::parse(link.trim()).ok();
+        let Some(pwd_id) = url
+            .as_ref()
+            .and_then(Url::path_segments)
+            .and_then(|mut segments| (segments.next() == Some("s")).then(|| segments.next()).flatten())
+            .filter(|id| !id.is_empty())
+        else {
+            bail!("not a share link: {}", link);
+        };
+        let passcode = passcode
+            .map(str::to_string)
+            .or_else(|| url.as_ref()?.query_pairs().find(|(k, _)| k == "pwd").map(|(_, v)| v.into_owned()))
+            .filter(|p| !p.is_empty());
+        Ok(Self { pwd_id: pwd_id.to_string(), passcode })
+    }
+
+    /// Top level entries of the link, with the token to save them with.
+    pub async fn open(&self, drive: &QuarkDrive) -> Result<(String, Vec<SharedFile>)> {
+        let stoken = drive.share_token(&self.pwd_id, self.passcode.as_deref()).await?;
+        let files = drive.shared_files(&self.pwd_id, &stoken, "0").await?;
+        Ok((stoken, files))
+    }
+
+    /// Saves the top level entries named in `items`, all of them when empty,
+    /// into the folder `to_pdir_fid`. Returns what was saved.
+    pub async fn save(&self, drive: &QuarkDrive, items: &[String], to_pdir_fid: &str) -> Result<Vec<SharedFile>> {
+        let (stoken, files) = self.open(drive).await?;
+        let files = select_items(files, items)?;
+        if files.is_empty() {
+            bail!("share {} is empty", self.pwd_id);
+        }
+        drive.save_shared_files(&self.pwd_id, &stoken, &files, to_pdir_fid).await?;
+        debug!(pwd_id = %self.pwd_id, count = files.len(), to_pdir_fid = %to_pdir_fid, "shares: saved");
+        Ok(files)
+    }
+}
+
+/// The entries of `files` named in `items`, all of them when `items` is empty.
+fn select_items(files: Vec<SharedFile>, items: &[String]) -> Result<Vec<SharedFile>> {
+    if items.is_empty() {
+        return Ok(files);
+    }
+    let missing: Vec<&str> = items
+        .iter()
+        .filter(|item| !files.iter().any(|f| &f.file_name == *item))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        bail!("not in the share: {}", missing.join(", "));
+    }
+    Ok(files.into_iter().filter(|f| items.contains(&f.file_name)).collect())
+}
+
+/// Share links of the drive by the fid they share, so PROPFIND can publish
+/// them as `quark:share-url` without asking the drive for every entry.
+#[derive(Clone, Default)]
+pub struct ShareTable {
+    by_fid: Arc<DashMap<String, ShareInfo>>,
+}
+
+impl ShareTable {
+    /// Replaces what is known with the links the drive has now, and returns them.
+    pub async fn refresh(&self, drive: &QuarkDrive) -> Result<Vec<ShareInfo>> {
+        let shares = drive.list_shares().await?;
+        debug!(count = shares.len(), "shares: refreshed");
+        self.by_fid.clear();
+        // newest first, so the newest link of a twice shared entry wins
+        for share in shares.iter().rev() {
+            self.insert(share.clone());
+        }
+        Ok(shares)
+    }
+
+    pub fn insert(&self, share: ShareInfo) {
+        if !share.first_fid.is_empty() {
+            self.by_fid.insert(share.first_fid.clone(), share);
+        }
+    }
+
+    pub fn remove(&self, share_id: &str) {
+        self.by_fid.retain(|_, share| share.share_id != share_id);
+    }
+
+    pub fn url(&self, fid: &str) -> Option<String> {
+        self.by_fid.get(fid).map(|share| share.share_url.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share(share_id: &str, fid: &str) -> ShareInfo {
+        ShareInfo {
+            share_id: share_id.to_string(),
+            share_url: format!("https://pan.quark.cn/s/{}", share_id),
+            passcode: None,
+            title: "title".to_string(),
+            first_fid: fid.to_string(),
+            expired_at: 0,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_share_table() {
+        let table = ShareTable::default();
+        table.insert(share("s1", "f1"));
+        table.insert(share("s2", "f2"));
+        table.insert(share("s3", ""));
+        assert_eq!(table.url("f1"), Some("https://pan.quark.cn/s/s1".to_string()));
+        table.remove("s1");
+        assert!(table.url("f1").is_none());
+        assert!(table.url("f2").is_some());
+    }
+
+    fn shared(name: &str) -> SharedFile {
+        SharedFile {
+            fid: format!("fid-{}", name),
+            file_name: name.to_string(),
+            pdir_fid: "0".to_string(),
+            share_fid_token: format!("token-{}", name),
+            dir: false,
+            size: 1,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_share_link_parse() {
+        let link = ShareLink::parse("https://pan.quark.cn/s/abc123", None).unwrap();
+        assert_eq!(link, ShareLink { pwd_id: "abc123".to_string(), passcode: None });
+        let link = ShareLink::parse("https://pan.quark.cn/s/abc123?pwd=x9y8#/list/share", None).unwrap();
+        assert_eq!(link.passcode.as_deref(), Some("x9y8"));
+        let link = ShareLink::parse("https://pan.quark.cn/s/abc123?pwd=x9y8", Some("1234")).unwrap();
+        assert_eq!(link.passcode.as_deref(), Some("1234"));
+        assert!(ShareLink::parse("https://pan.quark.cn/list", None).is_err());
+        assert!(ShareLink::parse("https://pan.quark.cn/s/", None).is_err());
+        assert!(ShareLink::parse("abc123", None).is_err());
+    }
+
+    #[test]
+    fn test_select_items() {
+        let files = vec![shared("a"), shared("b"), shared("c")];
+        assert_eq!(select_items(files.clone(), &[]).unwrap().len(), 3);
+        let picked = select_items(files.clone(), &["c".to_string(), "a".to_string()]).unwrap();
+        let names: Vec<_> = picked.iter().map(|f| f.file_name.as_str()).collect();
+        assert_eq!(names, vec!["a", "c"]);
+        let err = select_items(files, &["a".to_string(), "x".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "not in the share: x");
+    }
+}
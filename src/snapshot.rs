@@ -0,0 +1,219 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::time::interval;
+use tracing::{debug, error, info};
+
+use crate::cache::Cache;
+use crate::drive::{QuarkDrive, QuarkFile};
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotData {
+    version: u32,
+    saved_at: u64,
+    listings: Vec<(String, Vec<QuarkFile>)>,
+    dir_fids: Vec<(String, String)>,
+    dir_children: Vec<(String, Vec<String>)>,
+    md5: Vec<(String, String)>,
+}
+
+/// Persists directory listings, the path index and the md5 cache so a restart
+/// does not have to rebuild the whole tree through the API.
+#[derive(Clone)]
+pub struct Snapshot {
+    path: PathBuf,
+    cache: Cache,
+    drive: QuarkDrive,
+}
+
+impl Snapshot {
+    pub fn new(path: PathBuf, cache: Cache, drive: QuarkDrive) -> Self {
+        Self { path, cache, drive }
+    }
+
+    fn collect(&self) -> SnapshotData {
+        let listings = self
+            .cache
+            .export_listings()
+            .into_iter()
+            .map(|(key, mut files)| {
+                // file_name is html-unescaped when deserialized, store it the way the API sends it
+                for f in files.iter_mut() {
+                    // duplicate names are assigned again on load
+                    if let Some(real) = f.real_name.take() {
+                        f.file_name = real;
+                    }
+                    f.file_name = htmlescape::encode_minimal(&f.file_name);
+                    f.download_url = None;
+                }
+                (key, files)
+            })
+            .collect();
+        SnapshotData {
+            version: SNAPSHOT_VERSION,
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            listings,
+            dir_fids: self.cache.index().export_fids(),
+            dir_children: self.cache.index().export_children(),
+            md5: self.drive.export_md5_cache(),
+        }
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let data = self.collect();
+        let json = serde_json::to_vec(&data)?;
+        write_atomic(&self.path, &json).await?;
+        debug!(
+            path = %self.path.display(),
+            listings = data.listings.len(),
+            dirs = data.dir_fids.len(),
+            md5 = data.md5.len(),
+            "snapshot: saved"
+        );
+        Ok(())
+    }
+
+    pub async fn load(&self) -> Result<()> {
+        let json = match tokio::fs::read(&self.path).await {
+            Ok(json) => json,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                debug!(path = %self.path.display(), "snapshot: none found");
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let data: SnapshotData = serde_json::from_slice(&json)
+            .with_context(|| format!("parse {}", self.path.display()))?;
+        if data.version != SNAPSHOT_VERSION {
+            info!(version = data.version, "snapshot: ignoring incompatible version");
+            return Ok(());
+        }
+        let listings = data.listings.len();
+        self.cache.index().import(data.dir_fids, data.dir_children);
+        self.drive.import_md5_cache(data.md5);
+        self.cache.import_stale_listings(data.listings).await;
+        info!(
+            path = %self.path.display(),
+            listings = listings,
+            saved_at = data.saved_at,
+            "snapshot: loaded"
+        );
+        Ok(())
+    }
+}
+
+/// Writes `bytes` to a temporary file next to `path` and renames it over
+/// `path`, so a crash never leaves a truncated file behind.
+pub(crate) async fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // appended rather than swapped for the extension, `state.locks` and
+    // `state.props` would share a temporary file otherwise
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    tokio::fs::write(&tmp_path, bytes)
+        .await
+        .with_context(|| format!("write {}", tmp_path.display()))?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+pub fn start_periodic_snapshot(snapshot: Snapshot, secs: u64) {
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(secs));
+        // the first tick completes immediately, nothing worth saving yet
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(err) = snapshot.save().await {
+                error!(error = %err, "snapshot: save failed");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::create_test_drive;
+
+    fn temp_snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "quarkdrive-snapshot-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_write_atomic_siblings() {
+        let dir = std::env::temp_dir().join(format!("quarkdrive-atomic-{}", std::process::id()));
+        let locks = dir.join("state.locks");
+        let props = dir.join("state.props");
+        let (a, b) = tokio::join!(write_atomic(&locks, b"locks"), write_atomic(&props, b"props"));
+        a.unwrap();
+        b.unwrap();
+        assert_eq!(std::fs::read(&locks).unwrap(), b"locks");
+        assert_eq!(std::fs::read(&props).unwrap(), b"props");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_roundtrip() {
+        let path = temp_snapshot_path("roundtrip");
+        let drive = create_test_drive();
+        let cache = Cache::new(100, 60, drive.clone());
+        let mut file = QuarkFile::new_file("f1", "a &amp; b.txt", "/");
+        file.download_url = Some("https://example.com/x".to_string());
+        let files = vec![QuarkFile::new_dir("d1".into(), "movies".into(), "0".into(), "/".into()), file];
+        cache.index().record_listing("/", "0", &files);
+        cache.import_stale_listings(vec![("/".to_string(), files)]).await;
+        drive.import_md5_cache(vec![("f1".to_string(), "abc".to_string())]);
+        Snapshot::new(path.clone(), cache, drive).save().await.unwrap();
+
+        let drive = create_test_drive();
+        let cache = Cache::new(100, 60, drive.clone());
+        Snapshot::new(path.clone(), cache.clone(), drive.clone()).load().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(cache.index().fid("/movies"), Some("d1".to_string()));
+        assert_eq!(drive.get_cached_md5("f1"), Some("abc".to_string()));
+        let listing = cache.export_listings();
+        assert_eq!(listing.len(), 1);
+        let names: Vec<&str> = listing[0].1.iter().map(|f| f.file_name.as_str()).collect();
+        assert!(names.contains(&"a &amp; b.txt"));
+        assert!(listing[0].1.iter().all(|f| f.download_url.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_load_missing_file() {
+        let drive = create_test_drive();
+        let cache = Cache::new(100, 60, drive.clone());
+        let snapshot = Snapshot::new(temp_snapshot_path("missing"), cache, drive);
+        assert!(snapshot.load().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_load_incompatible_version() {
+        let path = temp_snapshot_path("version");
+        std::fs::write(
+            &path,
+            r#"{"version":0,"saved_at":0,"listings":[["/",[]]],"dir_fids":[["/a","x"]],"dir_children":[],"md5":[]}"#,
+        )
+        .unwrap();
+        let drive = create_test_drive();
+        let cache = Cache::new(100, 60, drive.clone());
+        Snapshot::new(path.clone(), cache.clone(), drive).load().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(cache.index().fid("/a").is_none());
+    }
+}
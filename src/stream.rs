@@ -0,0 +1,299 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::{Method, Response, StatusCode};
+use moka::future::Cache as MokaCache;
+use sha2::Sha256;
+use tracing::{debug, error};
+
+use crate::drive::QuarkDrive;
+use crate::vfs::is_url_expired;
+use crate::webdav::ResponseBody;
+
+pub const STREAM_PATH: &str = "/stream/";
+
+// headers copied from the upstream CDN response to the client
+const FORWARD_HEADERS: [&str; 6] = [
+    "content-type",
+    "content-length",
+    "content-range",
+    "accept-ranges",
+    "etag",
+    "last-modified",
+];
+
+type HmacSha256 = Hmac<Sha256>;
+
+// download URLs kept per fid; Quark's expire well before this anyway
+const DOWNLOAD_URL_CAPACITY: u64 = 10_000;
+const DOWNLOAD_URL_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Issues and verifies short-lived `/_quarkdrive/stream/<token>/<name>` URLs.
+///
+/// A token is `base64url("<expires>:<fid>") . base64url(hmac)`, so it can be
+/// verified without any server side state and survives restarts as long as
+/// the secret stays the same.
+#[derive(Clone)]
+pub struct StreamSigner {
+    secret: Arc<Vec<u8>>,
+    ttl: u64,
+    base_path: String,
+}
+
+impl StreamSigner {
+    pub fn new(secret: &[u8], ttl: u64, base_path: Option<&str>) -> Self {
+        let base_path = base_path
+            .map(|p| p.trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        Self {
+            secret: Arc::new(secret.to_vec()),
+            ttl,
+            base_path,
+        }
+    }
+
+    pub fn with_random_secret(ttl: u64, base_path: Option<&str>) -> Self {
+        let secret: [u8; 32] = rand::random();
+        Self::new(&secret, ttl, base_path)
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac accepts any key size");
+        mac.update(payload);
+        mac
+    }
+
+    pub fn sign(&self, fid: &str, now: u64) -> String {
+        let payload = format!("{}:{}", now + self.ttl, fid);
+        let sig = self.mac(payload.as_bytes()).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload.as_bytes()),
+            URL_SAFE_NO_PAD.encode(sig)
+        )
+    }
+
+    /// Returns the fid carried by `token` if the signature is valid and it has not expired.
+    pub fn verify(&self, token: &str, now: u64) -> Option<String> {
+        let (payload, sig) = token.split_once('.')?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+        let sig = URL_SAFE_NO_PAD.decode(sig).ok()?;
+        self.mac(&payload).verify_slice(&sig).ok()?;
+        let payload = String::from_utf8(payload).ok()?;
+        let (expires, fid) = payload.split_once(':')?;
+        let expires = expires.parse::<u64>().ok()?;
+        if now >= expires || fid.is_empty() {
+            return None;
+        }
+        Some(fid.to_string())
+    }
+
+    /// Relative URL handed out as the `Location` of a 302 redirect.
+    pub fn stream_url(&self, fid: &str, file_name: &str) -> String {
+        self.signed_url(STREAM_PATH, fid, file_name)
+    }
+
+    /// `<base path><endpoint><token>/<name>`, the token carrying `payload`.
+    pub fn signed_url(&self, endpoint: &str, payload: &str, name: &str) -> String {
+        format!(
+            "{}{}{}/{}",
+            self.base_path,
+            endpoint,
+            self.sign(payload, now_secs()),
+            percent_encoding::utf8_percent_encode(name, percent_encoding::NON_ALPHANUMERIC)
+        )
+    }
+
+    /// Extracts the token from a request path (after `strip_prefix` handling).
+    pub fn token_from_path(path: &str) -> Option<&str> {
+        Self::token_from_endpoint_path(path, STREAM_PATH)
+    }
+
+    pub fn token_from_endpoint_path<'a>(path: &'a str, endpoint: &str) -> Option<&'a str> {
+        let rest = path.strip_prefix(endpoint)?;
+        let token = rest.split('/').next()?;
+        if token.is_empty() {
+            None
+        } else {
+            Some(token)
+        }
+    }
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Serves `/_quarkdrive/stream/` requests by proxying the Quark CDN with the drive cookie.
+#[derive(Clone)]
+pub struct StreamProxy {
+    signer: StreamSigner,
+    drive: QuarkDrive,
+    download_urls: MokaCache<String, String>,
+}
+
+impl StreamProxy {
+    pub fn new(signer: StreamSigner, drive: QuarkDrive) -> Self {
+        Self {
+            signer,
+            drive,
+            download_urls: MokaCache::builder()
+                .max_capacity(DOWNLOAD_URL_CAPACITY)
+                .time_to_live(DOWNLOAD_URL_TTL)
+                .build(),
+        }
+    }
+
+    async fn download_url(&self, fid: &str) -> anyhow::Result<String> {
+        if let Some(url) = self.download_urls.get(fid).await
+            && !is_url_expired(&url)
+        {
+            return Ok(url);
+        }
+        let url = self.drive.get_download_url(fid).await?;
+        self.download_urls.insert(fid.to_string(), url.clone()).await;
+        Ok(url)
+    }
+
+    pub async fn serve(
+        &self,
+        method: &Method,
+        token: &str,
+        range: Option<&str>,
+    ) -> Response<ResponseBody> {
+        if method != Method::GET && method != Method::HEAD {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let Some(fid) = self.signer.verify(token, now_secs()) else {
+            debug!(token = %token, "stream: invalid or expired token");
+            return status_response(StatusCode::FORBIDDEN);
+        };
+        let url = match self.download_url(&fid).await {
+            Ok(url) => url,
+            Err(err) => {
+                error!(fid = %fid, error = %err, "stream: get download url failed");
+                return status_response(StatusCode::BAD_GATEWAY);
+            }
+        };
+        let head = method == Method::HEAD;
+        let res = match self.drive.download_response(&url, range, head).await {
+            Ok(res) => res,
+            Err(err) => {
+                error!(fid = %fid, error = %err, "stream: upstream request failed");
+                self.download_urls.invalidate(&fid).await;
+                return status_response(StatusCode::BAD_GATEWAY);
+            }
+        };
+        // e.g. 403 for a URL the CDN no longer accepts; 416 is about the range, not the URL
+        if res.status().is_client_error() && res.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+            self.download_urls.invalidate(&fid).await;
+        }
+        debug!(fid = %fid, status = %res.status(), range = ?range, "stream: proxy");
+        proxy_response(res, head)
+    }
+}
+
+/// Passes an upstream CDN response on to the client.
+pub(crate) fn proxy_response(res: reqwest::Response, head: bool) -> Response<ResponseBody> {
+    let mut builder = Response::builder().status(res.status().as_u16());
+    for name in FORWARD_HEADERS {
+        if let Some(value) = res.headers().get(name) {
+            builder = builder.header(name, value.as_bytes());
+        }
+    }
+    let body = if head {
+        Empty::new().map_err(|never| match never {}).boxed_unsync()
+    } else {
+        let stream = res
+            .bytes_stream()
+            .map_ok(Frame::data)
+            .map_err(io::Error::other);
+        StreamBody::new(stream).boxed_unsync()
+    };
+    builder.body(body).unwrap()
+}
+
+pub(crate) fn status_response(status: StatusCode) -> Response<ResponseBody> {
+    Response::builder()
+        .status(status)
+        .body(
+            Full::new(Bytes::from(status.canonical_reason().unwrap_or_default()))
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> StreamSigner {
+        StreamSigner::new(b"test-secret", 3600, None)
+    }
+
+    #[test]
+    fn test_sign_and_verify_roundtrip() {
+        let signer = signer();
+        let token = signer.sign("fid_123", 1_000);
+        assert_eq!(signer.verify(&token, 1_000), Some("fid_123".to_string()));
+        assert_eq!(signer.verify(&token, 4_599), Some("fid_123".to_string()));
+    }
+
+    #[test]
+    fn test_verify_expired() {
+        let signer = signer();
+        let token = signer.sign("fid_123", 1_000);
+        assert!(signer.verify(&token, 4_600).is_none());
+    }
+
+    #[test]
+    fn test_verify_wrong_secret() {
+        let token = signer().sign("fid_123", 1_000);
+        let other = StreamSigner::new(b"other-secret", 3600, None);
+        assert!(other.verify(&token, 1_000).is_none());
+    }
+
+    #[test]
+    fn test_verify_tampered_payload() {
+        let signer = signer();
+        let token = signer.sign("fid_123", 1_000);
+        let (_, sig) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode("999999999:fid_456"), sig);
+        assert!(signer.verify(&forged, 1_000).is_none());
+    }
+
+    #[test]
+    fn test_verify_garbage() {
+        let signer = signer();
+        assert!(signer.verify("", 0).is_none());
+        assert!(signer.verify("no-dot", 0).is_none());
+        assert!(signer.verify("!!.??", 0).is_none());
+    }
+
+    #[test]
+    fn test_stream_url_with_base_path() {
+        let signer = StreamSigner::new(b"k", 60, Some("/dav/_quarkdrive/"));
+        let url = signer.stream_url("fid", "电影 1.mkv");
+        assert!(url.starts_with("/dav/_quarkdrive/stream/"));
+        assert!(url.ends_with("/%E7%94%B5%E5%BD%B1%201%2Emkv"));
+    }
+
+    #[test]
+    fn test_token_from_path() {
+        assert_eq!(StreamSigner::token_from_path("/stream/abc.def/movie.mkv"), Some("abc.def"));
+        assert_eq!(StreamSigner::token_from_path("/stream/abc.def"), Some("abc.def"));
+        assert!(StreamSigner::token_from_path("/stream/").is_none());
+        assert!(StreamSigner::token_from_path("/movies/a.mkv").is_none());
+    }
+}
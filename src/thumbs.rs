@@ -0,0 +1,246 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Response, StatusCode};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+use crate::drive::{get_format_type, QuarkDrive, QuarkFile};
+use crate::stream::status_response;
+use crate::webdav::ResponseBody;
+
+pub const THUMB_PATH: &str = "/thumb";
+/// Size the thumbnail cache is kept under unless configured otherwise.
+pub const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Whether the drive makes a thumbnail of `file`: images and videos.
+pub fn has_thumbnail(file: &QuarkFile) -> bool {
+    if file.dir || file.fid.is_empty() {
+        return false;
+    }
+    let format_type = if file.format_type.is_empty() {
+        get_format_type(&file.file_name)
+    } else {
+        file.format_type.as_str()
+    };
+    format_type.starts_with("image/") || format_type.starts_with("video/")
+}
+
+/// Size the disk cache is brought back to once it outgrew its limit, in
+/// percent of the limit, so not every fetch after that evicts again.
+const PRUNE_TO_PERCENT: u64 = 75;
+
+/// Names the temporary files of fetches apart, two requests for the same
+/// thumbnail may fetch it at the same time.
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// Thumbnails by fid, kept on disk once fetched. A changed file gets a new
+/// fid on the drive, so nothing cached ever goes stale. The oldest fetched
+/// are evicted when the cache grows past its size limit.
+#[derive(Clone)]
+pub struct ThumbCache {
+    dir: Arc<PathBuf>,
+    drive: QuarkDrive,
+    max_bytes: u64,
+    // bytes on disk, `None` until the directory was first added up
+    used: Arc<Mutex<Option<u64>>>,
+}
+
+impl ThumbCache {
+    pub fn new(dir: PathBuf, drive: QuarkDrive) -> Self {
+        Self {
+            dir: Arc::new(dir),
+            drive,
+            max_bytes: DEFAULT_MAX_BYTES,
+            used: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn set_dir(&mut self, dir: PathBuf) {
+        self.dir = Arc::new(dir);
+        self.used = Arc::new(Mutex::new(None));
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = max_bytes;
+    }
+
+    /// The thumbnail of `fid`, `None` when the drive has none.
+    pub async fn get(&self, fid: &str) -> Result<Option<Bytes>> {
+        // fids are hex, anything else must not end up in a file name
+        if fid.is_empty() || !fid.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Ok(None);
+        }
+        let path = self.dir.join(fid);
+        if let Ok(data) = fs::read(&path).await {
+            return Ok(Some(Bytes::from(data)));
+        }
+        let Some(url) = self.drive.get_thumbnail_url(fid).await? else {
+            return Ok(None);
+        };
+        let data = self.drive.download(url.as_str(), None).await?;
+        debug!(fid = %fid, size = data.len(), "thumbs: fetched");
+        fs::create_dir_all(self.dir.as_path()).await?;
+        let tmp = self.dir.join(format!("{}.{}.tmp", fid, NEXT_TMP.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&tmp, &data).await?;
+        if let Err(err) = fs::rename(&tmp, &path).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(err.into());
+        }
+        self.added(data.len() as u64).await;
+        Ok(Some(data))
+    }
+
+    /// Accounts for `len` new bytes on disk and evicts the oldest thumbnails
+    /// once the cache is over its limit.
+    async fn added(&self, len: u64) {
+        let mut used = self.used.lock().await;
+        let total = match *used {
+            Some(total) => total + len,
+            None => self.entries().await.iter().map(|(_, _, size)| size).sum(),
+        };
+        *used = Some(if total > self.max_bytes { self.prune().await } else { total });
+    }
+
+    /// Removes the oldest files until the cache is down to
+    /// [`PRUNE_TO_PERCENT`] of its limit, returns the bytes left.
+    async fn prune(&self) -> u64 {
+        let mut entries = self.entries().await;
+        entries.sort_by_key(|(_, modified, _)| *modified);
+        let mut total: u64 = entries.iter().map(|(_, _, size)| size).sum();
+        let target = self.max_bytes / 100 * PRUNE_TO_PERCENT;
+        let mut removed = 0;
+        for (path, _, size) in entries {
+            if total <= target {
+                break;
+            }
+            if fs::remove_file(&path).await.is_ok() {
+                total -= size;
+                removed += 1;
+            }
+        }
+        debug!(removed = removed, bytes = total, "thumbs: pruned cache");
+        total
+    }
+
+    /// Path, modification time and size of every file in the cache directory.
+    async fn entries(&self) -> Vec<(PathBuf, SystemTime, u64)> {
+        let mut entries = Vec::new();
+        let Ok(mut dir) = fs::read_dir(self.dir.as_path()).await else {
+            return entries;
+        };
+        while let Ok(Some(entry)) = dir.next_entry().await {
+            if let Ok(meta) = entry.metadata().await
+                && meta.is_file()
+            {
+                entries.push((entry.path(), meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta.len()));
+            }
+        }
+        entries
+    }
+
+    pub async fn serve(&self, method: &Method, fid: &str) -> Response<ResponseBody> {
+        if method != Method::GET && method != Method::HEAD {
+            return status_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let data = match self.get(fid).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return status_response(StatusCode::NOT_FOUND),
+            Err(err) => {
+                error!(fid = %fid, error = %err, "thumbs: fetch failed");
+                return status_response(StatusCode::BAD_GATEWAY);
+            }
+        };
+        let content_type = image_content_type(&data);
+        let len = data.len();
+        let body = if method == Method::HEAD { Bytes::new() } else { data };
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", content_type)
+            .header("Content-Length", len)
+            .header("Cache-Control", "private, max-age=86400")
+            .body(Full::new(body).map_err(|never| match never {}).boxed_unsync())
+            .unwrap()
+    }
+}
+
+fn image_content_type(data: &[u8]) -> &'static str {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, format_type: &str) -> QuarkFile {
+        let mut file = QuarkFile::new_file("f1", name, "/");
+        file.format_type = format_type.to_string();
+        file
+    }
+
+    #[test]
+    fn test_has_thumbnail() {
+        assert!(has_thumbnail(&file("a.jpg", "")));
+        assert!(has_thumbnail(&file("a.bin", "video/mp4")));
+        assert!(!has_thumbnail(&file("a.txt", "")));
+        assert!(!has_thumbnail(&file("a.mkv.high.m3u8", "application/vnd.apple.mpegurl")));
+        let mut dir = file("photos", "");
+        dir.dir = true;
+        assert!(!has_thumbnail(&dir));
+    }
+
+    #[test]
+    fn test_image_content_type() {
+        assert_eq!(image_content_type(b"\x89PNG\r\n"), "image/png");
+        assert_eq!(image_content_type(b"RIFF\0\0\0\0WEBPVP8"), "image/webp");
+        assert_eq!(image_content_type(b"\xff\xd8\xff"), "image/jpeg");
+    }
+
+    #[tokio::test]
+    async fn test_thumb_cache_reads_disk() {
+        let dir = std::env::temp_dir().join(format!("quarkdrive-thumbs-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("abc123"), b"GIF89a").unwrap();
+        let cache = ThumbCache::new(dir.clone(), crate::drive::create_test_drive());
+        assert_eq!(cache.get("abc123").await.unwrap().as_deref(), Some(&b"GIF89a"[..]));
+        assert!(cache.get("../etc").await.unwrap().is_none());
+        let resp = cache.serve(&Method::GET, "abc123").await;
+        assert_eq!(resp.headers()["content-type"], "image/gif");
+        let resp = cache.serve(&Method::POST, "abc123").await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_thumb_cache_prunes_oldest() {
+        let dir = std::env::temp_dir().join(format!("quarkdrive-thumbs-prune-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cache = ThumbCache::new(dir.clone(), crate::drive::create_test_drive());
+        cache.set_max_bytes(400);
+        let old = std::fs::File::create(dir.join("old")).unwrap();
+        old.set_len(200).unwrap();
+        old.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        std::fs::write(dir.join("mid"), [0u8; 100]).unwrap();
+        cache.added(0).await;
+        assert_eq!(*cache.used.lock().await, Some(300));
+
+        std::fs::write(dir.join("new"), [0u8; 150]).unwrap();
+        cache.added(150).await;
+        // down to 300 bytes by evicting the oldest
+        assert!(!dir.join("old").exists());
+        assert!(dir.join("mid").exists() && dir.join("new").exists());
+        assert_eq!(*cache.used.lock().await, Some(250));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,24 +1,28 @@
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
 use std::io::{SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use bytes::{Buf, Bytes, BytesMut};
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use dav_server::{
     davpath::DavPath,
     fs::{
-        DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsStream, OpenOptions,
-        ReadDirMeta,
+        DavDirEntry, DavFile, DavFileSystem, DavMetaData, DavProp, FsError, FsFuture, FsStream,
+        OpenOptions, ReadDirMeta,
     },
 };
 use futures_util::future::{ready, FutureExt};
-use tracing::{debug, error, trace};
+use futures_util::StreamExt;
+use moka::future::Cache as MokaCache;
+use tracing::{debug, error, trace, warn};
 use crate::{
     cache::Cache,
     drive::{QuarkDrive, QuarkFile},
+    stream::StreamSigner,
 };
 use bytes::BufMut;
 
@@ -30,19 +34,54 @@ use sha1::Digest;
 use tokio::fs::File;
 
 use crate::drive::model::{Callback, UpAuthAndCommitRequest, UpPartMethodRequest};
+use crate::hls::{self, Resolution};
+use crate::locks::{lock_key, LockTable};
+use crate::names::DuplicateNames;
+use crate::offline::OfflineTasks;
+use crate::props::PropStore;
+use crate::shares::ShareTable;
+use crate::thumbs::{self, ThumbCache};
 use tokio::io::AsyncReadExt;
 
+/// Pages of hits fetched at most per search, so a too broad keyword stays cheap.
+const SEARCH_MAX_PAGES: u32 = 5;
+const SEARCH_PAGE_SIZE: u32 = 100;
+/// Folders never listed that one search asks the drive the path of.
+const SEARCH_MAX_RESOLVED: usize = 20;
+/// Page size of the listing read for same-named files after an upload.
+const DUPLICATE_CHECK_PAGE_SIZE: u32 = 100;
+/// Paths whose last uploaded fid is remembered, and for how long.
+const UPLOADED_CAPACITY: u64 = 10_000;
+const UPLOADED_TTL: Duration = Duration::from_secs(24 * 3600);
+
+const OC_NS: &str = "http://owncloud.org/ns";
+const NC_NS: &str = "http://nextcloud.org/ns";
+/// Namespace of the `quark:` properties, see [`QuarkDriveFileSystem::quark_props`].
+const QUARK_NS: &str = "http://pan.quark.cn/ns";
+
 #[derive(Clone)]
 pub struct QuarkDriveFileSystem {
     pub(crate) drive: QuarkDrive,
     pub(crate) dir_cache: Cache,
-    uploading: Arc<DashMap<String, Vec<QuarkFile>>>,
+    // files being uploaded, keyed by full path, until they show up in listings
+    uploading: Arc<DashMap<String, QuarkFile>>,
+    // paths with a PUT in progress
+    writes: Arc<DashSet<String>>,
+    // fid our last upload to each path committed, the only kind of duplicate we remove
+    uploaded: MokaCache<String, String>,
+    pub(crate) locks: LockTable,
+    pub(crate) props: PropStore,
+    pub(crate) shares: ShareTable,
+    pub(crate) offline: OfflineTasks,
+    pub(crate) thumbs: ThumbCache,
     pub(crate) root: PathBuf,
     no_trash: bool,
     read_only: bool,
     upload_buffer_size: usize,
     skip_upload_same_size: bool,
     prefer_http_download: bool,
+    stream_signer: Option<StreamSigner>,
+    hls_resolutions: Arc<Vec<Resolution>>,
 }
 
 impl QuarkDriveFileSystem {
@@ -55,16 +94,29 @@ impl QuarkDriveFileSystem {
         } else {
             Path::new("/").join(root)
         };
+        let thumbs = ThumbCache::new(std::env::temp_dir().join("quarkdrive-webdav-thumbs"), drive.clone());
         Ok(Self {
             drive,
             dir_cache,
             uploading: Arc::new(DashMap::new()),
+            writes: Arc::new(DashSet::new()),
+            uploaded: MokaCache::builder()
+                .max_capacity(UPLOADED_CAPACITY)
+                .time_to_live(UPLOADED_TTL)
+                .build(),
+            locks: LockTable::new(None),
+            props: PropStore::new(None),
+            shares: ShareTable::default(),
+            offline: OfflineTasks::default(),
+            thumbs,
             root,
             no_trash: false,
             read_only: false,
             upload_buffer_size: 16 * 1024 * 1024,
             skip_upload_same_size: false,
             prefer_http_download: false,
+            stream_signer: None,
+            hls_resolutions: Arc::new(Vec::new()),
         })
     }
 
@@ -73,6 +125,36 @@ impl QuarkDriveFileSystem {
         self
     }
 
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Saves WebDAV locks to `path` so they survive a restart.
+    pub fn set_lock_path(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.locks = LockTable::new(path);
+        self
+    }
+
+    /// Keeps fetched thumbnails in `dir` instead of the system temp directory.
+    pub fn set_thumb_cache_dir(&mut self, dir: Option<PathBuf>) -> &mut Self {
+        if let Some(dir) = dir {
+            self.thumbs.set_dir(dir);
+        }
+        self
+    }
+
+    /// Evicts the oldest thumbnails once the cache takes more than `max_bytes`.
+    pub fn set_thumb_cache_size(&mut self, max_bytes: u64) -> &mut Self {
+        self.thumbs.set_max_bytes(max_bytes);
+        self
+    }
+
+    /// Saves dead properties set by PROPPATCH to `path` so they survive a restart.
+    pub fn set_props_path(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.props = PropStore::new(path);
+        self
+    }
+
     pub fn set_no_trash(&mut self, no_trash: bool) -> &mut Self {
         self.no_trash = no_trash;
         self
@@ -92,21 +174,76 @@ impl QuarkDriveFileSystem {
         self.prefer_http_download = prefer_http_download;
         self
     }
+
+    pub fn set_stream_signer(&mut self, stream_signer: Option<StreamSigner>) -> &mut Self {
+        self.stream_signer = stream_signer;
+        self
+    }
+
+    pub fn set_cache_refresh_interval(&mut self, secs: u64) -> &mut Self {
+        self.dir_cache.set_refresh_after(secs);
+        self
+    }
+
+    pub fn set_duplicate_names(&mut self, policy: DuplicateNames) -> &mut Self {
+        self.dir_cache.set_duplicate_names(policy);
+        self
+    }
+
+    /// Lists every video also as `<name>.<resolution>.m3u8` for these resolutions.
+    pub fn set_hls_resolutions(&mut self, resolutions: Vec<Resolution>) -> &mut Self {
+        self.hls_resolutions = Arc::new(resolutions);
+        self
+    }
+
+    /// Claims `path` for a PUT, `None` while another one to the same path is running.
+    pub fn begin_write(&self, path: &Path) -> Option<WriteGuard> {
+        let path = path.to_string_lossy().into_owned();
+        if !self.writes.insert(path.clone()) {
+            debug!(path = %path, "fs: concurrent write rejected");
+            return None;
+        }
+        Some(WriteGuard { writes: self.writes.clone(), path })
+    }
+
     fn list_uploading_files(&self, parent_file_path: &str) -> Vec<QuarkFile> {
         self.uploading
-            .get(parent_file_path)
-            .map(|val_ref| val_ref.value().clone())
-            .unwrap_or_default()
+            .iter()
+            .filter(|entry| entry.value().parent_path.as_deref() == Some(parent_file_path))
+            .map(|entry| entry.value().clone())
+            .collect()
     }
 
-    fn remove_uploading_file(&self, parent_file_path: &str, file_name: &str) {
-        if let Some(mut files) = self.uploading.get_mut(parent_file_path) {
-            if let Some(index) = files.iter().position(|x| x.file_name == file_name) {
-                files.swap_remove(index);
-            }
+    fn get_uploading_file(&self, path: &Path) -> Option<QuarkFile> {
+        self.uploading
+            .get(path.to_string_lossy().as_ref())
+            .map(|entry| entry.value().clone())
+    }
+
+    fn add_uploading_file(&self, path: &Path, file: QuarkFile) {
+        self.uploading.insert(path.to_string_lossy().into_owned(), file);
+    }
+
+    fn set_uploading_size(&self, path: &Path, size: u64) {
+        if let Some(mut file) = self.uploading.get_mut(path.to_string_lossy().as_ref()) {
+            file.size = size;
         }
     }
 
+    fn remove_uploading_file(&self, path: &Path) {
+        self.uploading.remove(path.to_string_lossy().as_ref());
+    }
+
+    /// Merges in-flight uploads of `parent` into a listing, they win over same-named entries.
+    fn merge_uploading_files(&self, parent: &str, files: &mut Vec<QuarkFile>) {
+        let uploading = self.list_uploading_files(parent);
+        if uploading.is_empty() {
+            return;
+        }
+        files.retain(|f| !uploading.iter().any(|u| u.file_name == f.file_name));
+        files.extend(uploading);
+    }
+
     async fn find_in_cache(&self, path: &Path) -> Result<Option<QuarkFile>, FsError> {
         if let Some(parent) = path.parent() {
             let parent_str = parent.to_string_lossy();
@@ -155,6 +292,278 @@ impl QuarkDriveFileSystem {
         self.drive.get_file_md5(&file.fid).await.ok()?
     }
 
+    /// The entry a rename to `to` replaces. A destination that is the source
+    /// itself, e.g. on a case-only rename, is not one.
+    async fn destination(&self, to: &Path, source: &QuarkFile) -> Result<Option<QuarkFile>, FsError> {
+        Ok(self
+            .get_file(to.to_path_buf())
+            .await?
+            .filter(|existing| existing.fid != source.fid))
+    }
+
+    /// Trashes the entry a rename replaces so the drive does not end up with
+    /// two entries of the same name.
+    async fn remove_destination(&self, to: &Path, existing: &QuarkFile) -> Result<(), FsError> {
+        debug!(path = %to.display(), file_id = %existing.fid, "fs: overwriting destination");
+        self.drive
+            .remove_file(&existing.fid, !self.no_trash)
+            .await
+            .map_err(|err| {
+                error!(path = %to.display(), error = %err, "remove destination failed");
+                FsError::GeneralFailure
+            })?;
+        if existing.dir {
+            self.dir_cache.forget(to).await;
+        }
+        if let Some(parent) = to.parent() {
+            self.dir_cache.remove_entry(parent, &existing.fid).await;
+        }
+        self.props.remove(&existing.fid).await;
+        Ok(())
+    }
+
+    /// Whether another entry of `dir` goes by `name` on the drive.
+    async fn name_taken(&self, dir: &Path, name: &str, fid: &str) -> bool {
+        self.dir_cache
+            .get_or_insert(&dir.to_string_lossy())
+            .await
+            .is_some_and(|files| {
+                files.iter().any(|f| {
+                    f.fid != fid && f.real_name.as_deref().unwrap_or(&f.file_name) == name
+                })
+            })
+    }
+
+    /// Runs the steps of [`plan_move`] and returns the steps that undo them.
+    /// When one fails the steps already done are undone in reverse, so the
+    /// file is left where and as it was.
+    async fn run_move_steps(&self, file: &QuarkFile, from_name: &str, steps: Vec<MoveStep>) -> Result<Vec<MoveStep>> {
+        let mut undo = Vec::new();
+        let mut name = from_name.to_string();
+        let mut pdir_fid = file.pdir_fid.clone();
+        for step in steps {
+            if let Err(err) = self.apply_move_step(&file.fid, &step).await {
+                self.undo_move_steps(&file.fid, undo).await;
+                return Err(err);
+            }
+            undo.insert(0, match step {
+                MoveStep::Rename(to) => MoveStep::Rename(std::mem::replace(&mut name, to)),
+                MoveStep::Move(to) => MoveStep::Move(std::mem::replace(&mut pdir_fid, to)),
+            });
+        }
+        Ok(undo)
+    }
+
+    async fn undo_move_steps(&self, fid: &str, undo: Vec<MoveStep>) {
+        for step in undo {
+            if let Err(err) = self.apply_move_step(fid, &step).await {
+                error!(file_id = %fid, step = ?step, error = %err, "undoing move failed");
+                break;
+            }
+        }
+    }
+
+    async fn apply_move_step(&self, fid: &str, step: &MoveStep) -> Result<()> {
+        match step {
+            MoveStep::Rename(name) => self.drive.rename_file(fid, name).await,
+            MoveStep::Move(pdir_fid) => self.drive.move_file(fid, pdir_fid).await,
+        }
+    }
+
+    /// Makes `path` report `mtime` (unix milliseconds) as its modification
+    /// time, returns whether there was an entry to set it on.
+    pub(crate) async fn set_mtime(&self, path: &Path, mtime: u64) -> bool {
+        if self.read_only {
+            return false;
+        }
+        match self.get_file(path.to_path_buf()).await {
+            Ok(Some(file)) if !file.fid.is_empty() => {
+                self.props.set_mtime(&file.fid, mtime).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fid of the drive entry at `dav_path`. Virtual playlists have none, so a
+    /// lock on one never follows their video around.
+    pub(crate) async fn fid_of(&self, dav_path: &DavPath) -> Option<String> {
+        let file = self.get_file(self.normalize_dav_path(dav_path)).await.ok()??;
+        (!file.fid.is_empty()).then_some(file.fid)
+    }
+
+    /// Entries below `scope` (directly in it unless `deep`) whose name the
+    /// drive's search matches with `keyword`, with their paths. The path of a
+    /// folder that was never listed is asked from the drive.
+    pub(crate) async fn search(&self, keyword: &str, scope: &Path, deep: bool) -> Result<Vec<(PathBuf, QuarkFile)>, FsError> {
+        debug!(keyword = %keyword, scope = %scope.display(), "fs: search");
+        let mut paths = self.dir_cache.index().paths_by_fid();
+        let mut unresolved = HashSet::new();
+        let mut asked = 0;
+        let mut hits = Vec::new();
+        let mut seen = 0;
+        for page in 1..=SEARCH_MAX_PAGES {
+            let files = self.drive.search(keyword, page, SEARCH_PAGE_SIZE).await.map_err(|err| {
+                error!(keyword = %keyword, error = %err, "search failed");
+                FsError::GeneralFailure
+            })?;
+            seen += files.list.len();
+            let last_page = files.list.len() < SEARCH_PAGE_SIZE as usize || seen >= files.total as usize;
+            for mut file in files.list {
+                if file.pdir_fid != "0"
+                    && !paths.contains_key(&file.pdir_fid)
+                    && !unresolved.contains(&file.pdir_fid)
+                {
+                    let path = if asked < SEARCH_MAX_RESOLVED {
+                        asked += 1;
+                        self.resolve_folder(&file.pdir_fid).await
+                    } else {
+                        None
+                    };
+                    match path {
+                        Some(path) => {
+                            paths.insert(file.pdir_fid.clone(), path);
+                        }
+                        None => {
+                            unresolved.insert(file.pdir_fid.clone());
+                        }
+                    }
+                }
+                let parent = match paths.get(&file.pdir_fid) {
+                    Some(parent) => PathBuf::from(parent),
+                    None if file.pdir_fid == "0" => PathBuf::from("/"),
+                    None => {
+                        trace!(file_id = %file.fid, pdir_fid = %file.pdir_fid, "search hit in unknown folder");
+                        continue;
+                    }
+                };
+                let in_scope = if deep { parent.starts_with(scope) } else { parent == scope };
+                if in_scope {
+                    self.props.apply_mtime(&mut file);
+                    hits.push((parent.join(&file.file_name), file));
+                }
+            }
+            if last_page {
+                break;
+            }
+        }
+        Ok(hits)
+    }
+
+    /// Path of the folder `fid` as the drive reports it, recorded in the path
+    /// index for the searches after this one.
+    async fn resolve_folder(&self, fid: &str) -> Option<String> {
+        let folders = match self.drive.folder_path(fid).await {
+            Ok(folders) => folders,
+            Err(err) => {
+                warn!(fid = %fid, error = %err, "resolve folder path failed");
+                return None;
+            }
+        };
+        if folders.last().map(|f| f.fid.as_str()) != Some(fid) {
+            return None;
+        }
+        Some(self.dir_cache.index().record_folder_path(&folders))
+    }
+
+    /// The entry at `path` as PROPFIND reports it, `None` while it is still uploading.
+    pub(crate) async fn stat(&self, path: &Path) -> Option<QuarkFile> {
+        let mut file = self.get_file(path.to_path_buf()).await.ok()??;
+        if file.fid.is_empty() {
+            return None;
+        }
+        self.props.apply_mtime(&mut file);
+        Some(file)
+    }
+
+    /// The entry at `path`, a virtual playlist included.
+    async fn entry(&self, path: &Path) -> Result<Option<QuarkFile>, FsError> {
+        if let Some(file) = self.get_file(path.to_path_buf()).await? {
+            return Ok(Some(file));
+        }
+        Ok(self
+            .playlist_source(path)
+            .await
+            .map(|(video, resolution)| hls::playlist_entry(&video, resolution)))
+    }
+
+    /// The video a virtual `<name>.<resolution>.m3u8` at `path` is a playlist of.
+    pub(crate) async fn playlist_source(&self, path: &Path) -> Option<(QuarkFile, Resolution)> {
+        let name = path.file_name()?.to_str()?;
+        let (video, resolution) = hls::parse_playlist_name(name, &self.hls_resolutions)?;
+        let video = self.stat(&path.with_file_name(video)).await.filter(hls::is_video)?;
+        Some((video, resolution))
+    }
+
+    /// Fid and ETag of the entry at `path`, sent back as `OC-FileId` and
+    /// `OC-ETag` so ownCloud clients need no PROPFIND after an upload.
+    pub(crate) async fn owncloud_ids(&self, path: &Path) -> Option<(String, String)> {
+        let file = self.stat(path).await?;
+        let etag = file.etag()?;
+        Some((file.fid, etag))
+    }
+
+    /// The ownCloud and Nextcloud properties their clients ask for, `None` for
+    /// any other property.
+    async fn owncloud_prop(&self, path: &Path, prop: &DavProp) -> Result<Option<Vec<u8>>, FsError> {
+        let (prefix, namespace) = match prop.namespace.as_deref() {
+            Some(OC_NS) => ("oc", OC_NS),
+            Some(NC_NS) => ("nc", NC_NS),
+            _ => return Ok(None),
+        };
+        let value = match (prefix, prop.name.as_str()) {
+            ("oc", "fileid" | "id" | "permissions" | "size" | "checksums") | ("nc", "has-preview") => {
+                let file = self.entry(path).await?.ok_or(FsError::NotFound)?;
+                match prop.name.as_str() {
+                    "fileid" | "id" => file.fid,
+                    "permissions" => owncloud_permissions(&file, self.read_only).to_string(),
+                    // a playlist's size is not known before it is fetched
+                    "size" if hls::is_playlist(&file) => return Ok(None),
+                    "size" => file.size.to_string(),
+                    "checksums" => {
+                        let mut checksums = Vec::new();
+                        if let Some(sha1) = &file.content_hash {
+                            checksums.push(format!("SHA1:{}", sha1));
+                        }
+                        // only a cached md5, a listing must not cost an API call per file
+                        if let Some(md5) = self.drive.get_cached_md5(&file.fid) {
+                            checksums.push(format!("MD5:{}", md5));
+                        }
+                        if checksums.is_empty() {
+                            return Ok(None);
+                        }
+                        format!("<oc:checksum>{}</oc:checksum>", checksums.join(" "))
+                    }
+                    _ => thumbs::has_thumbnail(&file).to_string(),
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(prop_xml(prefix, namespace, &prop.name, &value)))
+    }
+
+    /// What the drive knows about `file` beyond WebDAV, published in [`QUARK_NS`]
+    /// for scripts that cross-reference the Quark web UI or look for duplicates.
+    fn quark_props(&self, file: &QuarkFile) -> Vec<(&'static str, String)> {
+        let mut props = vec![
+            ("fid", file.fid.clone()),
+            ("pdir-fid", file.pdir_fid.clone()),
+            ("format-type", htmlescape::encode_minimal(&file.format_type)),
+            ("status", file.status.to_string()),
+        ];
+        if let Some(md5) = self.drive.get_cached_md5(&file.fid) {
+            props.push(("md5", md5));
+        }
+        if let Some(sha1) = &file.content_hash {
+            props.push(("sha1", sha1.clone()));
+        }
+        if let Some(url) = self.shares.url(&file.fid) {
+            props.push(("share-url", htmlescape::encode_minimal(&url)));
+        }
+        props.retain(|(_, value)| !value.is_empty());
+        props
+    }
+
     fn normalize_dav_path(&self, dav_path: &DavPath) -> PathBuf {
         let path = dav_path.as_pathbuf();
         if self.root.parent().is_none() || path.starts_with(&self.root) {
@@ -252,11 +661,11 @@ impl DavFileSystem for QuarkDriveFileSystem {
                     updated_at: now as u64,
                     download_url: None,
                     parent_path: Some(parent_path.to_string_lossy().into_owned()),
+                    real_name: None,
                 };
 
-                let mut uploading = self.uploading.entry(parent_path.to_str().unwrap().to_string()).or_default();
-                uploading.push(file.clone());
-                QuarkDavFile::new(
+                self.add_uploading_file(&path, file.clone());
+                let mut dav_file = QuarkDavFile::new(
                     self.clone(),
                     file,
                     parent_file.fid,
@@ -266,7 +675,9 @@ impl DavFileSystem for QuarkDriveFileSystem {
                     // So the size is calculated uniformly by the post program
                     0u64,
                     sha1,
-                )
+                );
+                dav_file.in_uploading = true;
+                dav_file
             } else {
                 return Err(FsError::NotFound);
             };
@@ -284,19 +695,55 @@ impl DavFileSystem for QuarkDriveFileSystem {
         let path = self.normalize_dav_path(path);
         debug!(path = %path.display(), "fs: read_dir");
         async move {
-            let files = self.dir_cache.get_or_insert(&path.to_string_lossy())
+            let key = path.to_string_lossy();
+            let pages = self.dir_cache.stream_listing(&key).await.map_err(|err| {
+                error!(path = %path.display(), error = %err, "list directory failed");
+                FsError::GeneralFailure
+            })?;
+            if let Some(pages) = pages {
+                // not cached yet, hand entries over as each page arrives
+                let uploading = self.list_uploading_files(&key);
+                let uploading_names: Vec<String> =
+                    uploading.iter().map(|f| f.file_name.clone()).collect();
+                let head = futures_util::stream::iter(uploading.into_iter().map(|file| Ok(vec![file])));
+                let props = self.props.clone();
+                let resolutions = self.hls_resolutions.clone();
+                let stream = head.chain(pages).flat_map(move |page| {
+                    let entries: Vec<Result<Box<dyn DavDirEntry>, FsError>> = match page {
+                        Ok(files) => hls::with_playlists(files, &resolutions)
+                            .into_iter()
+                            // in-flight uploads have no fid yet and replace same-named entries
+                            .filter(|file| file.fid.is_empty() || !uploading_names.contains(&file.file_name))
+                            .map(|mut file| {
+                                props.apply_mtime(&mut file);
+                                Ok(hls::dir_entry(file))
+                            })
+                            .collect(),
+                        Err(err) => {
+                            error!(error = %err, "list directory page failed");
+                            vec![Err(FsError::GeneralFailure)]
+                        }
+                    };
+                    futures_util::stream::iter(entries)
+                });
+                return Ok(Box::pin(stream) as FsStream<Box<dyn DavDirEntry>>);
+            }
+            let mut files = self.dir_cache.get_or_insert(&key)
                 .await
                 .ok_or(FsError::NotFound)
                 .and_then(|files| {
                     Ok(files)
                 })?;
+            self.merge_uploading_files(&key, &mut files);
+            let files = hls::with_playlists(files, &self.hls_resolutions);
 
             // 创建包含结果的向量
             let mut v: Vec<Result<Box<dyn DavDirEntry>, FsError>> = Vec::with_capacity(files.len());
 
             // 将每个文件转换为 trait 对象
-            for file in files {
-                v.push(Ok(Box::new(file))); // 现在类型匹配了
+            for mut file in files {
+                self.props.apply_mtime(&mut file);
+                v.push(Ok(hls::dir_entry(file)));
             }
 
             // 创建流并装箱
@@ -325,15 +772,16 @@ impl DavFileSystem for QuarkDriveFileSystem {
             // if not found in cache, get from uploading files: self.fs.uploading
             let mut file = self.get_file(path.clone()).await.unwrap_or_else(|_| Option::None);
             if file.is_none() {
-                let parent_path = path.parent().ok_or(FsError::NotFound)?;
-                file = self.list_uploading_files(parent_path.to_str().unwrap())
-                    .first().cloned();
-
+                file = self.get_uploading_file(&path);
             };
+            if file.is_none() {
+                file = self.entry(&path).await.unwrap_or(None);
+            }
 
-            let file = file.ok_or(FsError::NotFound)?;
+            let mut file = file.ok_or(FsError::NotFound)?;
+            self.props.apply_mtime(&mut file);
 
-            Ok(Box::new(file) as Box<dyn DavMetaData>)
+            Ok(hls::metadata(file))
         }
             .boxed()
     }
@@ -344,7 +792,7 @@ impl DavFileSystem for QuarkDriveFileSystem {
         Box::pin(ready(true))
     }
 
-    fn get_prop(&self, dav_path: &DavPath, prop: dav_server::fs::DavProp) -> FsFuture<Vec<u8>> {
+    fn get_prop<'a>(&'a self, dav_path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
         let path = self.normalize_dav_path(dav_path);
         let prop_name = match prop.prefix.as_ref() {
             Some(prefix) => format!("{}:{}", prefix, prop.name),
@@ -352,23 +800,59 @@ impl DavFileSystem for QuarkDriveFileSystem {
         };
         debug!(path = %path.display(), prop = %prop_name, "fs: get_prop");
         async move {
-            if prop.namespace.as_deref() == Some("http://owncloud.org/ns")
-                && prop.name == "checksums"
-            {
-                let file = self.get_file(path).await?.ok_or(FsError::NotFound)?;
-                if let Some(sha1) = file.content_hash {
-                    let xml = format!(
-                        r#"<?xml version="1.0"?>
-                        <oc:checksums xmlns:d="DAV:" xmlns:nc="http://nextcloud.org/ns" xmlns:oc="http://owncloud.org/ns">
-                            <oc:checksum>sha1:{}</oc:checksum>
-                        </oc:checksums>
-                    "#,
-                        sha1
-                    );
-                    return Ok(xml.into_bytes());
-                }
+            if let Some(xml) = self.owncloud_prop(&path, &prop).await? {
+                return Ok(xml);
             }
-            Err(FsError::NotImplemented)
+            if prop.namespace.as_deref() == Some(QUARK_NS) {
+                let file = self.entry(&path).await?.ok_or(FsError::NotFound)?;
+                return self
+                    .quark_props(&file)
+                    .into_iter()
+                    .find(|(name, _)| *name == prop.name)
+                    .map(|(name, value)| prop_xml("quark", QUARK_NS, name, &value))
+                    .ok_or(FsError::NotFound);
+            }
+            let fid = self.fid_of(dav_path).await.ok_or(FsError::NotFound)?;
+            self.props
+                .get(&fid, prop.namespace.as_deref(), &prop.name)
+                .await
+                .ok_or(FsError::NotFound)
+        }
+            .boxed()
+    }
+
+    fn get_props<'a>(&'a self, dav_path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        debug!(path = %dav_path, "fs: get_props");
+        async move {
+            let file = self
+                .get_file(self.normalize_dav_path(dav_path))
+                .await?
+                .filter(|file| !file.fid.is_empty())
+                .ok_or(FsError::NotFound)?;
+            let mut props = self.props.get_all(&file.fid, do_content).await;
+            props.extend(self.quark_props(&file).into_iter().map(|(name, value)| DavProp {
+                name: name.to_string(),
+                prefix: Some("quark".to_string()),
+                namespace: Some(QUARK_NS.to_string()),
+                xml: do_content.then(|| prop_xml("quark", QUARK_NS, name, &value)),
+            }));
+            Ok(props)
+        }
+            .boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        dav_path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(hyper::StatusCode, DavProp)>> {
+        debug!(path = %dav_path, count = patch.len(), "fs: patch_props");
+        async move {
+            if self.read_only {
+                return Err(FsError::Forbidden);
+            }
+            let fid = self.fid_of(dav_path).await.ok_or(FsError::NotFound)?;
+            Ok(self.props.patch(&fid, patch).await)
         }
             .boxed()
     }
@@ -405,19 +889,16 @@ impl DavFileSystem for QuarkDriveFileSystem {
                 return Err(FsError::Exists);
             }
             if let Some(name) = path.file_name() {
-                self.dir_cache.invalidate(parent_path).await;
                 let name = name.to_string_lossy().into_owned();
-                self.drive
+                let fid = self.drive
                     .create_folder(&parent_file.fid, &name)
                     .await
                     .map_err(|err| {
                         error!(path = %path.display(), error = %err, "create folder failed");
                         FsError::GeneralFailure
                     })?;
-                // sleep 1s for quark server to update cache
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                self.dir_cache.invalidate(&path).await;
-                self.dir_cache.invalidate_parent(&path).await;
+                let dir = QuarkFile::new_dir(fid, name, parent_file.fid, parent_path.to_string_lossy().into_owned());
+                self.dir_cache.put_entry(parent_path, dir).await;
                 Ok(())
             } else {
                 Err(FsError::Forbidden)
@@ -449,10 +930,11 @@ impl DavFileSystem for QuarkDriveFileSystem {
                     error!(path = %path.display(), error = %err, "remove directory failed");
                     FsError::GeneralFailure
                 })?;
-            // sleep 1s for quark server to update cache
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            self.dir_cache.invalidate(&path).await;
-            self.dir_cache.invalidate_parent(&path).await;
+            self.dir_cache.forget(&path).await;
+            if let Some(parent) = path.parent() {
+                self.dir_cache.remove_entry(parent, &file.fid).await;
+            }
+            self.props.remove(&file.fid).await;
             Ok(())
         }
             .boxed()
@@ -480,9 +962,10 @@ impl DavFileSystem for QuarkDriveFileSystem {
                     error!(path = %path.display(), error = %err, "remove file failed");
                     FsError::GeneralFailure
                 })?;
-            // sleep 1s for quark server to update cache
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            self.dir_cache.invalidate_parent(&path).await;
+            if let Some(parent) = path.parent() {
+                self.dir_cache.remove_entry(parent, &file.fid).await;
+            }
+            self.props.remove(&file.fid).await;
             Ok(())
         }
             .boxed()
@@ -504,76 +987,90 @@ impl DavFileSystem for QuarkDriveFileSystem {
                 return Err(FsError::Forbidden);
             }
 
-            let is_dir;
-            if from.parent() == to.parent() {
-                // rename
-                if let Some(name) = to.file_name() {
-                    let file = self
-                        .get_file(from.clone())
-                        .await?
-                        .ok_or(FsError::NotFound)?;
-                    is_dir = file.dir;
-                    let name = name.to_string_lossy().into_owned();
-                    self.drive
-                        .rename_file(&file.fid, &name)
-                        .await
-                        .map_err(|err| {
-                            error!(from = %from.display(), to = %to.display(), error = %err, "rename file failed");
-                            FsError::GeneralFailure
-                        })?;
-                    // sleep 1s for quark server to update cache
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    self.dir_cache.invalidate_parent(&from).await;
-                } else {
-                    return Err(FsError::Forbidden);
-                }
+            let from_parent = from.parent().ok_or(FsError::Forbidden)?;
+            let to_parent = to.parent().ok_or(FsError::Forbidden)?;
+            let to_name = to
+                .file_name()
+                .ok_or(FsError::Forbidden)?
+                .to_string_lossy()
+                .into_owned();
+            let mut file = self
+                .get_file(from.clone())
+                .await?
+                .ok_or(FsError::NotFound)?;
+            // dav-server already answered 412 if the client asked not to overwrite
+            let existing = self.destination(&to, &file).await?;
+            // compare with the name on the drive, the source may be a disambiguated duplicate
+            let from_name = file.real_name.take().unwrap_or_else(|| file.file_name.clone());
+            // an entry being replaced is only trashed once the file is parked
+            // next to it, so a failed move leaves it in place
+            let target = match &existing {
+                Some(_) => format!("{}.moving-{}", to_name, file.fid),
+                None => to_name.clone(),
+            };
+            let (steps, pdir_fid) = if from_parent == to_parent {
+                let rename = (from_name != target).then(|| MoveStep::Rename(target.clone()));
+                (rename.into_iter().collect(), None)
             } else {
-                // move
-                let file = self
-                    .get_file(from.clone())
-                    .await?
-                    .ok_or(FsError::NotFound)?;
-                is_dir = file.dir;
                 let to_parent_file = self
-                    .get_file(to.parent().unwrap().to_path_buf())
+                    .get_file(to_parent.to_path_buf())
                     .await?
                     .ok_or(FsError::NotFound)?;
-                let new_name = to_dav.file_name();
-                self.drive
-                    .move_file(&file.fid, &to_parent_file.fid)
-                    // then rename ...
-                    .await
-                    .map_err(|err| {
-                        error!(from = %from.display(), to = %to.display(), error = %err, "move file failed");
-                        FsError::GeneralFailure
-                    })?;
-                if let Some(to_name) = new_name {
-                    if let Some(from_name) = from_dav.file_name(){
-                        if from_name != to_name {
-                            self.drive.rename_file(&file.fid, to_name)
-                                .await
-                                .map_err(|err| {
-                                    error!(from = %from.display(), to = %to.display(), error = %err, "rename file after move failed");
-                                    FsError::GeneralFailure
-                                })?;
-                        }
-                    }
+                let steps = plan_move(
+                    &file.fid,
+                    &from_name,
+                    &target,
+                    &to_parent_file.fid,
+                    self.name_taken(to_parent, &from_name, &file.fid).await,
+                    self.name_taken(from_parent, &target, &file.fid).await,
+                );
+                (steps, Some(to_parent_file.fid))
+            };
+            let undo = match self.run_move_steps(&file, &from_name, steps).await {
+                Ok(undo) => undo,
+                Err(err) => {
+                    error!(from = %from.display(), to = %to.display(), error = %err, "rename file failed");
+                    // the rollback may not have made it all the way
+                    self.dir_cache.invalidate(from_parent).await;
+                    self.dir_cache.invalidate(to_parent).await;
+                    return Err(FsError::GeneralFailure);
+                }
+            };
+            if let Some(existing) = existing {
+                if let Err(err) = self.remove_destination(&to, &existing).await {
+                    self.undo_move_steps(&file.fid, undo).await;
+                    self.dir_cache.invalidate(from_parent).await;
+                    self.dir_cache.invalidate(to_parent).await;
+                    return Err(err);
+                }
+                if let Err(err) = self.drive.rename_file(&file.fid, &to_name).await {
+                    // the drive has no way to bring the trashed destination back
+                    error!(
+                        file_id = %file.fid,
+                        name = %target,
+                        replaced = %existing.fid,
+                        error = %err,
+                        "rename file failed after the destination was removed",
+                    );
+                    self.undo_move_steps(&file.fid, undo).await;
+                    self.dir_cache.invalidate(from_parent).await;
+                    self.dir_cache.invalidate(to_parent).await;
+                    return Err(FsError::GeneralFailure);
                 }
-                // sleep 1s for quark server to update cache
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                self.dir_cache.invalidate_parent(&from).await;
-                self.dir_cache.invalidate_parent(&to).await;
-
             }
+            if let Some(pdir_fid) = pdir_fid {
+                file.pdir_fid = pdir_fid;
+                self.dir_cache.remove_entry(from_parent, &file.fid).await;
+            }
+            file.file_name = to_name;
 
-
-            // sleep 1s for quark server to update cache
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            if is_dir {
-                self.dir_cache.invalidate(&from).await;
+            if file.dir {
+                self.dir_cache.forget(&from).await;
             }
-            self.dir_cache.invalidate_parent(&from).await;
-            self.dir_cache.invalidate_parent(&to).await;
+            self.locks
+                .follow_rename(&file.fid, &lock_key(from_dav), &lock_key(to_dav))
+                .await;
+            self.dir_cache.put_entry(to_parent, file).await;
             Ok(())
         }
             .boxed()
@@ -581,6 +1078,65 @@ impl DavFileSystem for QuarkDriveFileSystem {
 
 }
 
+/// One drive call of a rename into another folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MoveStep {
+    Rename(String),
+    /// Move into the folder with this fid.
+    Move(String),
+}
+
+/// Orders the calls of a rename into another folder so the file never takes a
+/// name another entry already has: it is renamed before the move when its old
+/// name is taken in the destination, and parked under a temporary name when
+/// the new name is taken in the source folder as well.
+fn plan_move(
+    fid: &str,
+    from_name: &str,
+    to_name: &str,
+    to_pdir_fid: &str,
+    from_name_taken_in_dest: bool,
+    to_name_taken_in_source: bool,
+) -> Vec<MoveStep> {
+    let to_dir = MoveStep::Move(to_pdir_fid.to_string());
+    let rename = MoveStep::Rename(to_name.to_string());
+    if from_name == to_name || !from_name_taken_in_dest {
+        [to_dir].into_iter().chain((from_name != to_name).then_some(rename)).collect()
+    } else if !to_name_taken_in_source {
+        vec![rename, to_dir]
+    } else {
+        let temp = format!("{}.moving-{}", to_name, fid);
+        vec![MoveStep::Rename(temp), to_dir, rename]
+    }
+}
+
+/// A property element holding `value`, which has to be escaped already.
+fn prop_xml(prefix: &str, namespace: &str, name: &str, value: &str) -> Vec<u8> {
+    format!(r#"<{prefix}:{name} xmlns:{prefix}="{namespace}">{value}</{prefix}:{name}>"#).into_bytes()
+}
+
+/// `oc:permissions` of an entry: readable, deletable, renamable, movable and
+/// writable (files) or open to new files and folders (directories).
+fn owncloud_permissions(file: &QuarkFile, read_only: bool) -> &'static str {
+    match (read_only || hls::is_playlist(file), file.dir) {
+        (true, _) => "G",
+        (false, true) => "GDNVCK",
+        (false, false) => "GDNVW",
+    }
+}
+
+/// Releases a path claimed by [`QuarkDriveFileSystem::begin_write`] when dropped.
+pub struct WriteGuard {
+    writes: Arc<DashSet<String>>,
+    path: String,
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        self.writes.remove(&self.path);
+    }
+}
+
 #[derive(Debug, Clone)]
 struct UploadState {
     size: u64,
@@ -601,7 +1157,10 @@ struct UploadState {
     callback: Option<Callback>,
     is_uploading: bool,
     flush_count: u32,
-
+    // up_pre created a new fid for this upload
+    created: bool,
+    // fid of the file this upload replaces, its dead properties move to the new one
+    replaced_fid: Option<String>,
 }
 
 impl Default for UploadState {
@@ -625,6 +1184,8 @@ impl Default for UploadState {
             callback: None,
             is_uploading: false,
             flush_count: 0,
+            created: false,
+            replaced_fid: None,
         }
     }
 }
@@ -637,6 +1198,8 @@ struct QuarkDavFile {
     current_pos: u64,
     upload_state: UploadState,
     http_download: bool,
+    // this handle added the file to the uploading overlay
+    in_uploading: bool,
     md5_ctx: Md5Context,
     sha1_ctx: Sha1,
 }
@@ -674,6 +1237,7 @@ impl QuarkDavFile {
                 ..Default::default()
             },
             http_download: false,
+            in_uploading: false,
             md5_ctx: Md5Context::new(),
             sha1_ctx: Sha1::default(),
         }
@@ -735,6 +1299,7 @@ impl QuarkDavFile {
                 error!(file_name = %self.file.file_name, error = %err,
                        "delete file before upload failed");
             }
+            self.upload_state.replaced_fid = Some(self.file.fid.clone());
         }
 
         // up_pre
@@ -748,6 +1313,8 @@ impl QuarkDavFile {
                 FsError::GeneralFailure
             })?;
 
+        self.file.fid = res.data.fid.clone();
+        self.upload_state.created = true;
         if res.data.finish {
             // 秒传
             self.upload_state.is_finished = true;
@@ -769,8 +1336,6 @@ impl QuarkDavFile {
             self.upload_state.mime_type = res.data.format_type;
         }
 
-        self.file.fid = res.data.fid.clone();
-
         self.upload_state.chunk_size = res.metadata.part_size;
         let chunk_count =
             size / res.metadata.part_size + if size % res.metadata.part_size != 0 { 1 } else { 0 };
@@ -825,6 +1390,7 @@ impl QuarkDavFile {
                 error!(file_name = %self.file.file_name, error = %err,
                        "delete file before upload failed");
             }
+            self.upload_state.replaced_fid = Some(self.file.fid.clone());
         }
 
         // pre -> hash -> commit -> finish
@@ -839,6 +1405,8 @@ impl QuarkDavFile {
                 FsError::GeneralFailure
             })?;
 
+        self.file.fid = res.data.fid.clone();
+        self.upload_state.created = true;
         if res.data.finish {
             // 秒传
             self.upload_state.is_finished = true;
@@ -860,8 +1428,6 @@ impl QuarkDavFile {
             self.upload_state.mime_type = res.data.format_type;
         }
 
-        self.file.fid = res.data.fid.clone();
-
         self.upload_state.chunk_size = 0;
         let chunk_count = 1 ;
         self.upload_state.chunk_count = chunk_count;
@@ -952,6 +1518,9 @@ impl QuarkDavFile {
         // 保存回结构体
         self.md5_ctx = md5_ctx;
         self.sha1_ctx = sha1_ctx;
+        if self.in_uploading {
+            self.fs.set_uploading_size(&self.upload_path(), self.upload_state.size);
+        }
         Ok(())
     }
 
@@ -1070,15 +1639,113 @@ impl QuarkDavFile {
 
     async fn after_flush(&mut self) -> Result<(), FsError> {
         self.delete_temp_file().await?;
-        let parent_path = self.file.parent_path.as_ref().unwrap().as_str();
-        self.fs.remove_uploading_file(parent_path, &self.file.file_name);
+        self.remove_from_uploading();
+        if self.file.fid.is_empty() {
+            self.fs.dir_cache.invalidate(self.parent_dir.as_path()).await;
+        } else {
+            let mut file = self.file.clone();
+            file.size = self.upload_state.size;
+            // the hash of what was written, the ETag must not stay the old content's
+            file.content_hash = Some(format!("{:x}", self.sha1_ctx.clone().finalize()));
+            if self.upload_state.created {
+                // uploaded under the name the client used
+                file.real_name = None;
+                file.updated_at = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64;
+                file.created_at = file.updated_at;
+                self.remove_stray_duplicates().await;
+                if let Some(replaced) = self.upload_state.replaced_fid.take() {
+                    self.fs.props.transfer(&replaced, &file.fid).await;
+                }
+            }
+            self.fs.dir_cache.put_entry(&self.parent_dir, file).await;
+        }
         self.upload_state = UploadState::default();
-        // sleep 1s for quark server to update cache
-        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        Ok(())
+    }
+
+    /// Deletes other files with our name that this process replaced: the file
+    /// the upload overwrote if deleting it beforehand failed, and what an
+    /// earlier upload of ours to the same path left. Same-named files from
+    /// anyone else, e.g. a racing PUT from another server, are only reported.
+    async fn remove_stray_duplicates(&self) {
+        let path = self.upload_path().to_string_lossy().into_owned();
+        let previous = self.fs.uploaded.get(&path).await;
+        self.fs.uploaded.insert(path, self.file.fid.clone()).await;
+        let mut files = Vec::new();
+        for page in 1.. {
+            let list = match self
+                .fs
+                .drive
+                .get_files_by_pdir_fid_sorted(&self.parent_file_id, page, DUPLICATE_CHECK_PAGE_SIZE, "updated_at:desc")
+                .await
+            {
+                Ok((Some(list), total)) => (list.list, total),
+                Ok((None, _)) => return,
+                Err(err) => {
+                    error!(file_name = %self.file.file_name, error = %err, "list parent for duplicate check failed");
+                    return;
+                }
+            };
+            let (list, total) = list;
+            let count = list.len();
+            files.extend(list);
+            if count < DUPLICATE_CHECK_PAGE_SIZE as usize || files.len() >= total as usize {
+                break;
+            }
+        }
+        let ours: Vec<&str> = self
+            .upload_state
+            .replaced_fid
+            .iter()
+            .chain(previous.iter())
+            .map(String::as_str)
+            .collect();
+        let duplicates = stray_duplicates(&files, &self.file, &ours);
+        for dup in duplicates.remove {
+            debug!(file_name = %dup.file_name, file_id = %dup.fid, "removing duplicate left by our own upload");
+            if let Err(err) = self.fs.drive.remove_file(&dup.fid, !self.fs.no_trash).await {
+                error!(file_id = %dup.fid, error = %err, "remove duplicate failed");
+                continue;
+            }
+            self.fs.dir_cache.remove_entry(&self.parent_dir, &dup.fid).await;
+        }
+        if duplicates.survivor != self.file.fid {
+            warn!(file_name = %self.file.file_name, survivor = %duplicates.survivor, "a newer file of the same name exists");
+        }
+        for dup in duplicates.kept {
+            warn!(
+                file_name = %dup.file_name,
+                file_id = %dup.fid,
+                survivor = %duplicates.survivor,
+                "duplicate name from another writer left in place"
+            );
+        }
+    }
+
+    /// Cleans up after a failed upload. The old file may already be deleted
+    /// and a new fid half created, so the parent is re-listed from the server.
+    async fn abort_upload(&mut self) -> Result<(), FsError> {
+        self.delete_temp_file().await?;
+        self.remove_from_uploading();
         self.fs.dir_cache.invalidate(self.parent_dir.as_path()).await;
+        self.upload_state = UploadState::default();
         Ok(())
     }
 
+    fn upload_path(&self) -> PathBuf {
+        self.parent_dir.join(&self.file.file_name)
+    }
+
+    fn remove_from_uploading(&mut self) {
+        if self.in_uploading {
+            self.fs.remove_uploading_file(&self.upload_path());
+            self.in_uploading = false;
+        }
+    }
+
     async fn get_download_url(&self) -> Result<String, FsError> {
         self.fs.drive.get_download_url(&self.file.fid).await.map_err(|err| {
             error!(file_id = %self.file.fid, file_name = %self.file.file_name, error = %err, "get download url failed");
@@ -1088,11 +1755,26 @@ impl QuarkDavFile {
 
 }
 
+impl Drop for QuarkDavFile {
+    fn drop(&mut self) {
+        // closed without a successful flush, e.g. the client aborted the PUT
+        self.remove_from_uploading();
+        if self.upload_state.is_uploading
+            && !self.upload_state.is_finished
+            && let Err(err) = std::fs::remove_file(&self.upload_state.temp_file_path)
+            && err.kind() != std::io::ErrorKind::NotFound
+        {
+            error!(file_name = %self.file.file_name, error = %err, "remove temp file failed");
+        }
+    }
+}
+
 impl DavFile for QuarkDavFile {
     fn metadata(&'_ mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
         debug!(file_id = %self.file.fid, file_name = %self.file.file_name, "file: metadata");
         async move {
-            let file = self.file.clone();
+            let mut file = self.file.clone();
+            self.fs.props.apply_mtime(&mut file);
             Ok(Box::new(file) as Box<dyn DavMetaData>)
         }
             .boxed()
@@ -1104,6 +1786,9 @@ impl DavFile for QuarkDavFile {
             if self.file.fid.is_empty() {
                 return Err(FsError::NotFound);
             }
+            if let Some(signer) = &self.fs.stream_signer {
+                return Ok(Some(signer.stream_url(&self.file.fid, &self.file.file_name)));
+            }
             let download_url = self.fs.drive.get_download_url(&self.file.fid).await.unwrap();
 
             return Ok(Some(download_url));
@@ -1223,7 +1908,7 @@ impl DavFile for QuarkDavFile {
             let res = self.do_flush().await;
             if let Err(err) = res {
                 error!(file_id = %self.file.fid, file_name = %self.file.file_name, error = %err, "file: flush failed");
-                self.after_flush().await?;
+                self.abort_upload().await?;
                 return Err(err);
             }
             Ok(())
@@ -1234,7 +1919,35 @@ impl DavFile for QuarkDavFile {
 
 
 
-fn is_url_expired(url: &str) -> bool {
+/// Files named like an upload once it committed.
+struct StrayDuplicates<'a> {
+    /// ones this process replaced, to be removed
+    remove: Vec<&'a QuarkFile>,
+    /// fid of the file meant to keep the name: the newest, then the highest fid
+    survivor: String,
+    /// everyone else's besides the survivor, left alone
+    kept: Vec<&'a QuarkFile>,
+}
+
+/// Sorts the files named like `uploaded` into those with one of the fids in
+/// `ours`, which this process replaced, and the rest.
+fn stray_duplicates<'a>(files: &'a [QuarkFile], uploaded: &QuarkFile, ours: &[&str]) -> StrayDuplicates<'a> {
+    let (remove, mut rest): (Vec<&QuarkFile>, Vec<&QuarkFile>) = files
+        .iter()
+        .filter(|f| f.file && f.file_name == uploaded.file_name && f.fid != uploaded.fid)
+        .partition(|f| ours.contains(&f.fid.as_str()));
+    let survivor = rest
+        .iter()
+        .copied()
+        .chain(std::iter::once(uploaded))
+        .max_by(|a, b| a.updated_at.cmp(&b.updated_at).then_with(|| a.fid.cmp(&b.fid)))
+        .map(|f| f.fid.clone())
+        .unwrap_or_default();
+    rest.retain(|f| f.fid != survivor);
+    StrayDuplicates { remove, survivor, kept: rest }
+}
+
+pub(crate) fn is_url_expired(url: &str) -> bool {
     if let Ok(oss_url) = ::url::Url::parse(url) {
         let expires = oss_url.query_pairs().find_map(|(k, v)| {
             if k == "Expires" {
@@ -1341,4 +2054,320 @@ mod tests {
         // Non-numeric Expires should not cause a panic, returns false
         assert!(!is_url_expired(url));
     }
-}
\ No newline at end of file
+
+    // --- uploading overlay tests ---
+
+    fn create_test_fs() -> QuarkDriveFileSystem {
+        create_test_fs_at("https://drive.quark.cn")
+    }
+
+    fn create_test_fs_at(api_base_url: &str) -> QuarkDriveFileSystem {
+        let drive = crate::drive::create_test_drive_at(api_base_url);
+        QuarkDriveFileSystem::new(drive, "/".to_string(), 100, 60).unwrap()
+    }
+
+    /// A drive on a local port on which deletes succeed and every other call
+    /// fails, with the paths it was called on.
+    async fn failing_drive() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        mock_drive(|path, _| {
+            path.starts_with("/1/clouddrive/file/delete").then(|| {
+                r#"{"status":200,"code":0,"message":"ok","timestamp":0,"data":{"task_id":"t","finish":true},"metadata":{"tq_gap":0}}"#.to_string()
+            })
+        })
+        .await
+    }
+
+    /// A drive on a local port answering each call with the JSON `answer`
+    /// gives for its path and body, or with 400 for none.
+    async fn mock_drive(answer: fn(&str, &str) -> Option<String>) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+                let mut len = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).await.unwrap();
+                let response = match answer(&path, &String::from_utf8_lossy(&body)) {
+                    Some(json) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        json.len(),
+                        json
+                    ),
+                    None => "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                seen.lock().unwrap().push(path);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, calls)
+    }
+
+    #[tokio::test]
+    async fn test_playlist_ids() {
+        let mut fs = create_test_fs();
+        fs.set_hls_resolutions(vec![Resolution::High]);
+        let mut video = QuarkFile::new_file("v1", "a.mkv", "/");
+        video.size = 100;
+        fs.dir_cache.import_stale_listings(vec![("/".to_string(), vec![video])]).await;
+        let path = Path::new("/a.mkv.high.m3u8");
+        let prop = |name: &str| DavProp {
+            name: name.to_string(),
+            prefix: Some("oc".to_string()),
+            namespace: Some(OC_NS.to_string()),
+            xml: None,
+        };
+
+        let fileid = fs.owncloud_prop(path, &prop("fileid")).await.unwrap().unwrap();
+        assert!(String::from_utf8(fileid).unwrap().contains(">v1:high<"));
+        assert!(fs.owncloud_prop(path, &prop("size")).await.unwrap().is_none());
+        let permissions = fs.owncloud_prop(path, &prop("permissions")).await.unwrap().unwrap();
+        assert!(String::from_utf8(permissions).unwrap().contains(">G<"));
+        // locks on a playlist stay with its path
+        assert!(fs.fid_of(&DavPath::new("/a.mkv.high.m3u8").unwrap()).await.is_none());
+        assert_eq!(fs.fid_of(&DavPath::new("/a.mkv").unwrap()).await.as_deref(), Some("v1"));
+    }
+
+    #[tokio::test]
+    async fn test_search_resolves_unknown_folders() {
+        let (url, calls) = mock_drive(|path, _| {
+            if path.starts_with("/1/clouddrive/file/search") {
+                Some(r#"{"status":200,"code":0,"message":"","timestamp":0,"data":{"list":[
+                    {"fid":"f1","file_name":"x.txt","pdir_fid":"d2","format_type":"","status":1,"created_at":0,"updated_at":0,"dir":false,"file":true},
+                    {"fid":"f2","file_name":"y.txt","pdir_fid":"gone","format_type":"","status":1,"created_at":0,"updated_at":0,"dir":false,"file":true},
+                    {"fid":"f3","file_name":"z.txt","pdir_fid":"gone","format_type":"","status":1,"created_at":0,"updated_at":0,"dir":false,"file":true}
+                ]},"metadata":{"_total":3,"_count":3,"_page":1}}"#.to_string())
+            } else if path.contains("pdir_fid=d2&") {
+                Some(r#"{"status":200,"code":0,"message":"","timestamp":0,"data":{"list":[],"full_path":[
+                    {"fid":"d1","file_name":"a"},{"fid":"d2","file_name":"b"}
+                ]},"metadata":{}}"#.to_string())
+            } else {
+                None
+            }
+        })
+        .await;
+        let fs = create_test_fs_at(&url);
+        let hits = fs.search("x", Path::new("/a"), true).await.unwrap();
+        let paths: Vec<_> = hits.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("/a/b/x.txt")]);
+        assert_eq!(fs.dir_cache.index().fid("/a/b"), Some("d2".to_string()));
+        // a folder the drive cannot place is asked for once
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.iter().filter(|path| path.contains("pdir_fid=gone&")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_move_keeps_destination() {
+        let (url, calls) = failing_drive().await;
+        let fs = create_test_fs_at(&url);
+        let source = QuarkFile::new_file("src", "a.txt", "/");
+        let mut dir = QuarkFile::new_dir("d1".to_string(), "dir".to_string(), "0".to_string(), "/".to_string());
+        dir.parent_path = Some("/".to_string());
+        let mut existing = QuarkFile::new_file("dst", "b.txt", "/dir");
+        existing.pdir_fid = "d1".to_string();
+        fs.dir_cache
+            .import_stale_listings(vec![
+                ("/".to_string(), vec![source, dir]),
+                ("/dir".to_string(), vec![existing]),
+            ])
+            .await;
+
+        let from = DavPath::new("/a.txt").unwrap();
+        let to = DavPath::new("/dir/b.txt").unwrap();
+        assert!(fs.rename(&from, &to).await.is_err());
+        let calls = calls.lock().unwrap();
+        assert!(calls.iter().any(|path| path.starts_with("/1/clouddrive/file/move")));
+        assert!(!calls.iter().any(|path| path.starts_with("/1/clouddrive/file/delete")), "{:?}", calls);
+    }
+
+    #[tokio::test]
+    async fn test_failed_overwrite_restores_source() {
+        // parking and deleting work, giving the file its final name does not
+        let (url, calls) = mock_drive(|path, body| {
+            let ok = r#"{"status":200,"code":0,"message":"ok","timestamp":0,"data":{"task_id":"t","finish":true},"metadata":{"tq_gap":0}}"#;
+            let done = path.starts_with("/1/clouddrive/file/delete")
+                || (path.starts_with("/1/clouddrive/file/rename") && !body.contains(r#""file_name":"b.txt""#));
+            done.then(|| ok.to_string())
+        })
+        .await;
+        let fs = create_test_fs_at(&url);
+        let source = QuarkFile::new_file("src", "a.txt", "/");
+        let existing = QuarkFile::new_file("dst", "b.txt", "/");
+        fs.dir_cache
+            .import_stale_listings(vec![("/".to_string(), vec![source, existing])])
+            .await;
+
+        let from = DavPath::new("/a.txt").unwrap();
+        let to = DavPath::new("/b.txt").unwrap();
+        assert!(fs.rename(&from, &to).await.is_err());
+        let calls = calls.lock().unwrap();
+        let renames: Vec<_> = calls.iter().filter(|path| path.starts_with("/1/clouddrive/file/rename")).collect();
+        // parked, final name refused, original name back
+        assert_eq!(renames.len(), 3, "{:?}", calls);
+        assert!(calls.iter().any(|path| path.starts_with("/1/clouddrive/file/delete")));
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_changes_etag() {
+        let (url, _) = failing_drive().await;
+        let fs = create_test_fs_at(&url);
+        let mut old = QuarkFile::new_file("f1", "a.txt", "/");
+        old.content_hash = Some("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d".to_string());
+        fs.dir_cache.import_stale_listings(vec![("/".to_string(), vec![old.clone()])]).await;
+        let etag = |file: &QuarkFile| DavMetaData::etag(file).unwrap();
+        let old_etag = etag(&old);
+
+        let mut new = old.clone();
+        new.fid = "f2".to_string();
+        let mut dav_file = QuarkDavFile::new(fs.clone(), new, "0".to_string(), PathBuf::from("/"), 5, None);
+        dav_file.sha1_ctx.update(b"world");
+        dav_file.upload_state.created = true;
+        dav_file.upload_state.replaced_fid = Some("f1".to_string());
+        dav_file.after_flush().await.unwrap();
+
+        let file = fs.get_file(PathBuf::from("/a.txt")).await.unwrap().unwrap();
+        assert_eq!(file.fid, "f2");
+        assert_ne!(etag(&file), old_etag);
+        assert_eq!(etag(&file), "7c211433f02071597741e6ff5a8ea34789abbf43");
+    }
+
+    #[test]
+    fn test_uploading_lookup_by_full_path() {
+        let fs = create_test_fs();
+        fs.add_uploading_file(Path::new("/dir/a.bin"), QuarkFile::new_file("", "a.bin", "/dir"));
+        fs.add_uploading_file(Path::new("/dir/b.bin"), QuarkFile::new_file("", "b.bin", "/dir"));
+        fs.set_uploading_size(Path::new("/dir/b.bin"), 42);
+        assert_eq!(fs.get_uploading_file(Path::new("/dir/b.bin")).unwrap().size, 42);
+        assert_eq!(fs.get_uploading_file(Path::new("/dir/a.bin")).unwrap().size, 0);
+        assert!(fs.get_uploading_file(Path::new("/dir/c.bin")).is_none());
+    }
+
+    #[test]
+    fn test_merge_uploading_files() {
+        let fs = create_test_fs();
+        fs.add_uploading_file(Path::new("/dir/new.bin"), QuarkFile::new_file("", "new.bin", "/dir"));
+        fs.add_uploading_file(Path::new("/dir/a.txt"), QuarkFile::new_file("", "a.txt", "/dir"));
+        fs.add_uploading_file(Path::new("/other/x.bin"), QuarkFile::new_file("", "x.bin", "/other"));
+        let existing = QuarkFile::new_file("f1", "a.txt", "/dir");
+        let kept = QuarkFile::new_file("f2", "b.txt", "/dir");
+        let mut files = vec![existing, kept];
+        fs.merge_uploading_files("/dir", &mut files);
+        let mut names: Vec<(&str, &str)> = files.iter().map(|f| (f.file_name.as_str(), f.fid.as_str())).collect();
+        names.sort();
+        assert_eq!(names, vec![("a.txt", ""), ("b.txt", "f2"), ("new.bin", "")]);
+    }
+
+    #[test]
+    fn test_dropped_upload_leaves_overlay() {
+        let fs = create_test_fs();
+        let path = Path::new("/dir/a.bin");
+        fs.add_uploading_file(path, QuarkFile::new_file("", "a.bin", "/dir"));
+        let mut file = QuarkDavFile::new(fs.clone(), QuarkFile::new_file("", "a.bin", "/dir"), "0".to_string(), PathBuf::from("/dir"), 0, None);
+        file.in_uploading = true;
+        drop(file);
+        assert!(fs.get_uploading_file(path).is_none());
+
+        // a plain read handle for the same path must not touch someone else's upload
+        fs.add_uploading_file(path, QuarkFile::new_file("", "a.bin", "/dir"));
+        drop(QuarkDavFile::new(fs.clone(), QuarkFile::new_file("", "a.bin", "/dir"), "0".to_string(), PathBuf::from("/dir"), 0, None));
+        assert!(fs.get_uploading_file(path).is_some());
+    }
+
+    // --- concurrent write tests ---
+
+    #[test]
+    fn test_begin_write_rejects_second_writer() {
+        let fs = create_test_fs();
+        let path = Path::new("/dir/a.bin");
+        let guard = fs.begin_write(path).unwrap();
+        assert!(fs.begin_write(path).is_none());
+        assert!(fs.begin_write(Path::new("/dir/b.bin")).is_some());
+        drop(guard);
+        assert!(fs.begin_write(path).is_some());
+    }
+
+    #[test]
+    fn test_stray_duplicates() {
+        let file = |fid: &str, name: &str, updated_at: u64| {
+            let mut f = QuarkFile::new_file("", name, "/dir");
+            f.fid = fid.to_string();
+            f.updated_at = updated_at;
+            f
+        };
+        let ours = file("new", "a.bin", 2_000);
+        let mut dir = file("d1", "a.bin", 2_000);
+        dir.dir = true;
+        dir.file = false;
+        let files = vec![
+            ours.clone(),
+            file("race", "a.bin", 1_500),
+            file("replaced", "a.bin", 500),
+            file("older", "a.bin", 500),
+            file("other", "b.bin", 1_500),
+            dir,
+        ];
+        let fids = |files: &[&QuarkFile]| files.iter().map(|f| f.fid.clone()).collect::<Vec<_>>();
+        // only what we replaced goes, a racing upload from elsewhere stays
+        let dups = stray_duplicates(&files, &ours, &["replaced"]);
+        assert_eq!(fids(&dups.remove), vec!["replaced"]);
+        assert_eq!(dups.survivor, "new");
+        assert_eq!(fids(&dups.kept), vec!["race", "older"]);
+
+        // the other writer's file is newer, ours is not the survivor then
+        let files = vec![ours.clone(), file("race", "a.bin", 3_000)];
+        let dups = stray_duplicates(&files, &ours, &[]);
+        assert!(dups.remove.is_empty());
+        assert_eq!(dups.survivor, "race");
+        assert!(dups.kept.is_empty());
+
+        // same time, the higher fid wins
+        let files = vec![ours.clone(), file("zzz", "a.bin", 2_000)];
+        assert_eq!(stray_duplicates(&files, &ours, &[]).survivor, "zzz");
+    }
+
+    // --- move collision tests ---
+
+    #[test]
+    fn test_plan_move_collision_matrix() {
+        use MoveStep::{Move, Rename};
+        let plan = |same_name: bool, taken_in_dest: bool, taken_in_source: bool| {
+            let to_name = if same_name { "a.txt" } else { "b.txt" };
+            plan_move("f1", "a.txt", to_name, "d2", taken_in_dest, taken_in_source)
+        };
+        let to_dir = || Move("d2".to_string());
+        let rename = |name: &str| Rename(name.to_string());
+
+        // keeping the name, a same-named destination was trashed before
+        for (dest, source) in [(false, false), (false, true), (true, false), (true, true)] {
+            assert_eq!(plan(true, dest, source), vec![to_dir()]);
+        }
+        // the old name is free in the destination folder
+        assert_eq!(plan(false, false, false), vec![to_dir(), rename("b.txt")]);
+        assert_eq!(plan(false, false, true), vec![to_dir(), rename("b.txt")]);
+        // the old name is taken there, the new one is free here
+        assert_eq!(plan(false, true, false), vec![rename("b.txt"), to_dir()]);
+        // both are taken, park the file under a temporary name
+        assert_eq!(
+            plan(false, true, true),
+            vec![rename("b.txt.moving-f1"), to_dir(), rename("b.txt")]
+        );
+    }
+}
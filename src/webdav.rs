@@ -1,11 +1,21 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::io;
 use std::net::ToSocketAddrs;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::time::SystemTime;
 use anyhow::Result;
-use dav_server::{body::Body, DavConfig, DavHandler};
-use headers::{authorization::Basic, Authorization, HeaderMapExt};
+use bytes::Bytes;
+use dav_server::{
+    body::Body,
+    davpath::DavPath,
+    fs::{DavFileSystem, DavMetaData, FsError, OpenOptions},
+    DavConfig, DavHandler,
+};
+use futures_util::Stream;
+use headers::{authorization::Basic, Authorization, ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full, Limited};
 use hyper::service::Service;
 use hyper::{Method, Request, Response};
 use hyper_util::{
@@ -15,7 +25,36 @@ use hyper_util::{
 use tokio::net::TcpListener;
 use tracing::{debug, error, info};
 
-use crate::vfs::QuarkDriveFileSystem;
+use crate::csrf::CsrfTokens;
+use crate::hls::{HlsProxy, HLS_PATH};
+use crate::locks::{lock_key, LOCKS_PATH};
+use crate::multipart::{self, MultipartReader};
+use crate::offline::{NewOfflineTask, OFFLINE_PATH};
+use crate::props::parse_http_date;
+use crate::search::{self, SearchQuery};
+use crate::shares::{ImportShare, NewShare, ShareLink, IMPORT_PATH, SHARES_PATH};
+use crate::stream::{status_response, StreamProxy, StreamSigner};
+use crate::thumbs::{self, THUMB_PATH};
+use crate::vfs::{QuarkDriveFileSystem, WriteGuard};
+
+const OWNCLOUD_STATUS_PATH: &str = "/status.php";
+/// Server version reported to ownCloud and Nextcloud clients, recent enough
+/// for them to use chunk-free uploads and `X-OC-Mtime`.
+const OWNCLOUD_VERSION: &str = "28.0.0.0";
+const OWNCLOUD_VERSION_STRING: &str = "28.0.0";
+/// Where Nextcloud and ownCloud clients fetch previews, by `fileId` or `file` path.
+const OWNCLOUD_PREVIEW_PATHS: [&str; 2] = ["/index.php/core/preview", "/index.php/core/preview.png"];
+/// Largest urlencoded form of the browser UI that is read.
+const MAX_FORM_LEN: usize = 64 * 1024;
+/// Largest SEARCH request body that is read.
+const MAX_SEARCH_LEN: usize = 64 * 1024;
+/// Largest JSON body of the share, import and offline endpoints that is read.
+const MAX_JSON_LEN: usize = 64 * 1024;
+/// Largest PROPPATCH request body that is read.
+const MAX_PROPPATCH_LEN: usize = 64 * 1024;
+
+/// Body type of every response; lets proxied content stream alongside dav-server bodies.
+pub type ResponseBody = UnsyncBoxBody<Bytes, io::Error>;
 
 pub struct WebDavServer {
     pub host: String,
@@ -26,6 +65,9 @@ pub struct WebDavServer {
     pub handler: DavHandler,
     pub fs: QuarkDriveFileSystem,
     pub strip_prefix: Option<String>,
+    pub reserved_prefix: String,
+    pub stream_proxy: Option<StreamProxy>,
+    pub hls_proxy: Option<HlsProxy>,
 }
 
 impl WebDavServer {
@@ -41,6 +83,10 @@ impl WebDavServer {
             handler: self.handler.clone(),
             fs: self.fs.clone(),
             strip_prefix: self.strip_prefix.clone(),
+            reserved_prefix: self.reserved_prefix.clone(),
+            stream_proxy: self.stream_proxy.clone(),
+            hls_proxy: self.hls_proxy.clone(),
+            csrf: CsrfTokens::default(),
         };
 
         let listener = TcpListener::bind(&addr).await?;
@@ -78,6 +124,11 @@ pub struct QuarkDriveWebDav {
     handler: DavHandler,
     fs: QuarkDriveFileSystem,
     strip_prefix: Option<String>,
+    // the server's own endpoints live below it, e.g. `/_quarkdrive/shares`
+    reserved_prefix: String,
+    stream_proxy: Option<StreamProxy>,
+    hls_proxy: Option<HlsProxy>,
+    csrf: CsrfTokens,
 }
 
 impl QuarkDriveWebDav {
@@ -93,15 +144,61 @@ impl QuarkDriveWebDav {
         false
     }
 
-    fn compute_fs_path(&self, req_path: &str) -> PathBuf {
-        let path = if let Some(ref prefix) = self.strip_prefix {
+    fn strip_server_prefix<'a>(&self, req_path: &'a str) -> &'a str {
+        if let Some(ref prefix) = self.strip_prefix {
             let prefix = prefix.trim_end_matches('/');
             req_path
                 .strip_prefix(prefix)
                 .unwrap_or(req_path)
         } else {
             req_path
-        };
+        }
+    }
+
+    fn strip_request_prefix<'a>(&self, req_path: &'a str) -> &'a str {
+        let path = self.strip_server_prefix(req_path);
+        match owncloud_endpoint(path) {
+            Some(endpoint) => &path[endpoint.len()..],
+            None => path,
+        }
+    }
+
+    /// Prefix dav-server has to strip for a request to an ownCloud endpoint, so
+    /// the hrefs it answers with stay under that endpoint.
+    fn owncloud_dav_prefix(&self, req_path: &str) -> Option<String> {
+        let path = self.strip_server_prefix(req_path);
+        let endpoint = owncloud_endpoint(path)?;
+        let len = req_path.len() - path.len() + endpoint.len();
+        Some(percent_decode(&req_path[..len]))
+    }
+
+    /// `GET /status.php`, which ownCloud and Nextcloud clients probe without
+    /// credentials before they log in.
+    fn handle_status_request(&self, method: &Method, req_path: &str) -> Option<Response<ResponseBody>> {
+        if method != Method::GET || self.strip_server_prefix(req_path) != OWNCLOUD_STATUS_PATH {
+            return None;
+        }
+        let status = serde_json::json!({
+            "installed": true,
+            "maintenance": false,
+            "needsDbUpgrade": false,
+            "version": OWNCLOUD_VERSION,
+            "versionstring": OWNCLOUD_VERSION_STRING,
+            "edition": "",
+            "productname": "quarkdrive-webdav",
+            "extendedSupport": false,
+        });
+        Some(
+            Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .body(Body::from(status.to_string()).boxed_unsync())
+                .unwrap(),
+        )
+    }
+
+    fn compute_fs_path(&self, req_path: &str) -> PathBuf {
+        let path = self.strip_request_prefix(req_path);
 
         let path = path.trim_start_matches('/');
         let path = path.trim_end_matches('/');
@@ -120,27 +217,802 @@ impl QuarkDriveWebDav {
         }
     }
 
+    /// Serialises PUTs per path: a second PUT while one is still running gets 423 Locked
+    /// instead of racing it and leaving two same-named files behind.
+    fn claim_write(
+        &self,
+        method: &Method,
+        req_path: &str,
+    ) -> Result<Option<WriteGuard>, hyper::StatusCode> {
+        if method != Method::PUT {
+            return Ok(None);
+        }
+        match self.fs.begin_write(&self.compute_fs_path(req_path)) {
+            Some(guard) => Ok(Some(guard)),
+            None => Err(hyper::StatusCode::LOCKED),
+        }
+    }
+
+    /// Filesystem path named by the `Destination` header of a MOVE.
+    fn move_destination(&self, method: &Method, headers: &hyper::HeaderMap) -> Option<PathBuf> {
+        if method.as_str() != "MOVE" {
+            return None;
+        }
+        let dest = headers.get("destination")?.to_str().ok()?;
+        let uri = dest.parse::<hyper::Uri>().ok()?;
+        Some(self.compute_fs_path(uri.path()))
+    }
+
+    /// Re-lists the destination folder of a MOVE, so the existence check behind
+    /// `Overwrite` sees what is on the drive now and not a cached listing.
+    async fn refresh_move_destination(&self, dest: Option<&Path>) {
+        if let Some(parent) = dest.and_then(Path::parent) {
+            debug!(path = %parent.display(), "webdav: refreshing move destination");
+            self.fs.dir_cache.invalidate(parent).await;
+        }
+    }
+
+    /// `GET /_quarkdrive/locks` lists the WebDAV locks,
+    /// `DELETE /_quarkdrive/locks/<token>` breaks one, e.g. a lock left behind
+    /// by a client that crashed.
+    async fn handle_locks_request(&self, method: &Method, req_path: &str) -> Option<Response<ResponseBody>> {
+        let rest = self.endpoint_subpath(req_path, LOCKS_PATH)?;
+        let status = match (method, rest) {
+            (&Method::GET, "" | "/") => {
+                let locks = self.fs.locks.list().await;
+                let json = serde_json::to_string(&locks).unwrap_or_default();
+                return Some(
+                    Response::builder()
+                        .status(200)
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(json).boxed_unsync())
+                        .unwrap(),
+                );
+            }
+            (&Method::DELETE, _) if self.fs.is_read_only() => hyper::StatusCode::FORBIDDEN,
+            (&Method::DELETE, token) if token.len() > 1 && token.starts_with('/') => {
+                if self.fs.locks.break_lock(&percent_decode(&token[1..])).await {
+                    hyper::StatusCode::NO_CONTENT
+                } else {
+                    hyper::StatusCode::NOT_FOUND
+                }
+            }
+            _ => hyper::StatusCode::METHOD_NOT_ALLOWED,
+        };
+        Some(status_response(status))
+    }
+
+    /// `/_quarkdrive/thumb/<fid>` and the ownCloud preview endpoints, answered
+    /// with the thumbnail the drive made of an image or video.
+    async fn handle_thumb_request(
+        &self,
+        method: &Method,
+        req_path: &str,
+        query: Option<&str>,
+    ) -> Option<Response<ResponseBody>> {
+        let fid = if let Some(rest) = self.endpoint_subpath(req_path, THUMB_PATH) {
+            percent_decode(rest.trim_start_matches('/'))
+        } else if OWNCLOUD_PREVIEW_PATHS.contains(&self.strip_server_prefix(req_path)) {
+            match (query_param(query, "fileId"), query_param(query, "file")) {
+                (Some(fid), _) => fid,
+                (None, Some(file)) => {
+                    let file = self.fs.stat(&self.compute_fs_path(&file)).await;
+                    file.map(|f| f.fid).unwrap_or_default()
+                }
+                (None, None) => String::new(),
+            }
+        } else {
+            return None;
+        };
+        Some(self.fs.thumbs.serve(method, &fid).await)
+    }
+
+    /// Answers GET and HEAD of a virtual `<name>.<resolution>.m3u8` with the
+    /// playlist of that transcode, its URIs pointing at `/_quarkdrive/hls/`.
+    async fn handle_playlist_request(&self, method: &Method, req_path: &str) -> Option<Response<ResponseBody>> {
+        let proxy = self.hls_proxy.as_ref()?;
+        if method != Method::GET && method != Method::HEAD {
+            return None;
+        }
+        let path = self.compute_fs_path(req_path);
+        if self.fs.stat(&path).await.is_some() {
+            return None;
+        }
+        let (video, resolution) = self.fs.playlist_source(&path).await?;
+        debug!(path = %path.display(), fid = %video.fid, resolution = %resolution.as_str(), "hls: playlist");
+        Some(proxy.playlist(method, &video.fid, resolution).await)
+    }
+
+    /// Rest of the path of a request below the reserved prefix, `/shares/abc`
+    /// for `/_quarkdrive/shares/abc`. Nothing of the drive is served there.
+    fn reserved_subpath<'a>(&self, req_path: &'a str) -> Option<&'a str> {
+        let rest = self.strip_request_prefix(req_path).strip_prefix(&self.reserved_prefix)?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+
+    /// Rest of the path of a request to `endpoint`, such as [`SHARES_PATH`].
+    fn endpoint_subpath<'a>(&self, req_path: &'a str, endpoint: &str) -> Option<&'a str> {
+        let rest = self.reserved_subpath(req_path)?.strip_prefix(endpoint)?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+
+    /// `GET /_quarkdrive/shares` lists the drive's share links,
+    /// `POST /_quarkdrive/shares` with a JSON [`NewShare`] shares a file or
+    /// folder and `DELETE /_quarkdrive/shares/<id>` revokes a link. Only JSON is accepted for POST, which a cross-site
+    /// form cannot send.
+    async fn handle_shares_request(
+        &self,
+        method: &Method,
+        rest: &str,
+        json_body: Option<&[u8]>,
+    ) -> Response<ResponseBody> {
+        let status = match (method, rest) {
+            (&Method::GET, "" | "/") => match self.fs.shares.refresh(&self.fs.drive).await {
+                Ok(shares) => return json_response(hyper::StatusCode::OK, &shares),
+                Err(err) => {
+                    error!(error = %err, "shares: list failed");
+                    hyper::StatusCode::BAD_GATEWAY
+                }
+            },
+            (&Method::POST | &Method::DELETE, _) if self.fs.is_read_only() => hyper::StatusCode::FORBIDDEN,
+            (&Method::POST, "" | "/") => {
+                let Some(new_share) = json_body.and_then(|body| serde_json::from_slice::<NewShare>(body).ok()) else {
+                    return status_response(hyper::StatusCode::BAD_REQUEST);
+                };
+                let path = self.compute_fs_path(&new_share.path);
+                let Some(file) = self.fs.stat(&path).await else {
+                    return status_response(hyper::StatusCode::NOT_FOUND);
+                };
+                let passcode = new_share.passcode.as_deref().filter(|p| !p.is_empty());
+                match self.fs.drive.create_share(&file.fid, &file.file_name, passcode, new_share.expire_days).await {
+                    Ok(share) => {
+                        info!(path = %path.display(), share_id = %share.share_id, "shares: created");
+                        self.fs.shares.insert(share.clone());
+                        return json_response(hyper::StatusCode::CREATED, &share);
+                    }
+                    Err(err) => {
+                        error!(path = %path.display(), error = %err, "shares: create failed");
+                        hyper::StatusCode::BAD_GATEWAY
+                    }
+                }
+            }
+            (&Method::DELETE, id) if id.len() > 1 => {
+                let share_id = percent_decode(&id[1..]);
+                match self.fs.drive.delete_share(&share_id).await {
+                    Ok(()) => {
+                        info!(share_id = %share_id, "shares: revoked");
+                        self.fs.shares.remove(&share_id);
+                        hyper::StatusCode::NO_CONTENT
+                    }
+                    Err(err) => {
+                        error!(share_id = %share_id, error = %err, "shares: revoke failed");
+                        hyper::StatusCode::BAD_GATEWAY
+                    }
+                }
+            }
+            _ => hyper::StatusCode::METHOD_NOT_ALLOWED,
+        };
+        status_response(status)
+    }
+
+    /// `GET /_quarkdrive/import?url=<link>&passcode=<code>` lists what someone
+    /// else's share link holds, `POST /_quarkdrive/import` with a JSON
+    /// [`ImportShare`] saves it into a folder of the drive.
+    async fn handle_import_request(
+        &self,
+        method: &Method,
+        rest: &str,
+        query: Option<&str>,
+        json_body: Option<&[u8]>,
+    ) -> Response<ResponseBody> {
+        if !matches!(rest, "" | "/") {
+            return status_response(hyper::StatusCode::NOT_FOUND);
+        }
+        let status = match *method {
+            Method::GET => {
+                let Some(link) = query_param(query, "url")
+                    .and_then(|url| ShareLink::parse(&url, query_param(query, "passcode").as_deref()).ok())
+                else {
+                    return status_response(hyper::StatusCode::BAD_REQUEST);
+                };
+                match link.open(&self.fs.drive).await {
+                    Ok((_, files)) => return json_response(hyper::StatusCode::OK, &files),
+                    Err(err) => {
+                        error!(pwd_id = %link.pwd_id, error = %err, "import: open share failed");
+                        hyper::StatusCode::BAD_GATEWAY
+                    }
+                }
+            }
+            Method::POST if self.fs.is_read_only() => hyper::StatusCode::FORBIDDEN,
+            Method::POST => {
+                let Some(import) = json_body.and_then(|body| serde_json::from_slice::<ImportShare>(body).ok()) else {
+                    return status_response(hyper::StatusCode::BAD_REQUEST);
+                };
+                let Ok(link) = ShareLink::parse(&import.url, import.passcode.as_deref()) else {
+                    return status_response(hyper::StatusCode::BAD_REQUEST);
+                };
+                let path = self.compute_fs_path(&import.path);
+                let Some(dir) = self.fs.stat(&path).await.filter(|f| f.dir) else {
+                    return status_response(hyper::StatusCode::NOT_FOUND);
+                };
+                match link.save(&self.fs.drive, &import.items, &dir.fid).await {
+                    Ok(saved) => {
+                        info!(pwd_id = %link.pwd_id, path = %path.display(), count = saved.len(), "import: saved");
+                        self.fs.dir_cache.invalidate(&path).await;
+                        return json_response(hyper::StatusCode::CREATED, &saved);
+                    }
+                    Err(err) => {
+                        error!(pwd_id = %link.pwd_id, path = %path.display(), error = %err, "import: save failed");
+                        hyper::StatusCode::BAD_GATEWAY
+                    }
+                }
+            }
+            _ => hyper::StatusCode::METHOD_NOT_ALLOWED,
+        };
+        status_response(status)
+    }
+
+    /// `GET /_quarkdrive/offline` lists the drive's offline downloads,
+    /// `POST /_quarkdrive/offline` with a JSON [`NewOfflineTask`] starts one and
+    /// `DELETE /_quarkdrive/offline/<id>` cancels it. The target folder is re-listed once a download finishes.
+    async fn handle_offline_request(
+        &self,
+        method: &Method,
+        rest: &str,
+        json_body: Option<&[u8]>,
+    ) -> Response<ResponseBody> {
+        let status = match (method, rest) {
+            (&Method::GET, "" | "/") => match self.fs.offline.refresh(&self.fs.drive, &self.fs.dir_cache).await {
+                Ok(tasks) => return json_response(hyper::StatusCode::OK, &tasks),
+                Err(err) => {
+                    error!(error = %err, "offline: list failed");
+                    hyper::StatusCode::BAD_GATEWAY
+                }
+            },
+            (&Method::POST | &Method::DELETE, _) if self.fs.is_read_only() => hyper::StatusCode::FORBIDDEN,
+            (&Method::POST, "" | "/") => {
+                let Some(new_task) = json_body.and_then(|body| serde_json::from_slice::<NewOfflineTask>(body).ok()) else {
+                    return status_response(hyper::StatusCode::BAD_REQUEST);
+                };
+                let path = self.compute_fs_path(&new_task.path);
+                let Some(dir) = self.fs.stat(&path).await.filter(|f| f.dir) else {
+                    return status_response(hyper::StatusCode::NOT_FOUND);
+                };
+                match self.fs.drive.add_offline_task(&new_task.url, &dir.fid).await {
+                    Ok(task_id) => {
+                        info!(path = %path.display(), task_id = %task_id, "offline: task added");
+                        self.fs.offline.track(&task_id, &dir.fid);
+                        return json_response(hyper::StatusCode::CREATED, &serde_json::json!({ "task_id": task_id }));
+                    }
+                    Err(err) => {
+                        error!(path = %path.display(), error = %err, "offline: add failed");
+                        hyper::StatusCode::BAD_GATEWAY
+                    }
+                }
+            }
+            (&Method::DELETE, id) if id.len() > 1 => {
+                let task_id = percent_decode(&id[1..]);
+                match self.fs.drive.cancel_offline_task(&task_id).await {
+                    Ok(()) => {
+                        info!(task_id = %task_id, "offline: task cancelled");
+                        self.fs.offline.forget(&task_id);
+                        hyper::StatusCode::NO_CONTENT
+                    }
+                    Err(err) => {
+                        error!(task_id = %task_id, error = %err, "offline: cancel failed");
+                        hyper::StatusCode::BAD_GATEWAY
+                    }
+                }
+            }
+            _ => hyper::StatusCode::METHOD_NOT_ALLOWED,
+        };
+        status_response(status)
+    }
+
+    /// POST of a form of the browser UI to the directory it lists: a multipart
+    /// upload of files, or `action=mkdir|rename|delete` on entries named in it.
+    /// Every form carries the page's CSRF token; success redirects back to the
+    /// listing.
+    async fn handle_form_request<S, E>(
+        &self,
+        req_path: &str,
+        content_type: &str,
+        mut body: S,
+        principal: &str,
+    ) -> Response<ResponseBody>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        if self.fs.is_read_only() {
+            return status_response(hyper::StatusCode::FORBIDDEN);
+        }
+        let dir_path = self.compute_fs_path(req_path);
+        if !self.fs.stat(&dir_path).await.is_some_and(|f| f.dir) {
+            return status_response(hyper::StatusCode::NOT_FOUND);
+        }
+        let result = match multipart::boundary(content_type) {
+            Some(boundary) => {
+                self.upload_form_files(req_path, MultipartReader::new(body, &boundary), principal)
+                    .await
+            }
+            None => {
+                let mut form = Vec::new();
+                while let Some(chunk) = futures_util::StreamExt::next(&mut body).await {
+                    let Ok(chunk) = chunk else {
+                        return status_response(hyper::StatusCode::BAD_REQUEST);
+                    };
+                    form.extend_from_slice(&chunk);
+                    if form.len() > MAX_FORM_LEN {
+                        return status_response(hyper::StatusCode::PAYLOAD_TOO_LARGE);
+                    }
+                }
+                self.apply_form_action(req_path, &form, principal).await
+            }
+        };
+        if let Err(status) = result {
+            return status_response(status);
+        }
+        let location = if req_path.ends_with('/') { req_path.to_string() } else { format!("{}/", req_path) };
+        Response::builder()
+            .status(hyper::StatusCode::SEE_OTHER)
+            .header(hyper::header::LOCATION, location)
+            .body(Body::from("").boxed_unsync())
+            .unwrap()
+    }
+
+    /// Uploads the file parts of a form into the directory at `req_path`, each
+    /// streamed into the drive as it arrives, like the body of a PUT. The
+    /// CSRF token has to come before the first file.
+    async fn upload_form_files<S, E>(
+        &self,
+        req_path: &str,
+        mut reader: MultipartReader<S>,
+        principal: &str,
+    ) -> Result<(), hyper::StatusCode>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let mut verified = false;
+        while let Some(part) = reader.next_part().await.map_err(|_| hyper::StatusCode::BAD_REQUEST)? {
+            let Some(file_name) = part.file_name else {
+                if part.name == "csrf" {
+                    let token = reader.read_text(1024).await.map_err(|_| hyper::StatusCode::BAD_REQUEST)?;
+                    verified = self.csrf.verify(&token, principal);
+                }
+                continue;
+            };
+            if !verified {
+                return Err(hyper::StatusCode::FORBIDDEN);
+            }
+            // a file input left empty
+            if file_name.is_empty() {
+                continue;
+            }
+            // some browsers send the full path of the picked file
+            let name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+            let dav_path = self.form_entry_path(req_path, name).ok_or(hyper::StatusCode::BAD_REQUEST)?;
+            self.check_unlocked(&dav_path, principal, false).await?;
+            let Some(_guard) = self.fs.begin_write(&self.compute_fs_path(req_path).join(name)) else {
+                return Err(hyper::StatusCode::LOCKED);
+            };
+            let options = OpenOptions {
+                write: true,
+                create: true,
+                truncate: true,
+                ..OpenOptions::default()
+            };
+            let mut file = self.fs.open(&dav_path, options).await.map_err(fs_error_status)?;
+            while let Some(chunk) = reader.read_chunk().await.map_err(|_| hyper::StatusCode::BAD_REQUEST)? {
+                file.write_bytes(chunk).await.map_err(fs_error_status)?;
+            }
+            file.flush().await.map_err(fs_error_status)?;
+            info!(req_path = %req_path, name = %name, "browser: uploaded");
+        }
+        Ok(())
+    }
+
+    /// `mkdir` of `name`, `rename` of `name` to `to` and `delete` of `name`,
+    /// all within the directory at `req_path`.
+    async fn apply_form_action(&self, req_path: &str, form: &[u8], principal: &str) -> Result<(), hyper::StatusCode> {
+        let form: HashMap<String, String> = url::form_urlencoded::parse(form).into_owned().collect();
+        let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+        if !self.csrf.verify(field("csrf"), principal) {
+            return Err(hyper::StatusCode::FORBIDDEN);
+        }
+        let name = field("name").trim();
+        let path = self.form_entry_path(req_path, name).ok_or(hyper::StatusCode::BAD_REQUEST)?;
+        match field("action") {
+            "mkdir" => {
+                self.check_unlocked(&path, principal, false).await?;
+                self.fs.create_dir(&path).await
+            }
+            "rename" => {
+                let to = self.form_entry_path(req_path, field("to").trim()).ok_or(hyper::StatusCode::BAD_REQUEST)?;
+                // a MOVE would replace what is there, a rename in the page must not
+                if self.fs.metadata(&to).await.is_ok() {
+                    return Err(hyper::StatusCode::CONFLICT);
+                }
+                self.check_unlocked(&path, principal, true).await?;
+                self.check_unlocked(&to, principal, false).await?;
+                self.fs.rename(&path, &to).await
+            }
+            "delete" => {
+                self.check_unlocked(&path, principal, true).await?;
+                match self.fs.metadata(&path).await {
+                    Ok(meta) if meta.is_dir() => self.fs.remove_dir(&path).await,
+                    Ok(_) => self.fs.remove_file(&path).await,
+                    Err(err) => Err(err),
+                }
+            }
+            _ => return Err(hyper::StatusCode::BAD_REQUEST),
+        }
+        .map_err(fs_error_status)?;
+        info!(req_path = %req_path, action = %field("action"), name = %name, "browser: form action");
+        Ok(())
+    }
+
+    /// 423 while a WebDAV lock covers `path`, or with `deep` anything below
+    /// it, as dav-server answers a client not sending the lock's token. The
+    /// page never holds one.
+    async fn check_unlocked(&self, path: &DavPath, principal: &str, deep: bool) -> Result<(), hyper::StatusCode> {
+        self.fs
+            .locks
+            .check(&lock_key(path), Some(principal), false, deep, &[])
+            .await
+            .map_err(|lock| {
+                debug!(path = %lock_key(path), token = %lock.token, "browser: locked");
+                hyper::StatusCode::LOCKED
+            })
+    }
+
+    /// The entry `name` of the directory at `req_path`, `None` unless `name`
+    /// is a plain file name.
+    fn form_entry_path(&self, req_path: &str, name: &str) -> Option<DavPath> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        let dir = self.strip_request_prefix(req_path).trim_end_matches('/');
+        DavPath::new(&format!("{}/{}", dir, percent_encode_path(name))).ok()
+    }
+
+    /// dav-server refuses to set `DAV:getlastmodified`, so a PROPPATCH setting
+    /// it goes to dav-server without it and `mtime` is applied here once
+    /// dav-server accepted the rest, after its `If:` and lock checks. The
+    /// answer to a request setting nothing else is written here.
+    async fn apply_lastmodified(
+        &self,
+        req_path: &str,
+        mtime: u64,
+        alone: bool,
+        resp: Response<ResponseBody>,
+    ) -> Response<ResponseBody> {
+        if resp.status() != hyper::StatusCode::MULTI_STATUS {
+            return resp;
+        }
+        let (parts, body) = resp.into_parts();
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(err) => {
+                error!(req_path = %req_path, error = %err, "proppatch: reading response failed");
+                return status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let applied = !has_failed_propstat(&body)
+            && self.fs.set_mtime(&self.compute_fs_path(req_path), mtime).await;
+        if !alone {
+            return Response::from_parts(parts, Full::new(body).map_err(|never| match never {}).boxed_unsync());
+        }
+        let status = if applied { "200 OK" } else { "403 Forbidden" };
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:"><D:response><D:href>{}</D:href><D:propstat><D:prop><D:getlastmodified/></D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat></D:response></D:multistatus>"#,
+            htmlescape::encode_minimal(req_path),
+            status
+        );
+        Response::builder()
+            .status(207)
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(Body::from(xml).boxed_unsync())
+            .unwrap()
+    }
+
     async fn handle_browser_request(
         &self,
         req_path: &str,
+        query: Option<&str>,
+        headers: &hyper::HeaderMap,
+        principal: Option<&str>,
     ) -> Option<Response<Body>> {
         let fs_path = self.compute_fs_path(req_path);
         debug!(req_path = %req_path, fs_path = %fs_path.display(), "browser: checking path");
 
-        let files = self.fs.dir_cache.get_or_insert(&fs_path.to_string_lossy()).await?;
-        debug!(req_path = %req_path, count = files.len(), "browser: directory listing");
+        let mut files = self.fs.dir_cache.get_or_insert(&fs_path.to_string_lossy()).await?;
+        let base = self.href_for(req_path, &self.fs.root, true).trim_end_matches('/').to_string();
+        let writable = !self.fs.is_read_only();
+        let actions = PageActions {
+            shares_url: writable.then(|| format!("{}{}{}", base, self.reserved_prefix, SHARES_PATH)),
+            thumb_url: Some(format!("{}{}{}", base, self.reserved_prefix, THUMB_PATH)),
+            csrf_token: writable.then(|| self.csrf.issue(principal.unwrap_or_default())),
+        };
+        let html = match query_param(query, "q").filter(|q| !q.trim().is_empty()) {
+            Some(keyword) => {
+                let hits = self.fs.search(keyword.trim(), &fs_path, true).await.unwrap_or_default();
+                debug!(req_path = %req_path, keyword = %keyword, count = hits.len(), "browser: search");
+                let hits: Vec<_> = hits
+                    .into_iter()
+                    .map(|(path, file)| {
+                        let href = self.href_for(req_path, &path, file.dir);
+                        let name = path.strip_prefix(&fs_path).unwrap_or(&path).to_string_lossy().into_owned();
+                        (href, name, file)
+                    })
+                    .collect();
+                render_search_html(req_path, &keyword, &hits, &actions)
+            }
+            None => {
+                debug!(req_path = %req_path, count = files.len(), "browser: directory listing");
+                for file in files.iter_mut() {
+                    self.fs.props.apply_mtime(file);
+                }
+                render_directory_html(req_path, &files, &actions)
+            }
+        };
+        let etag: ETag = format!("\"{:x}\"", md5::compute(&html)).parse().ok()?;
+        if is_not_modified(headers, &etag, None) {
+            return Some(not_modified_response(&etag, None));
+        }
+        let mut resp = Response::builder()
+            .status(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(html))
+            .unwrap();
+        resp.headers_mut().typed_insert(etag);
+        Some(resp)
+    }
+
+    /// `SEARCH` (RFC 5323) with `DAV:basicsearch`, answered from the drive's
+    /// name search rather than a walk of the tree with PROPFIND.
+    async fn handle_search_request(&self, req_path: &str, body: &[u8]) -> Response<ResponseBody> {
+        let query = match SearchQuery::parse(body) {
+            Ok(query) => query,
+            Err(err) => {
+                debug!(error = %err, "search: unsupported request");
+                return Response::builder()
+                    .status(hyper::StatusCode::BAD_REQUEST)
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(Body::from(err.to_string()).boxed_unsync())
+                    .unwrap();
+            }
+        };
+        let scope = match &query.scope {
+            Some(href) => self.compute_fs_path(&resolve_href(req_path, href)),
+            None => self.compute_fs_path(req_path),
+        };
+        let Ok(hits) = self.fs.search(&query.keyword, &scope, query.deep).await else {
+            return status_response(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let hits: Vec<_> = hits
+            .into_iter()
+            .filter(|(_, file)| query.matches(file))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|(path, file)| (self.href_for(req_path, &path, file.dir), file))
+            .collect();
+        debug!(keyword = %query.keyword, scope = %scope.display(), count = hits.len(), "search: done");
+        Response::builder()
+            .status(hyper::StatusCode::MULTI_STATUS)
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(Body::from(search::multistatus(&hits)).boxed_unsync())
+            .unwrap()
+    }
 
-        let html = render_directory_html(req_path, &files);
-        Some(
-            Response::builder()
-                .status(200)
-                .header("Content-Type", "text/html; charset=utf-8")
-                .body(Body::from(html))
-                .unwrap(),
-        )
+    /// URL of the filesystem `path` under the same prefix `req_path` came in with.
+    fn href_for(&self, req_path: &str, path: &Path, dir: bool) -> String {
+        let base = &req_path[..req_path.len() - self.strip_request_prefix(req_path).len()];
+        let mut href = base.trim_end_matches('/').to_string();
+        let rel = path.strip_prefix(&self.fs.root).unwrap_or(path);
+        for part in rel.iter().filter(|part| *part != "/") {
+            href.push('/');
+            href.push_str(&percent_encode_path(&part.to_string_lossy()));
+        }
+        if href.is_empty() || dir {
+            href.push('/');
+        }
+        href
+    }
+
+    /// Answers a conditional GET of a file the client already has with 304
+    /// before dav-server opens it, which costs a download URL and, with
+    /// `--redirect`, would send a 302 before looking at the conditions.
+    async fn handle_file_not_modified(
+        &self,
+        req_path: &str,
+        headers: &hyper::HeaderMap,
+    ) -> Option<Response<Body>> {
+        if !headers.contains_key(hyper::header::IF_NONE_MATCH)
+            && !headers.contains_key(hyper::header::IF_MODIFIED_SINCE)
+        {
+            return None;
+        }
+        let file = self.fs.stat(&self.compute_fs_path(req_path)).await?;
+        if file.dir {
+            return None;
+        }
+        let etag: ETag = format!("\"{}\"", file.etag()?).parse().ok()?;
+        let modified = file.modified().ok();
+        is_not_modified(headers, &etag, modified).then(|| not_modified_response(&etag, modified))
+    }
+
+    /// Re-lists the folder of the target of a conditional PUT, DELETE or MOVE,
+    /// so dav-server checks `If-Match` and the like against the drive and not
+    /// against a listing cached before another client changed the file.
+    async fn refresh_conditional_target(&self, method: &Method, headers: &hyper::HeaderMap, req_path: &str) {
+        if !matches!(method.as_str(), "PUT" | "DELETE" | "MOVE") {
+            return;
+        }
+        let conditional = [
+            hyper::header::IF_MATCH,
+            hyper::header::IF_NONE_MATCH,
+            hyper::header::IF_UNMODIFIED_SINCE,
+        ]
+        .iter()
+        .any(|name| headers.contains_key(name));
+        if !conditional {
+            return;
+        }
+        if let Some(parent) = self.compute_fs_path(req_path).parent() {
+            debug!(path = %parent.display(), "webdav: refreshing conditional request target");
+            self.fs.dir_cache.invalidate(parent).await;
+        }
+    }
+}
+
+fn json_response(status: hyper::StatusCode, value: &impl serde::Serialize) -> Response<ResponseBody> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(value).unwrap_or_default()).boxed_unsync())
+        .unwrap()
+}
+
+/// Status a browser action answers with when the filesystem refused it.
+fn fs_error_status(err: FsError) -> hyper::StatusCode {
+    match err {
+        FsError::NotFound => hyper::StatusCode::NOT_FOUND,
+        FsError::Exists => hyper::StatusCode::CONFLICT,
+        FsError::Forbidden => hyper::StatusCode::FORBIDDEN,
+        _ => hyper::StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// The whole of `body`, or `None` when it is longer than `limit`.
+async fn collect_limited(body: hyper::body::Incoming, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    match Limited::new(body, limit).collect().await {
+        Ok(body) => Ok(Some(body.to_bytes())),
+        Err(err) => match err.downcast::<hyper::Error>() {
+            Ok(err) => Err(*err),
+            // anything else is the limit
+            Err(_) => Ok(None),
+        },
     }
 }
 
+/// Whether a POST with `content_type` is one of the forms of the browser UI.
+fn is_form_content_type(content_type: &str) -> bool {
+    multipart::boundary(content_type).is_some() || content_type.starts_with("application/x-www-form-urlencoded")
+}
+
+/// Value of `name` in a URL query string.
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(&value.replace('+', " ")))
+}
+
+/// A `DAV:href` as a request path; relative ones are relative to `req_path`.
+fn resolve_href(req_path: &str, href: &str) -> String {
+    if let Ok(uri) = href.parse::<hyper::Uri>()
+        && uri.scheme().is_some()
+    {
+        return uri.path().to_string();
+    }
+    if href.starts_with('/') {
+        href.to_string()
+    } else {
+        format!("{}/{}", req_path.trim_end_matches('/'), href)
+    }
+}
+
+/// RFC 7232 for a GET: a matching `If-None-Match`, or without one an
+/// `If-Modified-Since` no older than `modified`, means the client is up to date.
+fn is_not_modified(headers: &hyper::HeaderMap, etag: &ETag, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers.typed_get::<IfNoneMatch>() {
+        return !if_none_match.precondition_passes(etag);
+    }
+    match (headers.typed_get::<IfModifiedSince>(), modified) {
+        (Some(since), Some(modified)) => !since.is_modified(modified),
+        _ => false,
+    }
+}
+
+fn not_modified_response(etag: &ETag, modified: Option<SystemTime>) -> Response<Body> {
+    let mut resp = Response::builder()
+        .status(hyper::StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap();
+    resp.headers_mut().typed_insert(etag.clone());
+    if let Some(modified) = modified {
+        resp.headers_mut().typed_insert(LastModified::from(modified));
+    }
+    resp
+}
+
+/// The ownCloud WebDAV endpoint `path` starts with, `/remote.php/webdav` or
+/// `/remote.php/dav/files/<user>`. Both serve the same tree as `/`.
+fn owncloud_endpoint(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("/remote.php/")?;
+    let len = match rest.strip_prefix("webdav") {
+        Some(tail) if tail.is_empty() || tail.starts_with('/') => path.len() - tail.len(),
+        _ => {
+            let user = rest.strip_prefix("dav/files/")?;
+            let user_len = user.find('/').unwrap_or(user.len());
+            if user_len == 0 {
+                return None;
+            }
+            path.len() - user.len() + user_len
+        }
+    };
+    Some(&path[..len])
+}
+
+/// `X-OC-Mtime` is in unix seconds, possibly with a fraction.
+fn parse_oc_mtime(value: &str) -> Option<u64> {
+    let secs: f64 = value.trim().parse().ok()?;
+    (secs.is_finite() && secs >= 0.0).then_some((secs * 1000.0) as u64)
+}
+
+/// Takes a `DAV:getlastmodified` being set out of a PROPPATCH body. Returns its
+/// time in unix milliseconds, the remaining body and whether no property is
+/// left in it.
+fn take_lastmodified(body: &[u8]) -> Option<(u64, Vec<u8>, bool)> {
+    let mut tree = xmltree::Element::parse(body).ok()?;
+    let mut mtime = None;
+    let mut remaining = 0;
+    for op in tree.children.iter_mut().filter_map(xmltree::XMLNode::as_mut_element) {
+        let is_set = op.name == "set";
+        for prop in op
+            .children
+            .iter_mut()
+            .filter_map(xmltree::XMLNode::as_mut_element)
+            .filter(|e| e.name == "prop")
+        {
+            prop.children.retain(|node| match node.as_element() {
+                Some(e) if is_set && e.name == "getlastmodified" && e.namespace.as_deref() == Some("DAV:") => {
+                    mtime = e.get_text().and_then(|text| parse_http_date(&text));
+                    false
+                }
+                _ => true,
+            });
+            remaining += prop.children.iter().filter(|n| n.as_element().is_some()).count();
+        }
+    }
+    let mtime = mtime?;
+    let mut rest = Vec::new();
+    tree.write(&mut rest).ok()?;
+    Some((mtime, rest, remaining == 0))
+}
+
+/// Whether a PROPPATCH multistatus reports a property that was not changed,
+/// dav-server then changed none of them.
+fn has_failed_propstat(body: &[u8]) -> bool {
+    String::from_utf8_lossy(body)
+        .split("HTTP/1.1 ")
+        .skip(1)
+        .any(|status| !status.starts_with('2'))
+}
+
 fn percent_decode(s: &str) -> String {
     percent_encoding::percent_decode_str(s)
         .decode_utf8_lossy()
@@ -187,7 +1059,132 @@ fn format_timestamp(ts_millis: u64) -> String {
     }
 }
 
-fn render_directory_html(req_path: &str, files: &[crate::drive::QuarkFile]) -> String {
+/// What the listing offers besides browsing; nothing by default.
+#[derive(Default)]
+struct PageActions {
+    /// URL of the share endpoint, when entries can be shared from the page.
+    shares_url: Option<String>,
+    /// URL of the thumbnail endpoint, when images and videos show a preview.
+    thumb_url: Option<String>,
+    /// CSRF token of the forms, when files can be uploaded and managed from the page.
+    csrf_token: Option<String>,
+}
+
+impl PageActions {
+    /// The icon cell of `file`: its thumbnail, or `icon` when there is none.
+    fn icon(&self, file: &crate::drive::QuarkFile, icon: &str) -> String {
+        match &self.thumb_url {
+            Some(thumb_url) if thumbs::has_thumbnail(file) => format!(
+                r#"<td class="icon"><img class="thumb" loading="lazy" src="{}/{}" alt="{}"></td>"#,
+                html_escape(thumb_url),
+                percent_encode_path(&file.fid),
+                icon
+            ),
+            _ => format!(r#"<td class="icon">{}</td>"#, icon),
+        }
+    }
+
+    fn header(&self) -> &'static str {
+        if self.shares_url.is_some() || self.csrf_token.is_some() {
+            r#"<th class="actions"></th>"#
+        } else {
+            ""
+        }
+    }
+
+    /// The actions cell of the entry at `href`; `name` is set for entries of
+    /// the listed directory itself, which can be renamed and deleted.
+    fn cell(&self, href: &str, name: Option<&str>) -> String {
+        let mut links = Vec::new();
+        if self.shares_url.is_some() {
+            links.push(format!(r##"<a href="#" class="share" data-path="{}">分享</a>"##, html_escape(href)));
+        }
+        if let (Some(_), Some(name)) = (&self.csrf_token, name) {
+            let name = html_escape(name);
+            links.push(format!(r##"<a href="#" class="rename" data-name="{}">重命名</a>"##, name));
+            links.push(format!(r##"<a href="#" class="delete" data-name="{}">删除</a>"##, name));
+        }
+        match (self.header().is_empty(), links.is_empty()) {
+            (true, _) => String::new(),
+            (false, true) => r#"<td class="actions"></td>"#.to_string(),
+            (false, false) => format!(r#"<td class="actions">{}</td>"#, links.join(" ")),
+        }
+    }
+
+    /// Upload and new-folder forms, posted to the listed directory.
+    fn toolbar(&self) -> String {
+        let Some(token) = &self.csrf_token else {
+            return String::new();
+        };
+        let token = html_escape(token);
+        format!(
+            r#"<div class="toolbar">
+      <form method="post" enctype="multipart/form-data"><input type="hidden" name="csrf" value="{token}"><input type="file" name="file" multiple required><button>上传</button></form>
+      <form method="post"><input type="hidden" name="csrf" value="{token}"><input type="hidden" name="action" value="mkdir"><input type="text" name="name" placeholder="新文件夹名称" required><button>新建文件夹</button></form>
+    </div>"#
+        )
+    }
+
+    fn script(&self) -> String {
+        let mut script = String::new();
+        if let Some(shares_url) = &self.shares_url {
+            script.push_str(&self.share_script(shares_url));
+        }
+        if let Some(token) = &self.csrf_token {
+            script.push_str(&format!(
+                r#"<form id="manage" method="post" hidden><input type="hidden" name="csrf" value="{}"><input type="hidden" name="action"><input type="hidden" name="name"><input type="hidden" name="to"></form>
+<script>
+document.addEventListener("click", (event) => {{
+  const link = event.target.closest("a.rename, a.delete");
+  if (!link) return;
+  event.preventDefault();
+  const form = document.getElementById("manage");
+  const name = link.dataset.name;
+  if (link.classList.contains("rename")) {{
+    const to = prompt("新名称", name);
+    if (!to || to === name) return;
+    form.elements.action.value = "rename";
+    form.elements.to.value = to;
+  }} else {{
+    if (!confirm("删除“" + name + "”？")) return;
+    form.elements.action.value = "delete";
+  }}
+  form.elements.name.value = name;
+  form.submit();
+}});
+</script>"#,
+                html_escape(token)
+            ));
+        }
+        script
+    }
+
+    fn share_script(&self, shares_url: &str) -> String {
+        format!(
+            r#"<script>
+const SHARES_URL = "{}";
+document.addEventListener("click", async (event) => {{
+  const link = event.target.closest("a.share");
+  if (!link) return;
+  event.preventDefault();
+  const passcode = prompt("提取码（留空则公开分享）", "");
+  if (passcode === null) return;
+  const res = await fetch(SHARES_URL, {{
+    method: "POST",
+    headers: {{ "Content-Type": "application/json" }},
+    body: JSON.stringify({{ path: link.dataset.path, passcode: passcode || null }}),
+  }});
+  if (!res.ok) {{ alert("分享失败：" + res.status); return; }}
+  const share = await res.json();
+  prompt("分享链接", share.share_url + (share.passcode ? " 提取码：" + share.passcode : ""));
+}});
+</script>"#,
+            html_escape(shares_url)
+        )
+    }
+}
+
+fn render_directory_html(req_path: &str, files: &[crate::drive::QuarkFile], actions: &PageActions) -> String {
     let display_path = percent_decode(req_path);
     let display_path = if display_path.is_empty() || display_path == "/" {
         "/".to_string()
@@ -242,8 +1239,8 @@ fn render_directory_html(req_path: &str, files: &[crate::drive::QuarkFile]) -> S
         let href = format!("{}{}/", req_path_normalized, percent_encode_path(&dir.file_name));
         let date = format_timestamp(dir.updated_at);
         rows.push_str(&format!(
-            r#"<tr class="dir"><td class="icon">📁</td><td class="name"><a href="{}">{}</a></td><td class="size">-</td><td class="date">{}</td></tr>"#,
-            html_escape(&href), name, date
+            r#"<tr class="dir"><td class="icon">📁</td><td class="name"><a href="{}">{}</a></td><td class="size">-</td><td class="date">{}</td>{}</tr>"#,
+            html_escape(&href), name, date, actions.cell(&href, Some(&dir.file_name))
         ));
     }
 
@@ -252,15 +1249,54 @@ fn render_directory_html(req_path: &str, files: &[crate::drive::QuarkFile]) -> S
         let href = format!("{}{}", req_path_normalized, percent_encode_path(&file.file_name));
         let size = format_size(file.size);
         let date = format_timestamp(file.updated_at);
-        let icon = file_icon(&file.file_name);
+        let icon = actions.icon(file, file_icon(&file.file_name));
         rows.push_str(&format!(
-            r#"<tr class="file"><td class="icon">{}</td><td class="name"><a href="{}">{}</a></td><td class="size">{}</td><td class="date">{}</td></tr>"#,
-            icon, html_escape(&href), name, size, date
+            r#"<tr class="file">{}<td class="name"><a href="{}">{}</a></td><td class="size">{}</td><td class="date">{}</td>{}</tr>"#,
+            icon, html_escape(&href), name, size, date, actions.cell(&href, Some(&file.file_name))
         ));
     }
 
     let total = dirs.len() + regular_files.len();
+    render_page(&display_path, &breadcrumbs, "", &rows, total, actions)
+}
+
+/// Results of a `?q=` search below `req_path`: href, path relative to it and entry.
+fn render_search_html(
+    req_path: &str,
+    keyword: &str,
+    hits: &[(String, String, crate::drive::QuarkFile)],
+    actions: &PageActions,
+) -> String {
+    let display_path = percent_decode(req_path);
+    let breadcrumbs = format!(
+        r#"<a href="{}">{}</a> / <span class="current">搜索“{}”</span>"#,
+        html_escape(req_path),
+        html_escape(&display_path),
+        html_escape(keyword)
+    );
+    let mut rows = String::new();
+    for (href, name, file) in hits {
+        let (class, icon, size) = if file.dir {
+            ("dir", "📁", "-".to_string())
+        } else {
+            ("file", file_icon(&file.file_name), format_size(file.size))
+        };
+        rows.push_str(&format!(
+            r#"<tr class="{}">{}<td class="name"><a href="{}">{}</a></td><td class="size">{}</td><td class="date">{}</td>{}</tr>"#,
+            class, actions.icon(file, icon), html_escape(href), html_escape(name), size, format_timestamp(file.updated_at), actions.cell(href, None)
+        ));
+    }
+    render_page(&display_path, &breadcrumbs, keyword, &rows, hits.len(), actions)
+}
 
+fn render_page(
+    display_path: &str,
+    breadcrumbs: &str,
+    keyword: &str,
+    rows: &str,
+    total: usize,
+    actions: &PageActions,
+) -> String {
     format!(
         r#"<!DOCTYPE html>
 <html lang="zh-CN">
@@ -279,6 +1315,9 @@ body {{ font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Hel
 .breadcrumb a {{ color: #2563eb; text-decoration: none; }}
 .breadcrumb a:hover {{ text-decoration: underline; }}
 .breadcrumb .current {{ color: #333; font-weight: 500; }}
+.search {{ margin-top: 12px; }}
+.search input {{ width: 100%; padding: 8px 12px; font-size: 14px; border: 1px solid #ddd; border-radius: 6px; }}
+.search input:focus {{ outline: none; border-color: #2563eb; }}
 .content {{ background: #fff; border-radius: 8px; box-shadow: 0 1px 3px rgba(0,0,0,0.1); overflow: hidden; }}
 table {{ width: 100%; border-collapse: collapse; }}
 thead {{ background: #fafafa; }}
@@ -288,12 +1327,20 @@ tr:hover {{ background: #f8fafc; }}
 tr.parent:hover {{ background: #f0f7ff; }}
 tr.dir:hover {{ background: #f0f7ff; }}
 .icon {{ width: 32px; text-align: center; }}
+.thumb {{ width: 32px; height: 32px; object-fit: cover; border-radius: 4px; vertical-align: middle; }}
 .name {{ word-break: break-all; }}
 .name a {{ color: #1a1a1a; text-decoration: none; }}
 .name a:hover {{ color: #2563eb; text-decoration: underline; }}
 .dir .name a {{ font-weight: 500; }}
 .size {{ width: 100px; text-align: right; color: #888; white-space: nowrap; }}
 .date {{ width: 160px; color: #888; white-space: nowrap; }}
+.actions {{ width: 64px; text-align: right; white-space: nowrap; }}
+.toolbar {{ display: flex; flex-wrap: wrap; gap: 8px 16px; margin-top: 12px; font-size: 14px; }}
+.toolbar form {{ display: flex; gap: 8px; align-items: center; }}
+.toolbar input[type=text] {{ padding: 4px 8px; font-size: 14px; border: 1px solid #ddd; border-radius: 6px; }}
+.toolbar button {{ padding: 4px 12px; font-size: 14px; color: #fff; background: #2563eb; border: none; border-radius: 6px; cursor: pointer; }}
+.actions a {{ color: #2563eb; text-decoration: none; font-size: 13px; }}
+.actions a:hover {{ text-decoration: underline; }}
 .footer {{ text-align: center; padding: 16px; font-size: 12px; color: #aaa; }}
 .footer a {{ color: #aaa; text-decoration: none; }}
 .footer a:hover {{ color: #2563eb; text-decoration: underline; }}
@@ -310,21 +1357,28 @@ tr.dir:hover {{ background: #f0f7ff; }}
   <div class="header">
     <h1><a href="https://github.com/chenqimiao/quarkdrive-webdav" target="_blank" style="color:inherit;text-decoration:none;">QuarkDrive WebDAV</a></h1>
     <div class="breadcrumb">{breadcrumbs}</div>
+    <form class="search" method="get"><input type="search" name="q" value="{keyword}" placeholder="搜索此目录及子目录"></form>
+    {toolbar}
   </div>
   <div class="content">
     <table>
-      <thead><tr><th class="icon"></th><th>名称</th><th class="size">大小</th><th class="date">修改时间</th></tr></thead>
+      <thead><tr><th class="icon"></th><th>名称</th><th class="size">大小</th><th class="date">修改时间</th>{actions_header}</tr></thead>
       <tbody>{rows}</tbody>
     </table>
   </div>
   <div class="footer">{total} 个项目 · <a href="https://github.com/chenqimiao/quarkdrive-webdav" target="_blank">GitHub</a></div>
 </div>
+{script}
 </body>
 </html>"#,
-        title = html_escape(&display_path),
+        title = html_escape(display_path),
         breadcrumbs = breadcrumbs,
+        keyword = html_escape(keyword),
         rows = rows,
         total = total,
+        actions_header = actions.header(),
+        toolbar = actions.toolbar(),
+        script = actions.script(),
     )
 }
 
@@ -512,7 +1566,7 @@ mod tests {
     #[test]
     fn test_render_directory_html_root_empty() {
         let files: Vec<crate::drive::QuarkFile> = vec![];
-        let html = render_directory_html("/", &files);
+        let html = render_directory_html("/", &files, &PageActions::default());
         assert!(html.contains("QuarkDrive"));
         assert!(html.contains("0 个项目"));
         // root should not have parent link
@@ -536,6 +1590,7 @@ mod tests {
                 download_url: None,
                 content_hash: None,
                 parent_path: None,
+                real_name: None,
             },
             crate::drive::QuarkFile {
                 fid: "2".to_string(),
@@ -551,9 +1606,10 @@ mod tests {
                 download_url: None,
                 content_hash: None,
                 parent_path: None,
+                real_name: None,
             },
         ];
-        let html = render_directory_html("/docs", &files);
+        let html = render_directory_html("/docs", &files, &PageActions::default());
         assert!(html.contains("2 个项目"));
         // subdirectory should have parent link
         assert!(html.contains("href=\"../\""));
@@ -581,6 +1637,7 @@ mod tests {
                 download_url: None,
                 content_hash: None,
                 parent_path: None,
+                real_name: None,
             },
             crate::drive::QuarkFile {
                 fid: "2".to_string(),
@@ -596,9 +1653,10 @@ mod tests {
                 download_url: None,
                 content_hash: None,
                 parent_path: None,
+                real_name: None,
             },
         ];
-        let html = render_directory_html("/", &files);
+        let html = render_directory_html("/", &files, &PageActions::default());
         let pos_a = html.find("a.txt").unwrap();
         let pos_b = html.find("b.txt").unwrap();
         // a.txt should come before b.txt (sorted alphabetically)
@@ -608,15 +1666,7 @@ mod tests {
     // --- compute_fs_path tests ---
 
     fn create_test_webdav(root: &str, strip_prefix: Option<&str>) -> QuarkDriveWebDav {
-        use std::sync::Arc;
-        use dashmap::DashMap;
-        let cookie = Arc::new(DashMap::new());
-        cookie.insert("test".to_string(), "value".to_string());
-        let config = crate::drive::DriveConfig {
-            api_base_url: "https://drive.quark.cn".to_string(),
-            cookie,
-        };
-        let drive = crate::drive::QuarkDrive::new(config).unwrap();
+        let drive = crate::drive::create_test_drive();
         let fs = crate::vfs::QuarkDriveFileSystem::new(drive, root.to_string(), 100, 60).unwrap();
         let handler = DavHandler::builder()
             .filesystem(Box::new(fs.clone()))
@@ -627,6 +1677,10 @@ mod tests {
             handler,
             fs,
             strip_prefix: strip_prefix.map(|s| s.to_string()),
+            reserved_prefix: "/_quarkdrive".to_string(),
+            stream_proxy: None,
+            hls_proxy: None,
+            csrf: CsrfTokens::default(),
         }
     }
 
@@ -785,10 +1839,531 @@ mod tests {
         assert!(!should_add_digest(&Some("sha-256".to_string())));
         assert!(!should_add_digest(&Some("sha-512".to_string())));
     }
+
+    // --- concurrent PUT tests ---
+
+    #[test]
+    fn test_claim_write_rejects_concurrent_put() {
+        let webdav = create_test_webdav("/", None);
+        let guard = webdav.claim_write(&Method::PUT, "/dir/a.txt").unwrap();
+        assert!(guard.is_some());
+        assert_eq!(
+            webdav.claim_write(&Method::PUT, "/dir/a%2Etxt").err(),
+            Some(hyper::StatusCode::LOCKED)
+        );
+        assert!(webdav.claim_write(&Method::GET, "/dir/a.txt").unwrap().is_none());
+        drop(guard);
+        assert!(webdav.claim_write(&Method::PUT, "/dir/a.txt").unwrap().is_some());
+    }
+
+    // --- MOVE destination tests ---
+
+    #[test]
+    fn test_move_destination() {
+        let webdav = create_test_webdav("/", Some("/dav"));
+        let mv = Method::from_bytes(b"MOVE").unwrap();
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("destination", "http://host:8080/dav/dir/new%20name.txt".parse().unwrap());
+        assert_eq!(
+            webdav.move_destination(&mv, &headers),
+            Some(PathBuf::from("/dir/new name.txt"))
+        );
+        headers.insert("destination", "/dav/other.txt".parse().unwrap());
+        assert_eq!(webdav.move_destination(&mv, &headers), Some(PathBuf::from("/other.txt")));
+        assert!(webdav.move_destination(&Method::PUT, &headers).is_none());
+        assert!(webdav.move_destination(&mv, &hyper::HeaderMap::new()).is_none());
+    }
+
+    // --- modification time tests ---
+
+    #[test]
+    fn test_is_not_modified() {
+        use std::time::Duration;
+        let etag: ETag = "\"abc\"".parse().unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut headers = hyper::HeaderMap::new();
+        assert!(!is_not_modified(&headers, &etag, Some(modified)));
+        headers.insert("if-none-match", "\"abc\"".parse().unwrap());
+        assert!(is_not_modified(&headers, &etag, Some(modified)));
+        headers.insert("if-none-match", "W/\"abc\", \"def\"".parse().unwrap());
+        assert!(is_not_modified(&headers, &etag, None));
+        // If-None-Match takes precedence over If-Modified-Since
+        headers.insert("if-none-match", "\"def\"".parse().unwrap());
+        headers.insert("if-modified-since", "Wed, 15 Nov 2023 00:00:00 GMT".parse().unwrap());
+        assert!(!is_not_modified(&headers, &etag, Some(modified)));
+        headers.remove("if-none-match");
+        assert!(is_not_modified(&headers, &etag, Some(modified)));
+        assert!(!is_not_modified(&headers, &etag, None));
+        headers.insert("if-modified-since", "Mon, 13 Nov 2023 00:00:00 GMT".parse().unwrap());
+        assert!(!is_not_modified(&headers, &etag, Some(modified)));
+
+        let resp = not_modified_response(&etag, Some(modified));
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()["etag"], "\"abc\"");
+        assert_eq!(resp.headers()["last-modified"], "Tue, 14 Nov 2023 22:13:20 GMT");
+    }
+
+    #[test]
+    fn test_owncloud_endpoints() {
+        let webdav = create_test_webdav("/", Some("/dav"));
+        assert_eq!(webdav.compute_fs_path("/dav/remote.php/webdav/a/b.txt"), PathBuf::from("/a/b.txt"));
+        assert_eq!(webdav.compute_fs_path("/dav/remote.php/dav/files/alice/a.txt"), PathBuf::from("/a.txt"));
+        assert_eq!(webdav.compute_fs_path("/dav/remote.php/dav/files/alice/"), PathBuf::from("/"));
+        assert_eq!(webdav.compute_fs_path("/dav/remote.php/webdavx"), PathBuf::from("/remote.php/webdavx"));
+        assert_eq!(
+            webdav.owncloud_dav_prefix("/dav/remote.php/dav/files/a%20b/x"),
+            Some("/dav/remote.php/dav/files/a b".to_string())
+        );
+        assert_eq!(webdav.owncloud_dav_prefix("/dav/remote.php/webdav"), Some("/dav/remote.php/webdav".to_string()));
+        assert!(webdav.owncloud_dav_prefix("/dav/remote.php/dav/files/").is_none());
+        assert!(webdav.owncloud_dav_prefix("/dav/docs/a.txt").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_owncloud_status_and_props() {
+        let webdav = create_test_webdav("/", None);
+        let resp = webdav.handle_status_request(&Method::GET, "/status.php").unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["installed"], true);
+        assert_eq!(status["version"], OWNCLOUD_VERSION);
+        assert!(webdav.handle_status_request(&Method::PUT, "/status.php").is_none());
+        assert!(webdav.handle_status_request(&Method::GET, "/docs/status.php").is_none());
+
+        let req_path = "/remote.php/dav/files/alice/";
+        let config = DavConfig::new().strip_prefix(webdav.owncloud_dav_prefix(req_path).unwrap());
+        let req = Request::builder()
+            .method("PROPFIND")
+            .uri(req_path)
+            .header("Depth", "0")
+            .body(Full::new(Bytes::from(
+                r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns"><d:prop><oc:fileid/><oc:permissions/><oc:size/><nc:has-preview/></d:prop></d:propfind>"#,
+            )))
+            .unwrap();
+        let resp = webdav.handler.handle_with(config, req).await;
+        assert_eq!(resp.status(), hyper::StatusCode::MULTI_STATUS);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("/remote.php/dav/files/alice/</D:href>"), "{}", body);
+        assert!(body.contains(">0</oc:fileid>"), "{}", body);
+        assert!(body.contains(">GDNVCK</oc:permissions>"), "{}", body);
+        assert!(body.contains(">0</oc:size>"), "{}", body);
+        assert!(body.contains(">false</nc:has-preview>"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_quark_props() {
+        let webdav = create_test_webdav("/", None);
+        webdav.fs.shares.insert(crate::drive::model::ShareInfo {
+            share_id: "s1".to_string(),
+            share_url: "https://pan.quark.cn/s/s1".to_string(),
+            passcode: None,
+            title: "root".to_string(),
+            first_fid: "0".to_string(),
+            expired_at: 0,
+            created_at: 0,
+        });
+        let propfind = |body: &'static str| {
+            Request::builder()
+                .method("PROPFIND")
+                .uri("/")
+                .header("Depth", "0")
+                .body(Full::new(Bytes::from(body)))
+                .unwrap()
+        };
+        let resp = webdav
+            .handler
+            .handle(propfind(r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:"><d:allprop/></d:propfind>"#))
+            .await;
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(r#"<quark:fid xmlns:quark="http://pan.quark.cn/ns">0</quark:fid>"#), "{}", body);
+        assert!(body.contains(">1</quark:status>"), "{}", body);
+        assert!(body.contains(">https://pan.quark.cn/s/s1</quark:share-url>"), "{}", body);
+        // the root has no parent folder
+        assert!(!body.contains("pdir-fid"), "{}", body);
+
+        let resp = webdav
+            .handler
+            .handle(propfind(
+                r#"<?xml version="1.0"?><d:propfind xmlns:d="DAV:" xmlns:q="http://pan.quark.cn/ns"><d:prop><q:fid/><q:md5/></d:prop></d:propfind>"#,
+            ))
+            .await;
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(">0</quark:fid>"), "{}", body);
+        assert!(body.contains("404 Not Found"), "{}", body);
+    }
+
+    #[test]
+    fn test_search_paths() {
+        assert_eq!(query_param(Some("q=a+b%26c&x=1"), "q"), Some("a b&c".to_string()));
+        assert_eq!(query_param(Some("x=1"), "q"), None);
+        assert_eq!(query_param(None, "q"), None);
+
+        assert_eq!(resolve_href("/dav/docs/", "http://host/dav/movies"), "/dav/movies");
+        assert_eq!(resolve_href("/dav/docs/", "/dav/movies"), "/dav/movies");
+        assert_eq!(resolve_href("/dav/docs/", "2024"), "/dav/docs/2024");
+
+        let webdav = create_test_webdav("/media", Some("/dav"));
+        assert_eq!(webdav.href_for("/dav/docs/", Path::new("/media/a b/c.txt"), false), "/dav/a%20b/c%2Etxt");
+        assert_eq!(webdav.href_for("/dav/", Path::new("/media/sub"), true), "/dav/sub/");
+        assert_eq!(
+            webdav.href_for("/dav/remote.php/webdav/x", Path::new("/media/x"), false),
+            "/dav/remote.php/webdav/x"
+        );
+        let webdav = create_test_webdav("/", None);
+        assert_eq!(webdav.href_for("/docs", Path::new("/docs/a"), false), "/docs/a");
+        assert_eq!(webdav.href_for("/docs", Path::new("/"), true), "/");
+    }
+
+    #[test]
+    fn test_render_search_html() {
+        let mut file = crate::drive::QuarkFile::new_dir("f1".into(), "<b>.txt".into(), "d1".into(), "/docs/sub".into());
+        file.dir = false;
+        file.file = true;
+        let hits = [("/docs/sub/%3Cb%3E.txt".into(), "sub/<b>.txt".into(), file)];
+        let html = render_search_html("/docs/", "<b>", &hits, &PageActions::default());
+        assert!(html.contains(r#"href="/docs/sub/%3Cb%3E.txt">sub/&lt;b&gt;.txt</a>"#));
+        assert!(html.contains(r#"name="q" value="&lt;b&gt;""#));
+        assert!(html.contains("1 个项目"));
+    }
+
+    #[tokio::test]
+    async fn test_shares_endpoint_guards() {
+        let mut webdav = create_test_webdav("/", Some("/dav"));
+        assert_eq!(webdav.endpoint_subpath("/dav/_quarkdrive/shares", SHARES_PATH), Some(""));
+        assert_eq!(webdav.endpoint_subpath("/dav/_quarkdrive/shares/abc", SHARES_PATH), Some("/abc"));
+        assert_eq!(webdav.endpoint_subpath("/dav/remote.php/webdav/_quarkdrive/shares", SHARES_PATH), Some(""));
+        assert!(webdav.endpoint_subpath("/dav/_quarkdrive/shares.txt", SHARES_PATH).is_none());
+        // a drive folder named like an endpoint outside the prefix stays reachable
+        assert!(webdav.endpoint_subpath("/dav/_shares", SHARES_PATH).is_none());
+        assert!(webdav.endpoint_subpath("/dav/_quarkdriver/shares", SHARES_PATH).is_none());
+        assert_eq!(webdav.reserved_subpath("/dav/_quarkdrive/other"), Some("/other"));
+
+        let resp = webdav.handle_shares_request(&Method::POST, "", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = webdav.handle_shares_request(&Method::POST, "", Some(b"{\"passcode\": \"1234\"}")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = webdav.handle_shares_request(&Method::PUT, "", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+        let resp = webdav.handle_shares_request(&Method::DELETE, "/", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+
+        webdav.fs.set_read_only(true);
+        let resp = webdav.handle_shares_request(&Method::POST, "", Some(b"{\"path\": \"/dav/a.txt\"}")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        let resp = webdav.handle_shares_request(&Method::DELETE, "/abc", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_import_endpoint_guards() {
+        let mut webdav = create_test_webdav("/", Some("/dav"));
+        assert!(webdav.endpoint_subpath("/dav/_quarkdrive/imports", IMPORT_PATH).is_none());
+        let resp = webdav.handle_import_request(&Method::GET, "/x", None, None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+
+        let resp = webdav.handle_import_request(&Method::GET, "", None, None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = webdav
+            .handle_import_request(&Method::GET, "", Some("url=https%3A%2F%2Fpan.quark.cn%2Flist"), None)
+            .await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = webdav.handle_import_request(&Method::POST, "", None, Some(b"{\"path\": \"/dav/\"}")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let body = br#"{"url": "https://example.com/x", "path": "/dav/"}"#;
+        let resp = webdav.handle_import_request(&Method::POST, "", None, Some(body)).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = webdav.handle_import_request(&Method::DELETE, "", None, None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+
+        webdav.fs.set_read_only(true);
+        let body = br#"{"url": "https://pan.quark.cn/s/abc", "path": "/dav/"}"#;
+        let resp = webdav.handle_import_request(&Method::POST, "", None, Some(body)).await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_offline_endpoint_guards() {
+        let mut webdav = create_test_webdav("/", Some("/dav"));
+        assert_eq!(webdav.endpoint_subpath("/dav/_quarkdrive/offline/t1", OFFLINE_PATH), Some("/t1"));
+
+        let resp = webdav.handle_offline_request(&Method::POST, "", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = webdav.handle_offline_request(&Method::POST, "", Some(b"{\"url\": \"magnet:?xt=urn:btih:abc\"}")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = webdav.handle_offline_request(&Method::DELETE, "/", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+        let resp = webdav.handle_offline_request(&Method::PUT, "", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+
+        webdav.fs.set_read_only(true);
+        let body = br#"{"url": "magnet:?xt=urn:btih:abc", "path": "/dav/"}"#;
+        let resp = webdav.handle_offline_request(&Method::POST, "", Some(body)).await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        let resp = webdav.handle_offline_request(&Method::DELETE, "/t1", None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_thumb_endpoints() {
+        let webdav = create_test_webdav("/", Some("/dav"));
+        assert!(webdav.handle_thumb_request(&Method::GET, "/dav/_quarkdrive/thumbs", None).await.is_none());
+        assert!(webdav.handle_thumb_request(&Method::GET, "/dav/a.jpg", None).await.is_none());
+        let resp = webdav.handle_thumb_request(&Method::GET, "/dav/_quarkdrive/thumb/..%2Fx", None).await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+        let resp = webdav
+            .handle_thumb_request(&Method::GET, "/dav/index.php/core/preview", Some("x=32&y=32"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+        let resp = webdav
+            .handle_thumb_request(&Method::PUT, "/dav/index.php/core/preview.png", Some("fileId=abc"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_render_thumbnails() {
+        let mut photo = crate::drive::QuarkFile::new_dir("f1".into(), "a.jpg".into(), "0".into(), "/".into());
+        photo.dir = false;
+        photo.file = true;
+        let mut text = photo.clone();
+        text.fid = "f2".into();
+        text.file_name = "b.txt".into();
+        let actions = PageActions {
+            thumb_url: Some("/dav/_quarkdrive/thumb".to_string()),
+            ..PageActions::default()
+        };
+        let html = render_directory_html("/dav/", &[photo.clone(), text], &actions);
+        assert!(html.contains(r#"<img class="thumb" loading="lazy" src="/dav/_quarkdrive/thumb/f1" alt="🖼️">"#));
+        assert!(!html.contains("_thumb/f2"));
+        let html = render_directory_html("/dav/", &[photo], &PageActions::default());
+        assert!(!html.contains("<img"));
+    }
+
+    #[tokio::test]
+    async fn test_form_guards() {
+        let mut webdav = create_test_webdav("/", Some("/dav"));
+        let path = webdav.form_entry_path("/dav/a%20b/", "c d.txt").unwrap();
+        assert_eq!(path.as_rel_ospath(), Path::new("a b/c d.txt"));
+        assert!(webdav.form_entry_path("/dav/", "..").is_none());
+        assert!(webdav.form_entry_path("/dav/", "a/b").is_none());
+        assert!(webdav.form_entry_path("/dav/", "").is_none());
+        assert!(is_form_content_type("multipart/form-data; boundary=x"));
+        assert!(is_form_content_type("application/x-www-form-urlencoded"));
+        assert!(!is_form_content_type("application/json"));
+
+        let token = webdav.csrf.issue("");
+        let form = format!("action=bogus&name=a&csrf={}", token);
+        let status = webdav.apply_form_action("/dav/", form.as_bytes(), "").await.unwrap_err();
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        let form = format!("action=delete&name=..&csrf={}", token);
+        let status = webdav.apply_form_action("/dav/", form.as_bytes(), "").await.unwrap_err();
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        let status = webdav.apply_form_action("/dav/", b"action=mkdir&name=a", "").await.unwrap_err();
+        assert_eq!(status, hyper::StatusCode::FORBIDDEN);
+        let form = format!("action=mkdir&name=a&csrf={}", token);
+        let status = webdav.apply_form_action("/dav/", form.as_bytes(), "bob").await.unwrap_err();
+        assert_eq!(status, hyper::StatusCode::FORBIDDEN);
+
+        // the file comes before the token
+        let body = "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nhi\r\n--b--\r\n";
+        let stream = futures_util::stream::iter([Ok::<_, io::Error>(Bytes::from(body))]);
+        let status = webdav
+            .upload_form_files("/dav/", MultipartReader::new(stream, "b"), "")
+            .await
+            .unwrap_err();
+        assert_eq!(status, hyper::StatusCode::FORBIDDEN);
+
+        // what a WebDAV client locked is left alone
+        webdav.fs.locks.lock("/locked", None, Some("alice"), None, None, false, true).await.unwrap();
+        for action in ["mkdir", "delete"] {
+            let form = format!("action={}&name=locked&csrf={}", action, token);
+            let status = webdav.apply_form_action("/dav/", form.as_bytes(), "").await.unwrap_err();
+            assert_eq!(status, hyper::StatusCode::LOCKED, "{}", action);
+        }
+        let body = format!(
+            "--b\r\nContent-Disposition: form-data; name=\"csrf\"\r\n\r\n{}\r\n\
+--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"locked\"\r\n\r\nhi\r\n--b--\r\n",
+            token
+        );
+        let stream = futures_util::stream::iter([Ok::<_, io::Error>(Bytes::from(body))]);
+        let status = webdav
+            .upload_form_files("/dav/", MultipartReader::new(stream, "b"), "")
+            .await
+            .unwrap_err();
+        assert_eq!(status, hyper::StatusCode::LOCKED);
+
+        webdav.fs.set_read_only(true);
+        let stream = futures_util::stream::iter([Ok::<_, io::Error>(Bytes::from(form))]);
+        let resp = webdav
+            .handle_form_request("/dav/", "application/x-www-form-urlencoded", stream, "")
+            .await;
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_render_form_actions() {
+        let mut file = crate::drive::QuarkFile::new_dir("f1".into(), "a\"b.txt".into(), "0".into(), "/".into());
+        file.dir = false;
+        file.file = true;
+        let actions = PageActions {
+            csrf_token: Some("tok".to_string()),
+            ..PageActions::default()
+        };
+        let html = render_directory_html("/dav/", &[file.clone()], &actions);
+        assert!(html.contains(r#"enctype="multipart/form-data"><input type="hidden" name="csrf" value="tok">"#));
+        assert!(html.contains(r#"name="action" value="mkdir""#));
+        assert!(html.contains(r#"class="rename" data-name="a&quot;b.txt""#));
+        assert!(html.contains(r#"class="delete" data-name="a&quot;b.txt""#));
+        assert!(html.contains(r#"<form id="manage" method="post" hidden>"#));
+        assert!(!html.contains("class=\"share\""));
+
+        let hits = vec![("/dav/x/a.txt".to_string(), "x/a.txt".to_string(), file.clone())];
+        let html = render_search_html("/dav/", "a", &hits, &actions);
+        assert!(!html.contains("class=\"rename\""));
+
+        let html = render_directory_html("/dav/", &[file], &PageActions::default());
+        assert!(!html.contains("<form method=\"post\""));
+        assert!(!html.contains("class=\"delete\""));
+    }
+
+    #[test]
+    fn test_render_share_actions() {
+        let mut file = crate::drive::QuarkFile::new_dir("f1".into(), "a.txt".into(), "0".into(), "/".into());
+        file.dir = false;
+        file.file = true;
+        let actions = PageActions {
+            shares_url: Some("/dav/_quarkdrive/shares".to_string()),
+            ..PageActions::default()
+        };
+        let html = render_directory_html("/dav/", &[file.clone()], &actions);
+        assert!(html.contains(r#"class="share" data-path="/dav/a%2Etxt""#));
+        assert!(html.contains(r#"const SHARES_URL = "/dav/_quarkdrive/shares";"#));
+        let html = render_directory_html("/dav/", &[file], &PageActions::default());
+        assert!(!html.contains("class=\"share\""));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn test_parse_oc_mtime() {
+        assert_eq!(parse_oc_mtime("1700000000"), Some(1_700_000_000_000));
+        assert_eq!(parse_oc_mtime("1700000000.5"), Some(1_700_000_000_500));
+        assert!(parse_oc_mtime("-1").is_none());
+        assert!(parse_oc_mtime("soon").is_none());
+    }
+
+    #[test]
+    fn test_take_lastmodified() {
+        let only = br#"<?xml version="1.0"?>
+<D:propertyupdate xmlns:D="DAV:"><D:set><D:prop>
+<D:getlastmodified>Thu, 01 Jan 1970 00:00:03 GMT</D:getlastmodified>
+</D:prop></D:set></D:propertyupdate>"#;
+        let (mtime, rest, alone) = take_lastmodified(only).unwrap();
+        assert_eq!(mtime, 3000);
+        assert!(alone);
+        assert!(!String::from_utf8(rest).unwrap().contains("getlastmodified"));
+
+        let mixed = br#"<?xml version="1.0"?>
+<D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:schemas-microsoft-com:"><D:set><D:prop>
+<D:getlastmodified>Thu, 01 Jan 1970 00:00:03 GMT</D:getlastmodified>
+<Z:Win32FileAttributes>00000020</Z:Win32FileAttributes>
+</D:prop></D:set></D:propertyupdate>"#;
+        let (mtime, rest, alone) = take_lastmodified(mixed).unwrap();
+        assert_eq!(mtime, 3000);
+        assert!(!alone);
+        let rest = String::from_utf8(rest).unwrap();
+        assert!(rest.contains("Win32FileAttributes"));
+        assert!(!rest.contains("getlastmodified"));
+
+        // removing it, or setting anything else, is left to dav-server
+        let remove = br#"<D:propertyupdate xmlns:D="DAV:"><D:remove><D:prop><D:getlastmodified/></D:prop></D:remove></D:propertyupdate>"#;
+        assert!(take_lastmodified(remove).is_none());
+        let other = br#"<D:propertyupdate xmlns:D="DAV:"><D:set><D:prop><D:displayname>x</D:displayname></D:prop></D:set></D:propertyupdate>"#;
+        assert!(take_lastmodified(other).is_none());
+        assert!(take_lastmodified(b"not xml").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lastmodified_after_dav_checks() {
+        let webdav = create_test_webdav("/", None);
+        let body = br#"<?xml version="1.0"?>
+<D:propertyupdate xmlns:D="DAV:"><D:set><D:prop>
+<D:getlastmodified>Thu, 01 Jan 1970 00:00:03 GMT</D:getlastmodified>
+</D:prop></D:set></D:propertyupdate>"#;
+        let (mtime, rest, alone) = take_lastmodified(body).unwrap();
+        let proppatch = |condition: Option<&str>| {
+            let mut req = Request::builder().method("PROPPATCH").uri("/");
+            if let Some(condition) = condition {
+                req = req.header("If", condition);
+            }
+            req.body(Full::new(Bytes::from(rest.clone()))).unwrap()
+        };
+        let mtime_of_root = || {
+            let mut root = crate::drive::QuarkFile::new_root();
+            root.updated_at = 0;
+            webdav.fs.props.apply_mtime(&mut root);
+            root.updated_at
+        };
+
+        // a failed `If:` condition leaves the time alone
+        let resp = webdav.handler.handle_with(DavConfig::new(), proppatch(Some("(<urn:uuid:gone>)"))).await;
+        let resp = webdav.apply_lastmodified("/", mtime, alone, resp.map(BodyExt::boxed_unsync)).await;
+        assert_eq!(resp.status(), hyper::StatusCode::PRECONDITION_FAILED);
+        assert_eq!(mtime_of_root(), 0);
+
+        let resp = webdav.handler.handle_with(DavConfig::new(), proppatch(None)).await;
+        let resp = webdav.apply_lastmodified("/", mtime, alone, resp.map(BodyExt::boxed_unsync)).await;
+        assert_eq!(resp.status(), hyper::StatusCode::MULTI_STATUS);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("HTTP/1.1 200 OK"));
+        assert_eq!(mtime_of_root(), 3000);
+
+        assert!(has_failed_propstat(b"<D:status>HTTP/1.1 200 OK</D:status><D:status>HTTP/1.1 424 Failed Dependency</D:status>"));
+        assert!(!has_failed_propstat(b"<D:status>HTTP/1.1 200 OK</D:status>"));
+    }
+
+    // --- lock admin tests ---
+
+    #[tokio::test]
+    async fn test_locks_admin_list_and_break() {
+        let mut webdav = create_test_webdav("/", Some("/dav"));
+        let lock = webdav
+            .fs
+            .locks
+            .lock("/a.txt", None, None, None, None, false, false)
+            .await
+            .unwrap();
+
+        let resp = webdav.handle_locks_request(&Method::GET, "/dav/_quarkdrive/locks").await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains(&lock.token));
+
+        let break_path = format!("/dav/_quarkdrive/locks/{}", lock.token);
+        webdav.fs.set_read_only(true);
+        let resp = webdav.handle_locks_request(&Method::DELETE, &break_path).await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        assert_eq!(webdav.fs.locks.list().await.len(), 1);
+        webdav.fs.set_read_only(false);
+        let resp = webdav.handle_locks_request(&Method::DELETE, &break_path).await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::NO_CONTENT);
+        assert!(webdav.fs.locks.list().await.is_empty());
+        let resp = webdav.handle_locks_request(&Method::DELETE, &break_path).await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+
+        let resp = webdav.handle_locks_request(&Method::PUT, "/dav/_quarkdrive/locks").await.unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::METHOD_NOT_ALLOWED);
+        assert!(webdav.handle_locks_request(&Method::GET, "/dav/_quarkdrive/locksmith.txt").await.is_none());
+        assert!(webdav.handle_locks_request(&Method::GET, "/dav/docs").await.is_none());
+    }
 }
 
 impl Service<Request<hyper::body::Incoming>> for QuarkDriveWebDav {
-    type Response = Response<Body>;
+    type Response = Response<ResponseBody>;
     type Error = hyper::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
@@ -804,45 +2379,220 @@ impl Service<Request<hyper::body::Incoming>> for QuarkDriveWebDav {
             .headers()
             .get("want-digest")
             .and_then(|v| v.to_str().ok().map(|s| s.to_string()));
+        let move_dest = self.move_destination(&req_method, req.headers());
+        let oc_mtime = req
+            .headers()
+            .get("x-oc-mtime")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_oc_mtime);
         let browser_handler = self.clone();
+        let stream_token = self
+            .stream_proxy
+            .as_ref()
+            .and_then(|_| StreamSigner::token_from_path(self.reserved_subpath(&req_path)?))
+            .map(|token| token.to_string());
+        let hls_token = self
+            .hls_proxy
+            .as_ref()
+            .and_then(|_| StreamSigner::token_from_endpoint_path(self.reserved_subpath(&req_path)?, HLS_PATH))
+            .map(|token| token.to_string());
 
         Box::pin(async move {
-            let mut resp = if should_auth {
+            // Signed stream URLs carry their own authorization, no Basic auth required
+            if let (Some(proxy), Some(token)) = (&browser_handler.stream_proxy, &stream_token) {
+                let range = req
+                    .headers()
+                    .get(hyper::header::RANGE)
+                    .and_then(|v| v.to_str().ok());
+                return Ok(proxy.serve(req.method(), token, range).await);
+            }
+            if let (Some(proxy), Some(token)) = (&browser_handler.hls_proxy, &hls_token) {
+                let range = req
+                    .headers()
+                    .get(hyper::header::RANGE)
+                    .and_then(|v| v.to_str().ok());
+                return Ok(proxy.serve(req.method(), token, range).await);
+            }
+
+            if let Some(resp) = browser_handler.handle_status_request(&req_method, &req_path) {
+                return Ok(resp);
+            }
+
+            let principal = if should_auth {
                 let auth_user_val = auth_user.clone().unwrap();
                 let auth_pwd_val = auth_pwd.clone().unwrap();
 
-                let user = match req.headers().typed_get::<Authorization<Basic>>() {
+                match req.headers().typed_get::<Authorization<Basic>>() {
                     Some(Authorization(basic))
                     if basic.username() == auth_user_val && basic.password() == auth_pwd_val =>
                         {
-                            basic.username().to_string()
+                            Some(basic.username().to_string())
                         }
                     _ => {
                         return Ok(Response::builder()
                             .status(401)
                             .header("WWW-Authenticate", "Basic realm=\"quarkdrive-webdav\"")
-                            .body(Body::from("Authentication required"))
+                            .body(Body::from("Authentication required").boxed_unsync())
                             .unwrap());
                     }
-                };
+                }
+            } else {
+                None
+            };
 
-                if is_browser {
-                    if let Some(resp) = browser_handler.handle_browser_request(&req_path).await {
-                        return Ok(resp);
+            if let Some(resp) = browser_handler.handle_locks_request(&req_method, &req_path).await {
+                return Ok(resp);
+            }
+            if let Some(resp) = browser_handler
+                .handle_thumb_request(&req_method, &req_path, req.uri().query())
+                .await
+            {
+                return Ok(resp);
+            }
+            let endpoint = [SHARES_PATH, IMPORT_PATH, OFFLINE_PATH]
+                .into_iter()
+                .find_map(|endpoint| Some((endpoint, browser_handler.endpoint_subpath(&req_path, endpoint)?)));
+            if let Some((endpoint, rest)) = endpoint {
+                let is_json = req
+                    .headers()
+                    .get(hyper::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.starts_with("application/json"));
+                let query = req.uri().query().map(str::to_string);
+                let Some(body) = collect_limited(req.into_body(), MAX_JSON_LEN).await? else {
+                    return Ok(status_response(hyper::StatusCode::PAYLOAD_TOO_LARGE));
+                };
+                let json_body = is_json.then_some(&body[..]);
+                return Ok(match endpoint {
+                    SHARES_PATH => browser_handler.handle_shares_request(&req_method, rest, json_body).await,
+                    OFFLINE_PATH => browser_handler.handle_offline_request(&req_method, rest, json_body).await,
+                    _ => {
+                        browser_handler
+                            .handle_import_request(&req_method, rest, query.as_deref(), json_body)
+                            .await
                     }
+                });
+            }
+            if browser_handler.reserved_subpath(&req_path).is_some() {
+                return Ok(status_response(hyper::StatusCode::NOT_FOUND));
+            }
+            if is_browser {
+                if let Some(resp) = browser_handler
+                    .handle_browser_request(&req_path, req.uri().query(), req.headers(), principal.as_deref())
+                    .await
+                {
+                    return Ok(resp.map(BodyExt::boxed_unsync));
                 }
+            }
+            if let Some(resp) = browser_handler.handle_playlist_request(&req_method, &req_path).await {
+                return Ok(resp);
+            }
+            if req_method == Method::GET
+                && let Some(resp) = browser_handler.handle_file_not_modified(&req_path, req.headers()).await
+            {
+                return Ok(resp.map(BodyExt::boxed_unsync));
+            }
+
+            if req_method == Method::POST
+                && let Some(content_type) = req
+                    .headers()
+                    .get(hyper::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .filter(|v| is_form_content_type(v))
+                    .map(str::to_string)
+            {
+                let body = req.into_body().into_data_stream();
+                return Ok(browser_handler
+                    .handle_form_request(&req_path, &content_type, body, principal.as_deref().unwrap_or_default())
+                    .await);
+            }
+            if req_method.as_str() == "SEARCH" {
+                let Some(body) = collect_limited(req.into_body(), MAX_SEARCH_LEN).await? else {
+                    return Ok(status_response(hyper::StatusCode::PAYLOAD_TOO_LARGE));
+                };
+                return Ok(browser_handler.handle_search_request(&req_path, &body).await);
+            }
 
-                let config = DavConfig::new().principal(user);
-                dav_server.handle_with(config, req).await
+            let _write_guard = match browser_handler.claim_write(&req_method, &req_path) {
+                Ok(guard) => guard,
+                Err(status) => return Ok(status_response(status)),
+            };
+            browser_handler.refresh_move_destination(move_dest.as_deref()).await;
+            browser_handler
+                .refresh_conditional_target(&req_method, req.headers(), &req_path)
+                .await;
+            let mut config = match principal {
+                Some(user) => DavConfig::new().principal(user),
+                None => DavConfig::new(),
+            };
+            if let Some(prefix) = browser_handler.owncloud_dav_prefix(&req_path) {
+                config = config.strip_prefix(prefix);
+            }
+            let mut resp = if req_method.as_str() == "PROPPATCH" {
+                let (parts, body) = req.into_parts();
+                let Some(body) = collect_limited(body, MAX_PROPPATCH_LEN).await? else {
+                    return Ok(status_response(hyper::StatusCode::PAYLOAD_TOO_LARGE));
+                };
+                let lastmodified = take_lastmodified(&body);
+                let body = match &lastmodified {
+                    Some((_, rest, _)) => Bytes::from(rest.clone()),
+                    None => body,
+                };
+                let resp = dav_server
+                    .handle_with(config, Request::from_parts(parts, Full::new(body)))
+                    .await
+                    .map(BodyExt::boxed_unsync);
+                match lastmodified {
+                    Some((mtime, _, alone)) => browser_handler.apply_lastmodified(&req_path, mtime, alone, resp).await,
+                    None => resp,
+                }
             } else {
-                if is_browser {
-                    if let Some(resp) = browser_handler.handle_browser_request(&req_path).await {
-                        return Ok(resp);
-                    }
+                dav_server.handle_with(config, req).await.map(BodyExt::boxed_unsync)
+            };
+
+            // ownCloud clients send the modification time of what they upload
+            if req_method == Method::PUT
+                && resp.status().is_success()
+                && let Some(mtime) = oc_mtime
+                && browser_handler
+                    .fs
+                    .set_mtime(&browser_handler.compute_fs_path(&req_path), mtime)
+                    .await
+            {
+                resp.headers_mut()
+                    .insert("x-oc-mtime", hyper::header::HeaderValue::from_static("accepted"));
+            }
+
+            // RFC 5323: announce SEARCH and the grammar it understands
+            if req_method == Method::OPTIONS {
+                let headers = resp.headers_mut();
+                headers.insert("dasl", hyper::header::HeaderValue::from_static("<DAV:basicsearch>"));
+                if let Some(allow) = headers.get(hyper::header::ALLOW).and_then(|v| v.to_str().ok())
+                    && allow.contains("PROPFIND")
+                    && let Ok(val) = hyper::header::HeaderValue::from_str(&format!("{},SEARCH", allow))
+                {
+                    headers.insert(hyper::header::ALLOW, val);
                 }
+            }
 
-                dav_server.handle(req).await
-            };
+            // ownCloud clients take the id and ETag of what they changed from the response
+            if matches!(req_method.as_str(), "PUT" | "MKCOL" | "MOVE")
+                && resp.status().is_success()
+            {
+                let fs_path = match &move_dest {
+                    Some(dest) => dest.clone(),
+                    None => browser_handler.compute_fs_path(&req_path),
+                };
+                if let Some((fid, etag)) = browser_handler.fs.owncloud_ids(&fs_path).await {
+                    let headers = resp.headers_mut();
+                    if let Ok(val) = hyper::header::HeaderValue::from_str(&fid) {
+                        headers.insert("oc-fileid", val);
+                    }
+                    if let Ok(val) = hyper::header::HeaderValue::from_str(&format!("\"{}\"", etag)) {
+                        headers.insert("oc-etag", val);
+                    }
+                }
+            }
 
             // RFC 3230: Add Digest header for GET 200 responses
             if req_method == Method::GET && resp.status() == hyper::StatusCode::OK {
@@ -884,6 +2634,10 @@ pub struct MakeSvc {
     pub handler: DavHandler,
     pub fs: QuarkDriveFileSystem,
     pub strip_prefix: Option<String>,
+    pub reserved_prefix: String,
+    pub stream_proxy: Option<StreamProxy>,
+    pub hls_proxy: Option<HlsProxy>,
+    pub csrf: CsrfTokens,
 }
 
 impl Service<()> for MakeSvc {
@@ -897,6 +2651,10 @@ impl Service<()> for MakeSvc {
         let handler = self.handler.clone();
         let fs = self.fs.clone();
         let strip_prefix = self.strip_prefix.clone();
+        let reserved_prefix = self.reserved_prefix.clone();
+        let stream_proxy = self.stream_proxy.clone();
+        let hls_proxy = self.hls_proxy.clone();
+        let csrf = self.csrf.clone();
 
         Box::pin(async move {
             Ok(QuarkDriveWebDav {
@@ -905,6 +2663,10 @@ impl Service<()> for MakeSvc {
                 handler,
                 fs,
                 strip_prefix,
+                reserved_prefix,
+                stream_proxy,
+                hls_proxy,
+                csrf,
             })
         })
     }